@@ -0,0 +1,236 @@
+// Likes, retweets and quotes of timeline tweets. The model picks the tweets it wants to engage
+// with within the limits of a run. Every engagement is kept in the kv db, so the agent never likes,
+// retweets or quotes the same tweet twice, not even after a restart.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::types::Action,
+    llm::structured::{generate_structured, StructuredOutput},
+    twitter::api_types::{FeedTweet, TimelineTweet},
+};
+
+use super::{is_duplicate, is_rate_limited, Agent};
+
+pub const LIKE: &str = "like";
+pub const RETWEET: &str = "retweet";
+pub const QUOTE: &str = "quote";
+
+/// How many timeline tweets the agent may engage with per run
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct EngagementConfig {
+    pub max_likes: usize,
+    pub max_retweets: usize,
+    pub max_quotes: usize,
+}
+
+impl Default for EngagementConfig {
+    fn default() -> Self {
+        Self {
+            max_likes: 3,
+            max_retweets: 1,
+            max_quotes: 0,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct EngagementChoices {
+    likes: Vec<String>,
+    retweets: Vec<String>,
+    quotes: Vec<String>,
+}
+
+impl StructuredOutput for EngagementChoices {
+    const NAME: &'static str = "engagement_choices";
+
+    fn schema() -> serde_json::Value {
+        let ids = serde_json::json!({
+            "type": "array",
+            "items": { "type": "string", "description": "Id of the tweet" }
+        });
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "likes": ids,
+                "retweets": ids,
+                "quotes": ids
+            },
+            "required": ["likes", "retweets", "quotes"],
+            "additionalProperties": false
+        })
+    }
+}
+
+impl Agent {
+    /// Lets the model pick timeline tweets to like, retweet and quote. The quotes are written
+    /// with `context`, the prompt of the run's tweet.
+    pub async fn engage_with_timeline(
+        &self,
+        timeline_tweets: &[TimelineTweet],
+        context: &str,
+    ) -> Result<()> {
+        let limits = &self.config.engagement;
+        if limits.max_likes + limits.max_retweets + limits.max_quotes == 0 {
+            return Ok(());
+        }
+
+        let mut tweets_list = Vec::with_capacity(timeline_tweets.len());
+        let mut candidates = HashMap::with_capacity(timeline_tweets.len());
+        for tweet in timeline_tweets {
+            if tweet.author_id == self.user_id || tweet.is_retweet() || self.engaged(&tweet.id)? {
+                continue;
+            }
+            tweets_list.push(format!("id: {}, {tweet}", tweet.id));
+            candidates.insert(tweet.id.as_str(), tweet);
+        }
+        if candidates.is_empty() {
+            return Ok(());
+        }
+        let (prompt, prompt_tokens) = self.prompts.get_engagement_prompt(
+            &self.prompt_budget,
+            self.profiles.engagement.max_tokens as usize,
+            tweets_list,
+            limits.max_likes,
+            limits.max_retweets,
+            limits.max_quotes,
+        );
+        tracing::info!(
+            "Engagement prompt tokens: {}",
+            serde_json::to_string(&prompt_tokens)?
+        );
+
+        tracing::info!("Deciding which tweets to engage with...");
+        let EngagementChoices {
+            likes,
+            retweets,
+            quotes,
+        } = generate_structured(
+            self.chat_model.as_ref(),
+            &prompt,
+            "Pick the ids of the tweets to like, retweet and quote.",
+            &self.profiles.engagement,
+            3,
+            |choices: &EngagementChoices| {
+                for (action, ids, max) in [
+                    ("like", &choices.likes, limits.max_likes),
+                    ("retweet", &choices.retweets, limits.max_retweets),
+                    ("quote", &choices.quotes, limits.max_quotes),
+                ] {
+                    if ids.len() > max {
+                        bail!("You can {action} at most {max} tweets");
+                    }
+                    if let Some(id) = ids.iter().find(|id| !candidates.contains_key(id.as_str())) {
+                        bail!("{id} is not the id of one of the tweets");
+                    }
+                }
+                Ok(())
+            },
+        )
+        .await?;
+
+        // A failed engagement only skips its tweet, a rate limit the rest of its kind
+        for tweet_id in likes {
+            if self.database.engagement_exists(LIKE, &tweet_id)? {
+                continue;
+            }
+            match self
+                .twitter_client
+                .like_tweet(&self.user_id, &tweet_id)
+                .await
+            {
+                Ok(_) => self.record_engagement(LIKE, Action::Like, &tweet_id),
+                Err(e) if is_rate_limited(&e) => {
+                    tracing::warn!("Not liking any more tweets: {e}");
+                    break;
+                }
+                Err(e) => tracing::warn!("Failed to like tweet {tweet_id}: {e:?}"),
+            }
+        }
+        for tweet_id in retweets {
+            if self.database.engagement_exists(RETWEET, &tweet_id)? {
+                continue;
+            }
+            match self.twitter_client.retweet(&self.user_id, &tweet_id).await {
+                Ok(_) => self.record_engagement(RETWEET, Action::Retweet, &tweet_id),
+                Err(e) if is_rate_limited(&e) => {
+                    tracing::warn!("Not retweeting any more tweets: {e}");
+                    break;
+                }
+                Err(e) => tracing::warn!("Failed to retweet tweet {tweet_id}: {e:?}"),
+            }
+        }
+        for tweet_id in quotes {
+            if self.database.engagement_exists(QUOTE, &tweet_id)? {
+                continue;
+            }
+            if !self.x_budget.may_post()? {
+                tracing::warn!("X post allowance of today used up, not quoting tweets");
+                break;
+            }
+            let tweet = candidates[tweet_id.as_str()];
+            let text = match self
+                .chat_model
+                .generate_text(
+                    context,
+                    &format!(
+                        "Write a comment of less than 240 characters to quote this tweet with. {tweet}"
+                    ),
+                    &self.profiles.tweet,
+                )
+                .await
+            {
+                Ok(text) => text,
+                Err(e) => {
+                    tracing::warn!("Failed to write a quote of tweet {tweet_id}: {e:?}");
+                    continue;
+                }
+            };
+            match self.twitter_client.quote_tweet(&text, &tweet_id).await {
+                Ok(sent_tweet) => {
+                    if let Err(e) = self.database.insert_engagement(QUOTE, &tweet_id) {
+                        tracing::warn!("Failed to remember the quote of tweet {tweet_id}: {e:?}");
+                    }
+                    self.record_sent_post(
+                        false,
+                        Action::Quote,
+                        &format!("{} quoting {tweet_id}: {}", sent_tweet.id, sent_tweet.text),
+                    );
+                }
+                Err(e) if is_duplicate(&e) => tracing::warn!("Not quoting tweet: {e}"),
+                Err(e) if is_rate_limited(&e) => {
+                    tracing::warn!("Not quoting any more tweets: {e}");
+                    break;
+                }
+                Err(e) => tracing::warn!("Failed to quote tweet {tweet_id}: {e:?}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remembers a like or retweet X accepted. Like `record_sent_post`, failures are only logged,
+    /// the engagement happened either way.
+    fn record_engagement(&self, kind: &str, action: Action, tweet_id: &str) {
+        if let Err(e) = self.database.insert_engagement(kind, tweet_id) {
+            tracing::warn!("Failed to remember the {kind} of tweet {tweet_id}: {e:?}");
+        }
+        if let Err(e) = self.record_action(action, tweet_id) {
+            tracing::warn!("Failed to log the {kind} of tweet {tweet_id}: {e:?}");
+        }
+    }
+
+    /// Whether the agent liked, retweeted or quoted the tweet already
+    fn engaged(&self, tweet_id: &str) -> Result<bool> {
+        for kind in [LIKE, RETWEET, QUOTE] {
+            if self.database.engagement_exists(kind, tweet_id)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
@@ -0,0 +1,1637 @@
+use ethsign::SecretKey;
+use sha3::{Digest, Keccak256};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use std::{
+    collections::{HashMap, HashSet},
+    time::SystemTime,
+};
+
+use crate::encumber::XAccountDetails;
+use crate::env::ENV;
+use crate::{
+    cassette,
+    config::Config,
+    db::{
+        types::{
+            Action, ActionLogEntry, CollectionState, Embedding, Memory, MemoryData,
+            SignedActionLogEntry,
+        },
+        Database,
+    },
+    embeddings::{
+        build_embedding_model, cache::CachedEmbeddingModel, reembed::reembed_collection,
+        EmbeddingModel,
+    },
+    llm::{
+        build_fallback_chat_model,
+        fallback::ProviderHealth,
+        structured::{generate_structured, StructuredOutput},
+        ChatModel, Message, ModelProfile, TaskProfiles,
+    },
+    prompts::Prompts,
+    tokens::PromptBudget,
+    twitter::{
+        api_types::{FeedTweet, TimelineTweet, Tweet},
+        error::TwitterError,
+        Feed, TwitterClient, MIN_MENTIONS_PAGE, X_API_URL,
+    },
+    usage::{BudgetState, UsageMeter, UsageReport},
+    x_budget::XBudget,
+};
+use anyhow::{anyhow, bail, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::watch;
+
+use self::{engagement::EngagementConfig, tools::AgentMode};
+
+pub mod engagement;
+pub mod tools;
+
+/// The AI agent that tweets
+/// Should contain short term memory, long term memory, external context
+
+const LONG_TERM_MEMORY: &str = "long-term-memory";
+const TIMELINE_FEED: &str = "timeline";
+const MENTIONS_FEED: &str = "mentions";
+// New tweets read from a feed at once, anything older is skipped
+const MAX_FEED_TWEETS: usize = 500;
+// Tweets of a feed kept for later runs
+const MAX_BUFFERED_TWEETS: usize = 500;
+// How many tweets of the reply chain we read to understand a conversation, the root included
+const MAX_CONVERSATION_DEPTH: usize = 8;
+// Tokens the score of one mention takes in the ranking, `{"id":"<19 digits>","score":10},` with
+// room for tokenizers that split the id into single digits
+const MENTION_SCORE_TOKENS: u32 = 32;
+
+pub struct Agent {
+    prompts: Prompts,
+    twitter_client: TwitterClient,
+    chat_model: Box<dyn ChatModel>,
+    profiles: TaskProfiles,
+    prompt_budget: PromptBudget,
+    embedding_model: Arc<CachedEmbeddingModel>,
+    /// The qdrant collection the long term memories are stored in
+    memory_collection: String,
+    database: Arc<Database>,
+    usage_meter: Arc<UsageMeter>,
+    x_budget: XBudget,
+    user_id: String,
+    eth_private_key: SecretKey,
+    config: AgentConfig,
+}
+
+impl Agent {
+    pub async fn new(
+        account_details: XAccountDetails,
+        config: Config,
+        eth_private_key: SecretKey,
+        prompts: Prompts,
+        chat_health: watch::Sender<Vec<ProviderHealth>>,
+        usage_report: watch::Sender<UsageReport>,
+    ) -> Result<Self> {
+        let agent_config = AgentConfig::from(&config);
+
+        let XAccountDetails {
+            x_consumer_key,
+            x_consumer_secret,
+            x_access_token,
+            x_access_token_secret,
+            x_username,
+            ..
+        } = account_details;
+
+        let env = ENV.get().expect("unreachable");
+
+        let twitter_client = TwitterClient::new(
+            cassette::replay_url("twitter")
+                .or(config.x_api_url.clone())
+                .unwrap_or(X_API_URL.into()),
+            x_consumer_key.expose_secret().into(),
+            x_consumer_secret.expose_secret().into(),
+            x_access_token.expose_secret().into(),
+            x_access_token_secret.expose_secret().into(),
+        );
+        let user_id = twitter_client
+            .get_user_info_by_username(&x_username)
+            .await?
+            .id;
+
+        // TODO: we should use Docker compose to start the DB before starting this agent.
+        // For testing, pull the DB docker image with
+        // `docker pull qdrant/qdrant`
+        // and then run it with
+        // `docker run -p 6333:6333 -p 6334:6334 qdrant/qdrant`
+        let database = Arc::new(if cassette::is_replaying() {
+            // A replay has to run offline, the memories only live as long as the replay
+            Database::with_in_memory_vectors(PathBuf::from(&config.kv_db_path))?
+        } else {
+            Database::new("http://localhost:6334", PathBuf::from(&config.kv_db_path))?
+        }); // TODO: get url from config
+
+        let usage_meter = Arc::new(UsageMeter::new(
+            database.clone(),
+            config.usage.clone().unwrap_or_default(),
+            usage_report,
+        )?);
+        let x_budget = XBudget::new(database.clone(), config.x_api_budget.clone());
+
+        let mut chat_models = vec![config.chat_model.clone().unwrap_or_default()];
+        chat_models.extend(config.fallback_chat_models.clone().unwrap_or_default());
+        let chat_model = Box::new(build_fallback_chat_model(
+            &chat_models,
+            &config.circuit_breaker.clone().unwrap_or_default(),
+            env,
+            usage_meter.clone(),
+            chat_health,
+        )?);
+        let profiles = TaskProfiles::resolve(
+            &config.model_profiles.clone().unwrap_or_default(),
+            &config.task_profiles.clone().unwrap_or_default(),
+        )?;
+        let prompt_budget = PromptBudget::new(config.prompt_budget.clone().unwrap_or_default())?;
+        let embedding_model = Arc::new(CachedEmbeddingModel::new(
+            build_embedding_model(
+                &config.embedding_model.clone().unwrap_or_default(),
+                env,
+                usage_meter.clone(),
+            )?,
+            database.clone(),
+        ));
+
+        // Create collection for vector db with the embedding size of the model we use. If the
+        // stored memories were embedded with another model, re-embed them in the background.
+        let memory_collection = match database
+            .open_collection(
+                LONG_TERM_MEMORY,
+                embedding_model.name(),
+                embedding_model.dimension(),
+            )
+            .await?
+        {
+            CollectionState::Ready(info) => info.vector_collection,
+            CollectionState::Mismatch { current, target } => {
+                tracing::warn!(
+                    "Long term memories were embedded with {} ({} dimensions) but the agent uses {} ({} dimensions), re-embedding them",
+                    current.embedding_model,
+                    current.dimension,
+                    target.embedding_model,
+                    target.dimension
+                );
+                let vector_collection = target.vector_collection.clone();
+                let reembed = reembed_collection(
+                    database.clone(),
+                    embedding_model.clone(),
+                    LONG_TERM_MEMORY.into(),
+                    current,
+                    target,
+                );
+                // A failed run is picked up again on the next start
+                tokio::spawn(async move {
+                    if let Err(e) = reembed.await {
+                        tracing::error!("Re-embedding long term memories failed: {e:?}");
+                    }
+                });
+                vector_collection
+            }
+        };
+
+        tracing::info!(
+            "Agent address used to sign the action log: 0x{}",
+            hex::encode(eth_private_key.public().address())
+        );
+
+        // Create/seed database of long term memories
+
+        // Do initial run
+
+        Ok(Self {
+            prompts,
+            twitter_client,
+            chat_model,
+            profiles,
+            prompt_budget,
+            embedding_model,
+            memory_collection,
+            database,
+            usage_meter,
+            x_budget,
+            user_id,
+            eth_private_key,
+            config: agent_config,
+        })
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        if self.usage_meter.state() == BudgetState::Exhausted {
+            tracing::warn!("LLM spend cap reached, skipping this run");
+            return Ok(());
+        }
+        match self.config.mode {
+            AgentMode::Pipeline => self.run_pipeline().await,
+            AgentMode::Tools => self.run_with_tools().await.map(|_| ()),
+        }
+    }
+
+    /// Runs the fixed steps: read, think, tweet, reply. Close to the spend cap the follow
+    /// decisions and the engagement with the timeline are skipped and the context is cut in half.
+    pub async fn run_pipeline(&self) -> Result<()> {
+        let degraded = self.usage_meter.state() == BudgetState::Degraded;
+        if degraded {
+            tracing::warn!("LLM spend is close to its cap, running degraded");
+        }
+
+        // Step 1: retrieve own recent posts
+        tracing::info!("Reading recent posts...");
+        let recent_tweets = self
+            .database
+            .get_recent_memories(self.config.num_recent_posts)?;
+
+        // Step 2: Fetch External Context(Notifications, timelines, and reply trees)
+        // Step 2.1: filter all of the notifications for ones that haven't been seen before
+        // Step 2.2: add to database every tweet id you have seen
+        // The reply tree of a mention is read in step 10, once we know which one to answer
+        tracing::info!("Get timeline tweets...");
+        let timeline_tweets = self
+            .get_timeline_tweets(self.config.max_timeline_tweets)
+            .await?;
+        tracing::info!("Get mentions...");
+        let mentions = self.get_mentions(self.config.max_num_mentions).await?;
+        tracing::info!("{mentions:?}");
+
+        let mut context = Vec::with_capacity(timeline_tweets.len() + mentions.len());
+        timeline_tweets
+            .iter()
+            .for_each(|t| context.push(t.to_string()));
+        mentions.iter().for_each(|t| context.push(t.to_string()));
+        if degraded {
+            context.truncate(context.len().div_ceil(2));
+        }
+        tracing::info!("{context:?}");
+
+        // Step 2.3: Check wallet address in posts and decide if we should take onchain action
+
+        // Step 2.4: Decide to follow any users
+        if degraded {
+            tracing::info!("Skipping follow decisions");
+        } else if let Err(e) = self.follow_users(&timeline_tweets, &mentions).await {
+            tracing::info!("Failed to follow user: {e:?}");
+        }
+
+        // Step 3: Generate Short-term memory
+        let short_term_memory = self.generate_short_term_memory(context.clone()).await?;
+        tracing::info!("Short term memory:");
+        tracing::info!("{short_term_memory}");
+
+        // Step 4: Create embedding for short term memory
+        // Step 5: Retrieve relevent long-term memories
+        let num_long_term_memories = if degraded {
+            self.config.num_long_term_memories.div_ceil(2)
+        } else {
+            self.config.num_long_term_memories
+        };
+        let long_term_memories = self
+            .get_long_term_memories(&short_term_memory, num_long_term_memories)
+            .await?;
+
+        // Step 6: Generate new post or reply
+        let recent_posts = recent_tweets
+            .iter()
+            .map(|t| t.to_string())
+            .collect::<Vec<String>>();
+        let (tweet_prompt, prompt_tokens) = self.prompts.get_tweet_prompt(
+            &self.prompt_budget,
+            self.profiles.tweet.max_tokens as usize,
+            short_term_memory,
+            long_term_memories,
+            recent_posts,
+            context,
+        );
+        tracing::info!(
+            "Tweet prompt tokens: {}",
+            serde_json::to_string(&prompt_tokens)?
+        );
+        let tweet = self
+            .chat_model
+            .generate_text(
+                &tweet_prompt,
+                "Write a tweet that is less than 240 characters based on the context",
+                &self.profiles.tweet,
+            )
+            .await?;
+        tracing::info!("Proposed tweet:");
+        tracing::info!("{tweet}");
+
+        // Step 7: Score siginigicance of the new post
+        // Also when degraded, scoring is cheap and nothing gets posted without it
+        let tweet_score = self.score_tweet(&tweet, 3).await?;
+        tracing::info!("Tweet score:");
+        tracing::info!("{tweet_score}");
+
+        // Step 8: Store the new post in long term memory if significant enough
+        if tweet_score >= self.config.min_storing_memory_score {
+            tracing::info!("Storing tweet in memory");
+            self.store_memory(&tweet, tweet_score).await?;
+        }
+
+        // Step 9: Submit Post
+        if tweet_score < self.config.min_posting_score {
+            tracing::info!("Tweet not significant enough to post");
+        } else if !self.x_budget.may_post()? {
+            tracing::warn!("X post allowance of today used up, not posting tweet");
+        } else {
+            tracing::info!("Posting tweet");
+            match self.twitter_client.post_tweet(&tweet).await {
+                Ok(sent_tweet) => self.record_sent_post(
+                    false,
+                    Action::Post,
+                    &format!("{}: {}", sent_tweet.id, sent_tweet.text),
+                ),
+                // Not worth failing the run for, there are still mentions to answer
+                Err(e) if is_duplicate(&e) || is_rate_limited(&e) => {
+                    tracing::warn!("Not posting tweet: {e}")
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Step 10: Respond to mentions with the reply chain of the best one
+        self.respond_to_mentions(&mentions, &tweet_prompt, 3)
+            .await?;
+
+        // Step 11: Like, retweet and quote timeline tweets
+        if degraded {
+            tracing::info!("Skipping engagement with the timeline");
+        } else if let Err(e) = self
+            .engage_with_timeline(&timeline_tweets, &tweet_prompt)
+            .await
+        {
+            tracing::warn!("Failed to engage with timeline: {e:?}");
+        }
+
+        tracing::info!(
+            "Embedding cache: {} hits, {} misses",
+            self.embedding_model.hits(),
+            self.embedding_model.misses()
+        );
+
+        Ok(())
+    }
+
+    /// Retrieves the latest tweets from the timeline.
+    /// Filters out tweets that have already been seen.
+    /// Marks retrieved tweets as seen.
+    pub async fn get_timeline_tweets(
+        &self,
+        max_timeline_tweets: usize,
+    ) -> Result<Vec<TimelineTweet>> {
+        let since_id = self.database.get_feed_checkpoint(TIMELINE_FEED)?;
+        let max_reads = self.x_budget.reads_left()?.min(MAX_FEED_TWEETS);
+        let feed = if max_reads == 0 {
+            tracing::warn!("X read allowance of today used up, using the buffered timeline");
+            Feed::default()
+        } else {
+            self.twitter_client
+                .get_new_timeline(&self.user_id, since_id.as_deref(), max_reads)
+                .await?
+        };
+        self.x_budget.record_reads(feed.tweets.len())?;
+        self.take_from_feed(TIMELINE_FEED, feed, max_timeline_tweets)
+    }
+
+    /// Retrieves the latest mentions.
+    /// Filters out tweets that have already been seen.
+    /// Marks retrieved tweets as seen.
+    pub async fn get_mentions(&self, max_num_mentions: usize) -> Result<Vec<Tweet>> {
+        let since_id = self.database.get_feed_checkpoint(MENTIONS_FEED)?;
+        let max_reads = self.x_budget.reads_left()?.min(MAX_FEED_TWEETS);
+        let feed = if max_reads < MIN_MENTIONS_PAGE {
+            tracing::warn!(
+                "{max_reads} reads left of today's X allowance, too few for a page of mentions, \
+                using the buffered mentions"
+            );
+            Feed::default()
+        } else {
+            self.twitter_client
+                .get_new_mentions(&self.user_id, since_id.as_deref(), max_reads)
+                .await?
+        };
+        self.x_budget.record_reads(feed.tweets.len())?;
+        self.take_from_feed(MENTIONS_FEED, feed, max_num_mentions)
+    }
+
+    /// Takes the `max` newest unseen tweets from the new tweets of a feed and its buffer. The
+    /// rest is buffered for the next runs and the feed is read from its newest tweet next time.
+    fn take_from_feed<T: FeedTweet + Serialize + DeserializeOwned>(
+        &self,
+        name: &str,
+        feed: Feed<T>,
+        max: usize,
+    ) -> Result<Vec<T>> {
+        let mut tweets = feed.tweets;
+        tweets.extend(self.database.get_feed_buffer::<T>(name)?);
+
+        let mut ids = HashSet::new();
+        let mut unseen = Vec::with_capacity(tweets.len());
+        for tweet in tweets {
+            if tweet.username().is_some()
+                && ids.insert(tweet.id().to_string())
+                && !self.database.tweet_id_exists(tweet.id())?
+            {
+                unseen.push(tweet);
+            }
+        }
+        let mut buffer = unseen.split_off(max.min(unseen.len()));
+        buffer.truncate(MAX_BUFFERED_TWEETS);
+
+        self.database.set_feed_buffer(name, &buffer)?;
+        if let Some(newest_id) = &feed.newest_id {
+            self.database.set_feed_checkpoint(name, newest_id)?;
+        }
+        for tweet in unseen.iter() {
+            self.database.insert_tweet_id(tweet.id())?;
+        }
+
+        Ok(unseen)
+    }
+
+    pub async fn generate_short_term_memory(&self, context: Vec<String>) -> Result<String> {
+        let (prompt_context, prompt_tokens) = self.prompts.get_short_term_memory_prompt(
+            &self.prompt_budget,
+            self.profiles.short_term_memory.max_tokens as usize,
+            context,
+        );
+        tracing::info!(
+            "Short term memory prompt tokens: {}",
+            serde_json::to_string(&prompt_tokens)?
+        );
+        // TODO: try multiple times?
+        self.chat_model
+            .generate_text(
+                &prompt_context,
+                "Respond only with your internal monologue based on the given context.",
+                &self.profiles.short_term_memory,
+            )
+            .await
+    }
+
+    /// Stores `content` in long term memory
+    pub async fn store_memory(&self, content: &str, score: u16) -> Result<()> {
+        let embedding = self.embedding_model.embed(content).await?;
+        let start = SystemTime::now();
+        let since_the_epoch = start.duration_since(UNIX_EPOCH)?;
+        let memory_id = since_the_epoch.as_millis();
+        self.database
+            .upsert_memories(
+                &self.memory_collection,
+                vec![Memory {
+                    data: MemoryData {
+                        id: memory_id,
+                        score,
+                        content: content.into(),
+                    },
+                    embedding: Embedding::new(memory_id, embedding),
+                }],
+            )
+            .await
+    }
+
+    pub async fn get_long_term_memories(
+        &self,
+        short_term_memory: &str,
+        num_long_term_memories: u64,
+    ) -> Result<Vec<String>> {
+        let embedding = self.embedding_model.embed(short_term_memory).await?;
+        // 0 serves as a dummy id
+        let short_term_mem_embd = Embedding::new(0, embedding);
+        let long_term_memories = self
+            .database
+            .get_k_most_similar_memories(
+                &self.memory_collection,
+                short_term_mem_embd,
+                num_long_term_memories,
+            )
+            .await?
+            .into_iter()
+            .map(|m| m.content)
+            .collect();
+        Ok(long_term_memories)
+    }
+
+    pub async fn follow_users(
+        &self,
+        timeline_tweets: &[TimelineTweet],
+        mentions: &[Tweet],
+    ) -> Result<()> {
+        let mut username_to_id = HashMap::new();
+        for tweet in timeline_tweets {
+            if let Some(username) = &tweet.username {
+                // Check if we are following this user already
+                if !self.database.user_id_exists(&tweet.author_id)? {
+                    username_to_id.insert(username.clone(), &tweet.author_id);
+                }
+            }
+        }
+        for tweet in mentions {
+            if let Some(username) = &tweet.username {
+                // Check if we are following this user already
+                if !self.database.user_id_exists(&tweet.author_id)? {
+                    username_to_id.insert(username.clone(), &tweet.author_id);
+                }
+            }
+        }
+        let usernames = username_to_id.keys().cloned().collect::<Vec<String>>();
+        let follow_prompt = self.prompts.get_follow_prompt(usernames);
+
+        tracing::info!("Deciding which user to follow...");
+        let FollowDecision { username } = generate_structured(
+            self.chat_model.as_ref(),
+            &follow_prompt,
+            "Pick one username from the list to follow.",
+            &self.profiles.follow,
+            3,
+            |decision: &FollowDecision| {
+                if !username_to_id.contains_key(&decision.username) {
+                    bail!(
+                        "{} is not one of the usernames in the list",
+                        decision.username
+                    );
+                }
+                Ok(())
+            },
+        )
+        .await?;
+        let target_user_id = username_to_id[&username];
+        tracing::info!("Following user: {username}");
+
+        self.twitter_client
+            .follow_user(&self.user_id, target_user_id)
+            .await?;
+        // Mark user_id as followed
+        self.database.insert_user_id(target_user_id)?;
+        self.record_action(Action::Follow, &format!("{target_user_id}: @{username}"))?;
+
+        Ok(())
+    }
+
+    pub async fn score_tweet(&self, tweet: &str, max_tries: u32) -> Result<u16> {
+        let TweetScore { score } = generate_structured(
+            self.chat_model.as_ref(),
+            tweet,
+            "Give the given memory a score from 1 to 10.",
+            &self.profiles.scoring,
+            max_tries,
+            |s: &TweetScore| check_score(s.score),
+        )
+        .await?;
+
+        Ok(score)
+    }
+
+    pub async fn respond_to_mentions(
+        &self,
+        mentions: &[Tweet],
+        context: &str,
+        max_tries: u32,
+    ) -> Result<()> {
+        if mentions.is_empty() {
+            return Ok(());
+        }
+        // Ranking the mentions is wasted when the reply can't be sent
+        if !self.x_budget.may_post()? {
+            tracing::warn!("X post allowance of today used up, not responding to mentions");
+            return Ok(());
+        }
+
+        let mut mentions_list = Vec::with_capacity(mentions.len());
+        let mut mentions_map = HashMap::with_capacity(mentions.len());
+        for mention in mentions {
+            mentions_list.push(format!("id: {}, tweet: {}", mention.id, mention.text));
+            mentions_map.insert(&mention.id, mention);
+        }
+        // The scores of a busy run don't fit into the few tokens a single decision needs
+        let ranking_profile = ModelProfile {
+            max_tokens: self
+                .profiles
+                .mention_ranking
+                .max_tokens
+                .max(MENTION_SCORE_TOKENS * mentions.len() as u32 + MENTION_SCORE_TOKENS),
+            ..self.profiles.mention_ranking.clone()
+        };
+        let (prompt_context, prompt_tokens) = self.prompts.get_mentions_prompt(
+            &self.prompt_budget,
+            ranking_profile.max_tokens as usize,
+            mentions_list,
+        );
+        tracing::info!(
+            "Mentions prompt tokens: {}",
+            serde_json::to_string(&prompt_tokens)?
+        );
+
+        let MentionScores { scores } = generate_structured(
+            self.chat_model.as_ref(),
+            &prompt_context,
+            "Give each of these tweets a score from 1 to 10.",
+            &ranking_profile,
+            max_tries,
+            |r: &MentionScores| {
+                for s in &r.scores {
+                    if !mentions_map.contains_key(&s.id) {
+                        bail!("{} is not the id of one of the tweets", s.id);
+                    }
+                    check_score(s.score)?;
+                }
+                Ok(())
+            },
+        )
+        .await?;
+
+        let Some(best) = scores.iter().max_by_key(|s| s.score) else {
+            return Ok(());
+        };
+        if best.score < self.config.min_mention_score as u16 {
+            tracing::info!("No mentions found that are worth our time.");
+            return Ok(());
+        }
+        let mention = mentions_map[&best.id];
+
+        let mut messages = vec![Message::system(context)];
+        messages.extend(self.get_conversation(mention).await);
+        messages.push(Message::user(
+            "Write a witty response to the last tweet of this conversation.",
+        ));
+
+        let mut tries = 0;
+        while tries < max_tries {
+            tries += 1;
+
+            let Ok(tweet) = self.chat_model.chat(&messages, &self.profiles.reply).await else {
+                continue;
+            };
+
+            match self
+                .twitter_client
+                .reply_to_tweet(&tweet, &mention.id)
+                .await
+            {
+                Ok(sent_tweet) => {
+                    tracing::info!("Sent response: {tweet}");
+                    self.record_sent_post(
+                        true,
+                        Action::Reply,
+                        &format!(
+                            "{} in reply to {}: {}",
+                            sent_tweet.id, mention.id, sent_tweet.text
+                        ),
+                    );
+                    return Ok(());
+                }
+                // Another try writes another text
+                Err(e) if is_duplicate(&e) => tracing::info!("Not sending response: {e}"),
+                // The rest of the run can still read and engage
+                Err(e) if is_rate_limited(&e) => {
+                    tracing::warn!("Not sending response: {e}");
+                    return Ok(());
+                }
+                // Trying again won't help with a deleted mention
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds the transcript of the reply chain that ends in `mention`. Our own tweets are
+    /// assistant messages, everyone else's are user messages named after their author.
+    pub async fn get_conversation(&self, mention: &Tweet) -> Vec<Message> {
+        // Every tweet up the chain is a read, cached ones aren't but we can't tell beforehand
+        let max_depth = match self.x_budget.reads_left() {
+            Ok(reads_left) => MAX_CONVERSATION_DEPTH.min(reads_left.saturating_add(1)),
+            Err(e) => {
+                tracing::warn!("Failed to check the X read allowance: {e:?}");
+                1
+            }
+        };
+        if max_depth == 1 {
+            tracing::warn!("X read allowance of today used up, replying without the conversation");
+        }
+        let chain = self
+            .twitter_client
+            .get_reply_chain(mention, max_depth)
+            .await;
+        if let Err(e) = self.x_budget.record_reads(chain.fetched) {
+            tracing::warn!("Failed to count X reads: {e:?}");
+        }
+
+        chain
+            .tweets
+            .into_iter()
+            .map(|tweet| to_message(tweet, &self.user_id))
+            .collect()
+    }
+
+    /// Books a post X accepted against the allowance and in the action log. The tweet is live
+    /// whatever happens here, so failures are only logged and the caller must not post again.
+    pub fn record_sent_post(&self, reply: bool, action: Action, details: &str) {
+        if let Err(e) = self.x_budget.record_post(reply) {
+            tracing::warn!("Failed to count a post against the X allowance: {e:?}");
+        }
+        if let Err(e) = self.record_action(action, details) {
+            tracing::warn!("Failed to log the action {details}: {e:?}");
+        }
+    }
+
+    /// Signs and appends an entry to the action log.
+    pub fn record_action(&self, action: Action, details: &str) -> Result<()> {
+        let entry = ActionLogEntry {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos(),
+            action,
+            details: details.into(),
+        };
+        let hash = Keccak256::digest(bincode::serialize(&entry)?);
+        let signature = self
+            .eth_private_key
+            .sign(&hash)
+            .map_err(|e| anyhow!("Failed to sign action log entry: {e:?}"))?;
+
+        let mut signature_bytes = Vec::with_capacity(65);
+        signature_bytes.extend_from_slice(&signature.r);
+        signature_bytes.extend_from_slice(&signature.s);
+        signature_bytes.push(signature.v);
+
+        self.database
+            .insert_action_log_entry(&SignedActionLogEntry {
+                entry,
+                signature: signature_bytes,
+            })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct TweetScore {
+    score: u16,
+}
+
+impl StructuredOutput for TweetScore {
+    const NAME: &'static str = "tweet_score";
+
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "score": { "type": "integer", "description": "Score from 1 to 10" }
+            },
+            "required": ["score"],
+            "additionalProperties": false
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct FollowDecision {
+    username: String,
+}
+
+impl StructuredOutput for FollowDecision {
+    const NAME: &'static str = "follow_decision";
+
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "username": { "type": "string", "description": "Username from the list, without the @" }
+            },
+            "required": ["username"],
+            "additionalProperties": false
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct MentionScores {
+    scores: Vec<MentionScore>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MentionScore {
+    id: String,
+    score: u16,
+}
+
+impl StructuredOutput for MentionScores {
+    const NAME: &'static str = "mention_scores";
+
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "scores": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "id": { "type": "string", "description": "Id of the tweet" },
+                            "score": { "type": "integer", "description": "Score from 1 to 10" }
+                        },
+                        "required": ["id", "score"],
+                        "additionalProperties": false
+                    }
+                }
+            },
+            "required": ["scores"],
+            "additionalProperties": false
+        })
+    }
+}
+
+fn check_score(score: u16) -> Result<()> {
+    if !(1..=10).contains(&score) {
+        bail!("score {score} is not between 1 and 10");
+    }
+    Ok(())
+}
+
+fn is_duplicate(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<TwitterError>() == Some(&TwitterError::DuplicateContent)
+}
+
+fn is_rate_limited(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<TwitterError>(),
+        Some(TwitterError::RateLimited { .. })
+    )
+}
+
+fn to_message(tweet: Tweet, user_id: &str) -> Message {
+    if tweet.author_id == user_id {
+        return Message::assistant(tweet.text);
+    }
+    match tweet.username {
+        Some(username) => Message::user(tweet.text).with_name(username),
+        None => Message::user(tweet.text),
+    }
+}
+
+struct AgentConfig {
+    max_num_mentions: usize,
+    max_timeline_tweets: usize,
+    num_long_term_memories: u64,
+    min_storing_memory_score: u16,
+    min_posting_score: u16,
+    num_recent_posts: usize,
+    min_mention_score: u8,
+    mode: AgentMode,
+    action_budget: u32,
+    engagement: EngagementConfig,
+    eth_rpc_url: String,
+}
+
+impl From<&Config> for AgentConfig {
+    fn from(value: &Config) -> Self {
+        Self {
+            max_num_mentions: value.max_num_mentions,
+            max_timeline_tweets: value.max_timeline_tweets,
+            num_long_term_memories: value.num_long_term_memories,
+            min_storing_memory_score: value.min_storing_memory_score,
+            min_posting_score: value.min_posting_score,
+            num_recent_posts: value.num_recent_posts,
+            min_mention_score: value.min_mention_score,
+            mode: value.agent_mode.unwrap_or_default(),
+            action_budget: value.action_budget.unwrap_or(5),
+            engagement: value.engagement.clone().unwrap_or_default(),
+            eth_rpc_url: value.eth_rpc_url.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use axum::{extract::State, routing::post, Json, Router};
+    use ethsign::SecretKey;
+    use tokio::sync::watch;
+
+    use super::{Agent, AgentConfig};
+    use crate::{
+        cassette::{serve, Cassette, Recorder},
+        config::Config,
+        db::{types::Action, Database},
+        embeddings::{cache::CachedEmbeddingModel, EmbeddingModel},
+        llm::{ChatModel, Message, ModelProfile, Role, TaskProfileNames, TaskProfiles, Tool},
+        openai::OpenAIClient,
+        prompts::Prompts,
+        tokens::PromptBudget,
+        twitter::{api_types::TimelineTweet, fake::FakeX, TwitterClient},
+        usage::UsageMeter,
+        x_budget::XBudget,
+    };
+
+    /// Answers with the scripted responses in order and keeps the conversations it was sent
+    struct ScriptedModel {
+        responses: Mutex<Vec<String>>,
+        conversations: Arc<Mutex<Vec<Vec<Message>>>>,
+    }
+
+    #[async_trait]
+    impl ChatModel for ScriptedModel {
+        async fn chat(&self, messages: &[Message], _profile: &ModelProfile) -> Result<String> {
+            self.conversations.lock().unwrap().push(messages.to_vec());
+            Ok(self.responses.lock().unwrap().remove(0))
+        }
+
+        fn supports_tools(&self) -> bool {
+            true
+        }
+
+        /// The scripted response is the JSON of the assistant message, e.g. from `tool_calls`
+        async fn chat_with_tools(
+            &self,
+            messages: &[Message],
+            _tools: &[Tool],
+            _profile: &ModelProfile,
+        ) -> Result<Message> {
+            self.conversations.lock().unwrap().push(messages.to_vec());
+            let response = self.responses.lock().unwrap().remove(0);
+            Ok(serde_json::from_str(&response)?)
+        }
+    }
+
+    /// An assistant message that calls the tools with the arguments
+    fn tool_calls(calls: &[(&str, serde_json::Value)]) -> String {
+        let tool_calls = calls
+            .iter()
+            .enumerate()
+            .map(|(i, (name, arguments))| {
+                serde_json::json!({
+                    "id": format!("call_{i}"),
+                    "type": "function",
+                    "function": { "name": name, "arguments": arguments.to_string() }
+                })
+            })
+            .collect::<Vec<serde_json::Value>>();
+        serde_json::json!({ "role": "assistant", "content": "", "tool_calls": tool_calls })
+            .to_string()
+    }
+
+    /// Like `ScriptedModel`, but cuts the responses off at the `max_tokens` of the profile like
+    /// a real model does
+    struct TruncatingModel {
+        responses: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl ChatModel for TruncatingModel {
+        async fn chat(&self, _messages: &[Message], profile: &ModelProfile) -> Result<String> {
+            let bpe = tiktoken_rs::cl100k_base().unwrap();
+            let mut tokens = bpe.encode_with_special_tokens(&self.responses.lock().unwrap()[0]);
+            tokens.truncate(profile.max_tokens as usize);
+            let response = bpe.decode(tokens).unwrap();
+            self.responses.lock().unwrap().remove(0);
+            Ok(response)
+        }
+    }
+
+    struct NoEmbeddings;
+
+    #[async_trait]
+    impl EmbeddingModel for NoEmbeddings {
+        fn name(&self) -> &str {
+            "none"
+        }
+
+        fn dimension(&self) -> u64 {
+            1
+        }
+
+        async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+            Ok(texts.iter().map(|_| vec![0.0]).collect())
+        }
+    }
+
+    /// An agent that talks to `fake` as the user of `access_token`
+    fn test_agent(
+        fake: &FakeX,
+        access_token: &str,
+        user_id: &str,
+        responses: Vec<String>,
+        name: &str,
+    ) -> (Agent, Arc<Mutex<Vec<Vec<Message>>>>, PathBuf) {
+        let conversations = Arc::new(Mutex::new(Vec::new()));
+        let chat_model = Box::new(ScriptedModel {
+            responses: Mutex::new(responses),
+            conversations: conversations.clone(),
+        });
+        let (agent, path) = build_agent(
+            fake.client(access_token),
+            chat_model,
+            Box::new(NoEmbeddings),
+            user_id,
+            name,
+        );
+        (agent, conversations, path)
+    }
+
+    /// An agent with a kv db in the temp dir. The vectors are kept in memory, qdrant is never
+    /// connected to.
+    fn build_agent(
+        twitter_client: TwitterClient,
+        chat_model: Box<dyn ChatModel>,
+        embedding_model: Box<dyn EmbeddingModel>,
+        user_id: &str,
+        name: &str,
+    ) -> (Agent, PathBuf) {
+        let path = std::env::temp_dir().join(format!("agent-{name}-{}", std::process::id()));
+        let database = Arc::new(Database::with_in_memory_vectors(path.clone()).unwrap());
+        let config = Config::load();
+
+        let agent = Agent {
+            prompts: Prompts::load(),
+            twitter_client,
+            chat_model,
+            profiles: TaskProfiles::resolve(&HashMap::new(), &TaskProfileNames::default()).unwrap(),
+            prompt_budget: PromptBudget::new(Default::default()).unwrap(),
+            embedding_model: Arc::new(CachedEmbeddingModel::new(embedding_model, database.clone())),
+            memory_collection: "long-term-memory".into(),
+            usage_meter: Arc::new(
+                UsageMeter::new(
+                    database.clone(),
+                    Default::default(),
+                    watch::channel(Default::default()).0,
+                )
+                .unwrap(),
+            ),
+            x_budget: XBudget::new(database.clone(), None),
+            database,
+            user_id: user_id.into(),
+            eth_private_key: SecretKey::from_raw(&[1; 32]).unwrap(),
+            config: AgentConfig::from(&config),
+        };
+        (agent, path)
+    }
+
+    /// Answers like OpenAI would. Structured requests get an answer that fits their schema, the
+    /// engagement choices like the first tweet in the prompt. Everything else gets the same text.
+    async fn start_openai_stub(requests: Arc<AtomicUsize>) -> String {
+        async fn chat_completions(
+            State(requests): State<Arc<AtomicUsize>>,
+            Json(body): Json<serde_json::Value>,
+        ) -> Json<serde_json::Value> {
+            requests.fetch_add(1, Ordering::SeqCst);
+            let prompt = body["messages"].to_string();
+            let first_id = prompt
+                .split("id: ")
+                .nth(1)
+                .map(|rest| {
+                    rest.chars()
+                        .take_while(char::is_ascii_digit)
+                        .collect::<String>()
+                })
+                .unwrap_or_default();
+            let content = match body["response_format"]["json_schema"]["name"].as_str() {
+                Some("follow_decision") => r#"{"username": "alice"}"#.to_string(),
+                Some("tweet_score") => r#"{"score": 8}"#.to_string(),
+                Some("engagement_choices") => {
+                    format!(r#"{{"likes": ["{first_id}"], "retweets": [], "quotes": []}}"#)
+                }
+                _ => "gm, the timeline is quiet today".to_string(),
+            };
+            Json(serde_json::json!({
+                "id": "chatcmpl-1",
+                "model": "stub-model",
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": content },
+                    "finish_reason": "stop"
+                }]
+            }))
+        }
+
+        async fn embeddings(
+            State(requests): State<Arc<AtomicUsize>>,
+            Json(body): Json<serde_json::Value>,
+        ) -> Json<serde_json::Value> {
+            requests.fetch_add(1, Ordering::SeqCst);
+            let data = body["input"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .enumerate()
+                .map(|(index, _)| {
+                    serde_json::json!({ "object": "embedding", "index": index, "embedding": [1.0, 0.0] })
+                })
+                .collect::<Vec<serde_json::Value>>();
+            Json(serde_json::json!({
+                "object": "list",
+                "data": data,
+                "model": "text-embedding-3-small",
+                "usage": { "prompt_tokens": 1, "total_tokens": 1 }
+            }))
+        }
+
+        let app = Router::new()
+            .route("/v1/chat/completions", post(chat_completions))
+            .route("/v1/embeddings", post(embeddings))
+            .with_state(requests);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        format!("http://{addr}/v1")
+    }
+
+    #[tokio::test]
+    async fn test_tweets_are_seen_once_across_runs() {
+        let fake = FakeX::start().await.unwrap();
+        let agent_id = fake.add_user("agent", "agent-token");
+        let alice_id = fake.add_user("alice", "alice-token");
+        let bob_id = fake.add_user("bob", "bob-token");
+        fake.follow(&agent_id, &alice_id);
+        let (agent, _, path) = test_agent(&fake, "agent-token", &agent_id, vec![], "seen");
+
+        let gm = fake.tweet(&alice_id, "gm");
+        let mention = fake.tweet(&bob_id, "@agent what's up");
+        let timeline = agent.get_timeline_tweets(50).await.unwrap();
+        assert_eq!(timeline.len(), 1);
+        assert_eq!(timeline[0].id, gm);
+        assert_eq!(timeline[0].username.as_deref(), Some("alice"));
+        let mentions = agent.get_mentions(50).await.unwrap();
+        assert_eq!(mentions.len(), 1);
+        assert_eq!(mentions[0].id, mention);
+
+        // The next run only gets what is new
+        let gn = fake.tweet(&alice_id, "gn");
+        let timeline = agent.get_timeline_tweets(50).await.unwrap();
+        assert_eq!(timeline.len(), 1);
+        assert_eq!(timeline[0].id, gn);
+        assert!(agent.get_mentions(50).await.unwrap().is_empty());
+
+        drop(agent);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_new_tweets_are_paged_and_buffered() {
+        let fake = FakeX::start().await.unwrap();
+        let agent_id = fake.add_user("agent", "agent-token");
+        let alice_id = fake.add_user("alice", "alice-token");
+        fake.follow(&agent_id, &alice_id);
+        let (agent, _, path) = test_agent(&fake, "agent-token", &agent_id, vec![], "paged");
+
+        fake.tweet(&alice_id, "gm");
+        assert_eq!(agent.get_timeline_tweets(10).await.unwrap().len(), 1);
+
+        // More than a page of new tweets, the ones that aren't used wait in the buffer
+        let ids = (0..150)
+            .map(|i| fake.tweet(&alice_id, &format!("tweet {i}")))
+            .collect::<Vec<String>>();
+        let timeline = agent.get_timeline_tweets(10).await.unwrap();
+        assert_eq!(timeline.len(), 10);
+        assert_eq!(timeline[0].id, ids[149]);
+        let buffer = agent
+            .database
+            .get_feed_buffer::<TimelineTweet>("timeline")
+            .unwrap();
+        assert_eq!(buffer.len(), 140);
+        assert_eq!(buffer[139].id, ids[0]);
+
+        // New tweets come first, then the buffered ones
+        let new = fake.tweet(&alice_id, "gn");
+        let timeline = agent.get_timeline_tweets(2).await.unwrap();
+        assert_eq!(timeline[0].id, new);
+        assert_eq!(timeline[1].id, ids[139]);
+
+        drop(agent);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_follows_the_chosen_user() {
+        let fake = FakeX::start().await.unwrap();
+        let agent_id = fake.add_user("agent", "agent-token");
+        let alice_id = fake.add_user("alice", "alice-token");
+        let bob_id = fake.add_user("bob", "bob-token");
+        fake.follow(&agent_id, &alice_id);
+        fake.tweet(&alice_id, "gm");
+        fake.tweet(&bob_id, "@agent follow me");
+        let (agent, _, path) = test_agent(
+            &fake,
+            "agent-token",
+            &agent_id,
+            vec![
+                // Not in the list, the model is asked again
+                r#"{"username": "carol"}"#.into(),
+                r#"{"username": "bob"}"#.into(),
+            ],
+            "follow",
+        );
+
+        let timeline = agent.get_timeline_tweets(50).await.unwrap();
+        let mentions = agent.get_mentions(50).await.unwrap();
+        agent.follow_users(&timeline, &mentions).await.unwrap();
+
+        assert!(fake.is_following(&agent_id, &bob_id));
+        assert!(agent.database.user_id_exists(&bob_id).unwrap());
+        let log = agent.database.get_recent_action_log_entries(10).unwrap();
+        assert!(matches!(log[0].entry.action, Action::Follow));
+
+        drop(agent);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_engages_with_a_tweet_once() {
+        let fake = FakeX::start().await.unwrap();
+        let agent_id = fake.add_user("agent", "agent-token");
+        let alice_id = fake.add_user("alice", "alice-token");
+        fake.follow(&agent_id, &alice_id);
+        let gm = fake.tweet(&alice_id, "gm");
+        let eth = fake.tweet(&alice_id, "$ETH 10k");
+        let (mut agent, _, path) = test_agent(
+            &fake,
+            "agent-token",
+            &agent_id,
+            vec![
+                // Two retweets are over the limit, the model is asked again
+                format!(r#"{{"likes": ["{gm}"], "retweets": ["{gm}", "{eth}"], "quotes": []}}"#),
+                format!(r#"{{"likes": ["{gm}"], "retweets": [], "quotes": ["{eth}"]}}"#),
+                "told you".into(),
+            ],
+            "engage",
+        );
+        agent.config.engagement.max_quotes = 1;
+
+        let timeline = agent.get_timeline_tweets(50).await.unwrap();
+        agent
+            .engage_with_timeline(&timeline, "context")
+            .await
+            .unwrap();
+
+        assert!(fake.has_liked(&agent_id, &gm));
+        assert!(!fake.has_retweeted(&agent_id, &eth));
+        let quote = &fake.tweets_by(&agent_id)[0];
+        assert_eq!(quote.text, "told you");
+        assert_eq!(quote.quoted.as_deref(), Some(eth.as_str()));
+        let log = agent.database.get_recent_action_log_entries(10).unwrap();
+        assert!(log.iter().any(|e| e.entry.action == Action::Like));
+        assert!(log.iter().any(|e| e.entry.action == Action::Quote));
+
+        // Both tweets were engaged with, the model isn't asked about them again
+        agent
+            .engage_with_timeline(&timeline, "context")
+            .await
+            .unwrap();
+
+        drop(agent);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_like_does_not_stop_the_engagement() {
+        let fake = FakeX::start().await.unwrap();
+        let agent_id = fake.add_user("agent", "agent-token");
+        let alice_id = fake.add_user("alice", "alice-token");
+        fake.follow(&agent_id, &alice_id);
+        let gm = fake.tweet(&alice_id, "gm");
+        let gn = fake.tweet(&alice_id, "gn");
+        let eth = fake.tweet(&alice_id, "$ETH 10k");
+        fake.limit("POST /2/users/:id/likes", 0);
+        let (mut agent, _, path) = test_agent(
+            &fake,
+            "agent-token",
+            &agent_id,
+            vec![
+                format!(
+                    r#"{{"likes": ["{gm}", "{gn}"], "retweets": ["{gn}"], "quotes": ["{eth}"]}}"#
+                ),
+                "told you".into(),
+            ],
+            "engage-limited",
+        );
+        agent.config.engagement.max_likes = 2;
+        agent.config.engagement.max_quotes = 1;
+
+        let timeline = agent.get_timeline_tweets(50).await.unwrap();
+        agent
+            .engage_with_timeline(&timeline, "context")
+            .await
+            .unwrap();
+
+        // X refused the first like, the second one isn't tried within the same window
+        assert_eq!(fake.calls("POST /2/users/:id/likes"), 1);
+        assert!(!fake.has_liked(&agent_id, &gm));
+        assert!(!fake.has_liked(&agent_id, &gn));
+        assert!(fake.has_retweeted(&agent_id, &gn));
+        let quote = &fake.tweets_by(&agent_id)[0];
+        assert_eq!(quote.quoted.as_deref(), Some(eth.as_str()));
+        let log = agent.database.get_recent_action_log_entries(10).unwrap();
+        assert!(log.iter().all(|e| e.entry.action != Action::Like));
+        assert!(log.iter().any(|e| e.entry.action == Action::Retweet));
+        assert!(log.iter().any(|e| e.entry.action == Action::Quote));
+
+        drop(agent);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tool_calls_stay_within_the_action_budget() {
+        let fake = FakeX::start().await.unwrap();
+        let agent_id = fake.add_user("agent", "agent-token");
+        let alice_id = fake.add_user("alice", "alice-token");
+        fake.follow(&agent_id, &alice_id);
+        let gm = fake.tweet(&alice_id, "gm");
+        let gn = fake.tweet(&alice_id, "gn");
+        let (mut agent, conversations, path) = test_agent(
+            &fake,
+            "agent-token",
+            &agent_id,
+            vec![
+                tool_calls(&[
+                    ("post", serde_json::json!({ "text": "gm to you too" })),
+                    ("post", serde_json::json!({ "text": "a".repeat(300) })),
+                ]),
+                // The score of the posted tweet
+                r#"{"score": 8}"#.into(),
+                tool_calls(&[
+                    ("like", serde_json::json!({ "tweet_id": gm })),
+                    ("like", serde_json::json!({ "tweet_id": gn })),
+                ]),
+            ],
+            "tools",
+        );
+        agent.config.action_budget = 3;
+
+        let trace = agent.run_with_tools().await.unwrap();
+
+        let steps = trace
+            .steps
+            .iter()
+            .map(|s| (s.tool.as_str(), s.ok))
+            .collect::<Vec<(&str, bool)>>();
+        assert_eq!(
+            steps,
+            vec![
+                ("post", true),
+                ("post", false),
+                ("like", true),
+                ("like", false)
+            ]
+        );
+        let posted = fake.tweets_by(&agent_id);
+        assert_eq!(posted.len(), 1);
+        assert_eq!(posted[0].text, "gm to you too");
+        assert_eq!(
+            trace.steps[0].result,
+            format!("Posted tweet {}", posted[0].id)
+        );
+        assert_eq!(
+            trace.steps[3].result,
+            "Error: The action budget of this run is used up"
+        );
+        assert!(fake.has_liked(&agent_id, &gm));
+        assert!(!fake.has_liked(&agent_id, &gn));
+        // The run ended with the budget, not with a summary of the model
+        assert_eq!(trace.summary, None);
+
+        // The model saw the error of the tool call it got wrong
+        let conversations = conversations.lock().unwrap();
+        let last = conversations.last().unwrap();
+        let error = last
+            .iter()
+            .find(|m| m.role == Role::Tool && !m.content.starts_with("Posted"));
+        assert_eq!(
+            error.map(|m| m.content.as_str()),
+            Some("Error: The text has to be less than 240 characters")
+        );
+
+        // Both timeline tweets are in the prompt, nothing was posted before
+        assert!(trace.prompt_tokens.total > 0);
+        let sections = trace
+            .prompt_tokens
+            .sections
+            .iter()
+            .map(|s| (s.name, s.items, s.dropped))
+            .collect::<Vec<(&str, usize, usize)>>();
+        assert_eq!(sections[0], ("external_context", 2, 0));
+        assert_eq!(sections[1], ("recent_posts", 0, 0));
+        drop(conversations);
+
+        drop(agent);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replies_to_the_best_mention() {
+        let fake = FakeX::start().await.unwrap();
+        let agent_id = fake.add_user("agent", "agent-token");
+        let alice_id = fake.add_user("alice", "alice-token");
+        let bob_id = fake.add_user("bob", "bob-token");
+        let own = fake.tweet(&agent_id, "eth is going to 10k");
+        let boring = fake.tweet(&bob_id, "@agent hi");
+        let good = fake.reply(&alice_id, "what makes you so sure?", &own);
+        let (agent, conversations, path) = test_agent(
+            &fake,
+            "agent-token",
+            &agent_id,
+            vec![
+                format!(
+                    r#"{{"scores": [{{"id": "{boring}", "score": 3}}, {{"id": "{good}", "score": 8}}]}}"#
+                ),
+                "vibes".into(),
+            ],
+            "reply",
+        );
+
+        let mentions = agent.get_mentions(50).await.unwrap();
+        assert_eq!(mentions.len(), 2);
+        agent
+            .respond_to_mentions(&mentions, "context", 3)
+            .await
+            .unwrap();
+
+        let reply = &fake.tweets_by(&agent_id)[0];
+        assert_eq!(reply.text, "vibes");
+        assert_eq!(reply.in_reply_to.as_deref(), Some(good.as_str()));
+        // The reply was written knowing the whole thread
+        let conversation = conversations.lock().unwrap().last().unwrap().clone();
+        let thread = conversation
+            .iter()
+            .filter(|m| m.role != Role::System)
+            .map(|m| (m.role, m.content.as_str()))
+            .collect::<Vec<(Role, &str)>>();
+        assert_eq!(thread[0], (Role::Assistant, "eth is going to 10k"));
+        assert_eq!(thread[1], (Role::User, "what makes you so sure?"));
+
+        drop(agent);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ranks_a_busy_run_of_mentions() {
+        let fake = FakeX::start().await.unwrap();
+        let agent_id = fake.add_user("agent", "agent-token");
+        let bob_id = fake.add_user("bob", "bob-token");
+        let mention_ids = (0..50)
+            .map(|i| fake.tweet(&bob_id, &format!("@agent hi #{i}")))
+            .collect::<Vec<String>>();
+        let scores = mention_ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| {
+                format!(
+                    r#"{{"id": "{id}", "score": {}}}"#,
+                    if i == 42 { 9 } else { 2 }
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+        let chat_model = Box::new(TruncatingModel {
+            responses: Mutex::new(vec![format!(r#"{{"scores": [{scores}]}}"#), "vibes".into()]),
+        });
+        let (agent, path) = build_agent(
+            fake.client("agent-token"),
+            chat_model,
+            Box::new(NoEmbeddings),
+            &agent_id,
+            "busy-mentions",
+        );
+
+        let mentions = agent.get_mentions(50).await.unwrap();
+        assert_eq!(mentions.len(), 50);
+        agent
+            .respond_to_mentions(&mentions, "context", 1)
+            .await
+            .unwrap();
+
+        let reply = &fake.tweets_by(&agent_id)[0];
+        assert_eq!(reply.text, "vibes");
+        assert_eq!(reply.in_reply_to.as_deref(), Some(mention_ids[42].as_str()));
+
+        drop(agent);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_post_does_not_stop_the_run() {
+        let fake = FakeX::start().await.unwrap();
+        let agent_id = fake.add_user("agent", "agent-token");
+        let alice_id = fake.add_user("alice", "alice-token");
+        fake.follow(&agent_id, &alice_id);
+        let gm = fake.tweet(&alice_id, "gm");
+        fake.limit("POST /2/tweets", 0);
+        let openai_url = start_openai_stub(Arc::new(AtomicUsize::new(0))).await;
+        let openai = || OpenAIClient::new("".into(), openai_url.clone());
+        let (agent, path) = build_agent(
+            fake.client("agent-token"),
+            Box::new(openai()),
+            Box::new(openai()),
+            &agent_id,
+            "post-limit",
+        );
+        agent
+            .database
+            .create_collection(&agent.memory_collection, 1)
+            .await
+            .unwrap();
+
+        agent.run_pipeline().await.unwrap();
+
+        // The tweet was refused, the timeline was still engaged with
+        assert_eq!(fake.calls("POST /2/tweets"), 1);
+        assert!(fake.tweets_by(&agent_id).is_empty());
+        assert!(fake.has_liked(&agent_id, &gm));
+
+        drop(agent);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replays_a_recorded_run() {
+        let fake = FakeX::start().await.unwrap();
+        let agent_id = fake.add_user("agent", "agent-token");
+        let alice_id = fake.add_user("alice", "alice-token");
+        fake.follow(&agent_id, &alice_id);
+        let gm = fake.tweet(&alice_id, "gm");
+        fake.limit("POST /2/tweets", 10);
+        let requests = Arc::new(AtomicUsize::new(0));
+        let openai_url = start_openai_stub(requests.clone()).await;
+
+        // Record a run against the fake and the stub
+        let cassette_path =
+            std::env::temp_dir().join(format!("cassette-run-{}.json", std::process::id()));
+        let recorder = Arc::new(Recorder::new(cassette_path.clone(), Vec::new()));
+        let openai =
+            || OpenAIClient::new("".into(), openai_url.clone()).with_recorder(recorder.clone());
+        let (agent, path) = build_agent(
+            fake.client("agent-token").with_recorder(recorder.clone()),
+            Box::new(openai()),
+            Box::new(openai()),
+            &agent_id,
+            "record",
+        );
+        agent
+            .database
+            .create_collection(&agent.memory_collection, 1)
+            .await
+            .unwrap();
+        agent.run_pipeline().await.unwrap();
+        let recorded = agent.database.get_recent_action_log_entries(10).unwrap();
+        drop(agent);
+        std::fs::remove_dir_all(path).unwrap();
+
+        assert!(fake.has_liked(&agent_id, &gm));
+        assert!(fake.is_following(&agent_id, &alice_id));
+        assert_eq!(fake.calls("POST /2/tweets"), 1);
+        let llm_requests = requests.load(Ordering::SeqCst);
+
+        // Replay it from a fresh kv db, without the fake or the stub
+        let cassette = Cassette::load(&cassette_path).unwrap();
+        std::fs::remove_file(&cassette_path).unwrap();
+        let replay_url = serve(cassette).await.unwrap();
+        let openai = || OpenAIClient::new("".into(), format!("{replay_url}/openai"));
+        let twitter_client = TwitterClient::new(
+            format!("{replay_url}/twitter"),
+            "".into(),
+            "".into(),
+            "".into(),
+            "".into(),
+        );
+        let (agent, path) = build_agent(
+            twitter_client,
+            Box::new(openai()),
+            Box::new(openai()),
+            &agent_id,
+            "replay",
+        );
+        agent
+            .database
+            .create_collection(&agent.memory_collection, 1)
+            .await
+            .unwrap();
+        agent.run_pipeline().await.unwrap();
+        let replayed = agent.database.get_recent_action_log_entries(10).unwrap();
+        drop(agent);
+        std::fs::remove_dir_all(path).unwrap();
+
+        assert_eq!(fake.calls("POST /2/tweets"), 1);
+        assert_eq!(requests.load(Ordering::SeqCst), llm_requests);
+        let actions = |log: &[crate::db::types::SignedActionLogEntry]| {
+            log.iter()
+                .map(|e| (e.entry.action.clone(), e.entry.details.clone()))
+                .collect::<Vec<(Action, String)>>()
+        };
+        assert_eq!(actions(&replayed), actions(&recorded));
+        assert!(replayed.iter().any(|e| e.entry.action == Action::Post));
+        assert!(replayed.iter().any(|e| e.entry.action == Action::Like));
+    }
+}
@@ -0,0 +1,501 @@
+// Agent mode where the model decides what to do instead of following the fixed pipeline.
+// The model gets the agent's context and a set of tools and calls them one after another until
+// it is done or the action budget of the run is used up. Every call ends up in the run trace.
+
+use anyhow::{anyhow, bail, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    db::types::Action,
+    llm::{FunctionCall, Message, Tool},
+    tokens::PromptTokens,
+    usage::BudgetState,
+};
+
+use super::{
+    engagement::{LIKE, QUOTE, RETWEET},
+    Agent,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum AgentMode {
+    /// The fixed steps of `Agent::run_pipeline`
+    #[default]
+    #[serde(rename = "pipeline")]
+    Pipeline,
+    /// The model picks its actions from the tools
+    #[serde(rename = "tools")]
+    Tools,
+}
+
+/// Everything the model did in a run
+#[derive(Serialize, Debug, Default)]
+pub struct RunTrace {
+    /// Token counts of the prompt the run started with
+    pub prompt_tokens: PromptTokens,
+    pub steps: Vec<TraceStep>,
+    /// The model's own summary, missing if the run ended because the budget was used up
+    pub summary: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TraceStep {
+    pub tool: String,
+    pub arguments: String,
+    pub result: String,
+    pub ok: bool,
+}
+
+#[derive(Deserialize)]
+struct PostArgs {
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct ReplyArgs {
+    tweet_id: String,
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct TweetIdArgs {
+    tweet_id: String,
+}
+
+#[derive(Deserialize)]
+struct FollowArgs {
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct QueryArgs {
+    query: String,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse {
+    result: String,
+}
+
+fn tool(name: &str, description: &str, properties: serde_json::Value) -> Tool {
+    let required = properties
+        .as_object()
+        .map(|p| p.keys().cloned().collect::<Vec<String>>())
+        .unwrap_or_default();
+    Tool {
+        name: name.into(),
+        description: description.into(),
+        parameters: serde_json::json!({
+            "type": "object",
+            "properties": properties,
+            "required": required,
+            "additionalProperties": false
+        }),
+    }
+}
+
+/// The tools the model can call in tool mode
+pub fn tools() -> Vec<Tool> {
+    vec![
+        tool(
+            "post",
+            "Post a new tweet",
+            serde_json::json!({
+                "text": { "type": "string", "description": "Text of the tweet, less than 240 characters" }
+            }),
+        ),
+        tool(
+            "reply",
+            "Reply to a tweet",
+            serde_json::json!({
+                "tweet_id": { "type": "string", "description": "Id of the tweet to reply to" },
+                "text": { "type": "string", "description": "Text of the reply, less than 240 characters" }
+            }),
+        ),
+        tool(
+            "like",
+            "Like a tweet",
+            serde_json::json!({
+                "tweet_id": { "type": "string", "description": "Id of the tweet to like" }
+            }),
+        ),
+        tool(
+            "unlike",
+            "Take back the like of a tweet",
+            serde_json::json!({
+                "tweet_id": { "type": "string", "description": "Id of the tweet to unlike" }
+            }),
+        ),
+        tool(
+            "retweet",
+            "Retweet a tweet to your followers",
+            serde_json::json!({
+                "tweet_id": { "type": "string", "description": "Id of the tweet to retweet" }
+            }),
+        ),
+        tool(
+            "unretweet",
+            "Undo a retweet",
+            serde_json::json!({
+                "tweet_id": { "type": "string", "description": "Id of the tweet you retweeted" }
+            }),
+        ),
+        tool(
+            "quote",
+            "Post a tweet that quotes another tweet with your comment",
+            serde_json::json!({
+                "tweet_id": { "type": "string", "description": "Id of the tweet to quote" },
+                "text": { "type": "string", "description": "Your comment, less than 240 characters" }
+            }),
+        ),
+        tool(
+            "follow",
+            "Follow a user",
+            serde_json::json!({
+                "username": { "type": "string", "description": "Username without the @" }
+            }),
+        ),
+        tool(
+            "search",
+            "Search the tweets of the last 7 days",
+            serde_json::json!({
+                "query": { "type": "string", "description": "X search query" }
+            }),
+        ),
+        tool(
+            "recall_memory",
+            "Recall the long-term memories most related to a topic",
+            serde_json::json!({
+                "query": { "type": "string", "description": "What to remember" }
+            }),
+        ),
+        tool(
+            "check_wallet",
+            "Check the ETH balance of your wallet",
+            serde_json::json!({}),
+        ),
+    ]
+}
+
+fn parse_args<T: DeserializeOwned>(call: &FunctionCall) -> Result<T> {
+    serde_json::from_str(&call.arguments)
+        .map_err(|e| anyhow!("Invalid arguments for {}: {e}", call.name))
+}
+
+fn check_tweet_length(text: &str) -> Result<()> {
+    if text.chars().count() >= 240 {
+        bail!("The text has to be less than 240 characters");
+    }
+    Ok(())
+}
+
+impl Agent {
+    /// Lets the model decide what to do with the tools until it is done or the action budget
+    /// of the run is used up.
+    pub async fn run_with_tools(&self) -> Result<RunTrace> {
+        let recent_posts = self
+            .database
+            .get_recent_memories(self.config.num_recent_posts)?
+            .iter()
+            .map(|t| t.to_string())
+            .collect::<Vec<String>>();
+        let timeline_tweets = self
+            .get_timeline_tweets(self.config.max_timeline_tweets)
+            .await?;
+        let mentions = self.get_mentions(self.config.max_num_mentions).await?;
+
+        let mut context = Vec::with_capacity(timeline_tweets.len() + mentions.len());
+        timeline_tweets
+            .iter()
+            .for_each(|t| context.push(format!("id: {}, {t}", t.id)));
+        mentions
+            .iter()
+            .for_each(|t| context.push(format!("id: {}, {t}", t.id)));
+
+        // Close to the spend cap the model gets half the actions
+        let action_budget = match self.usage_meter.state() {
+            BudgetState::Degraded => self.config.action_budget.div_ceil(2),
+            _ => self.config.action_budget,
+        };
+        let (prompt, prompt_tokens) = self.prompts.get_tool_agent_prompt(
+            &self.prompt_budget,
+            self.profiles.tool_agent.max_tokens as usize,
+            action_budget,
+            recent_posts,
+            context,
+        );
+        let mut messages = vec![
+            Message::system(prompt),
+            Message::user("What do you want to do?"),
+        ];
+
+        let tools = tools();
+        let mut trace = RunTrace {
+            prompt_tokens,
+            ..Default::default()
+        };
+        let mut actions_left = action_budget;
+        // Every turn with tool calls uses up at least one action, so this ends
+        while actions_left > 0 {
+            let response = self
+                .chat_model
+                .chat_with_tools(&messages, &tools, &self.profiles.tool_agent)
+                .await?;
+            let tool_calls = response.tool_calls.clone();
+            if tool_calls.is_empty() {
+                trace.summary = Some(response.content);
+                break;
+            }
+            messages.push(response);
+
+            for call in tool_calls {
+                let result = if actions_left == 0 {
+                    Err(anyhow!("The action budget of this run is used up"))
+                } else {
+                    actions_left -= 1;
+                    self.call_tool(&call.function).await
+                };
+                let (result, ok) = match result {
+                    Ok(result) => (result, true),
+                    Err(e) => (format!("Error: {e}"), false),
+                };
+                tracing::info!(
+                    "Tool call {}({}): {result}",
+                    call.function.name,
+                    call.function.arguments
+                );
+
+                messages.push(Message::tool(call.id, result.clone()));
+                trace.steps.push(TraceStep {
+                    tool: call.function.name,
+                    arguments: call.function.arguments,
+                    result,
+                    ok,
+                });
+            }
+        }
+
+        tracing::info!("Run trace: {}", serde_json::to_string(&trace)?);
+        Ok(trace)
+    }
+
+    /// Runs a tool and returns the result that is shown to the model
+    async fn call_tool(&self, call: &FunctionCall) -> Result<String> {
+        match call.name.as_str() {
+            "post" => {
+                let PostArgs { text } = parse_args(call)?;
+                check_tweet_length(&text)?;
+                self.check_post_allowance()?;
+                let sent_tweet = self.twitter_client.post_tweet(&text).await?;
+                // From here on the tweet is live, an error would make the model post it again
+                self.record_sent_post(
+                    false,
+                    Action::Post,
+                    &format!("{}: {}", sent_tweet.id, sent_tweet.text),
+                );
+                match self.score_tweet(&text, 3).await {
+                    Ok(score) if score >= self.config.min_storing_memory_score => {
+                        if let Err(e) = self.store_memory(&text, score).await {
+                            tracing::warn!("Failed to remember tweet {}: {e:?}", sent_tweet.id);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => tracing::warn!("Failed to score tweet {}: {e:?}", sent_tweet.id),
+                }
+                Ok(format!("Posted tweet {}", sent_tweet.id))
+            }
+            "reply" => {
+                let ReplyArgs { tweet_id, text } = parse_args(call)?;
+                check_tweet_length(&text)?;
+                self.check_post_allowance()?;
+                let sent_tweet = self.twitter_client.reply_to_tweet(&text, &tweet_id).await?;
+                self.record_sent_post(
+                    true,
+                    Action::Reply,
+                    &format!(
+                        "{} in reply to {tweet_id}: {}",
+                        sent_tweet.id, sent_tweet.text
+                    ),
+                );
+                Ok(format!("Posted reply {}", sent_tweet.id))
+            }
+            "like" => {
+                let TweetIdArgs { tweet_id } = parse_args(call)?;
+                if self.database.engagement_exists(LIKE, &tweet_id)? {
+                    return Ok(format!("You already liked tweet {tweet_id}"));
+                }
+                self.twitter_client
+                    .like_tweet(&self.user_id, &tweet_id)
+                    .await?;
+                self.database.insert_engagement(LIKE, &tweet_id)?;
+                self.record_action(Action::Like, &tweet_id)?;
+                Ok(format!("Liked tweet {tweet_id}"))
+            }
+            // An undone like or retweet stays in the db, so the tweet isn't liked or retweeted again
+            "unlike" => {
+                let TweetIdArgs { tweet_id } = parse_args(call)?;
+                self.twitter_client
+                    .unlike_tweet(&self.user_id, &tweet_id)
+                    .await?;
+                self.record_action(Action::Unlike, &tweet_id)?;
+                Ok(format!("Unliked tweet {tweet_id}"))
+            }
+            "retweet" => {
+                let TweetIdArgs { tweet_id } = parse_args(call)?;
+                if self.database.engagement_exists(RETWEET, &tweet_id)? {
+                    return Ok(format!("You already retweeted tweet {tweet_id}"));
+                }
+                self.twitter_client
+                    .retweet(&self.user_id, &tweet_id)
+                    .await?;
+                self.database.insert_engagement(RETWEET, &tweet_id)?;
+                self.record_action(Action::Retweet, &tweet_id)?;
+                Ok(format!("Retweeted tweet {tweet_id}"))
+            }
+            "unretweet" => {
+                let TweetIdArgs { tweet_id } = parse_args(call)?;
+                self.twitter_client
+                    .unretweet(&self.user_id, &tweet_id)
+                    .await?;
+                self.record_action(Action::Unretweet, &tweet_id)?;
+                Ok(format!("Undid the retweet of tweet {tweet_id}"))
+            }
+            "quote" => {
+                let ReplyArgs { tweet_id, text } = parse_args(call)?;
+                check_tweet_length(&text)?;
+                if self.database.engagement_exists(QUOTE, &tweet_id)? {
+                    return Ok(format!("You already quoted tweet {tweet_id}"));
+                }
+                self.check_post_allowance()?;
+                let sent_tweet = self.twitter_client.quote_tweet(&text, &tweet_id).await?;
+                if let Err(e) = self.database.insert_engagement(QUOTE, &tweet_id) {
+                    tracing::warn!("Failed to remember the quote of tweet {tweet_id}: {e:?}");
+                }
+                self.record_sent_post(
+                    false,
+                    Action::Quote,
+                    &format!("{} quoting {tweet_id}: {}", sent_tweet.id, sent_tweet.text),
+                );
+                Ok(format!("Posted quote {}", sent_tweet.id))
+            }
+            "follow" => {
+                let FollowArgs { username } = parse_args(call)?;
+                let username = username.trim_start_matches('@');
+                let user = self
+                    .twitter_client
+                    .get_user_info_by_username(username)
+                    .await?;
+                if self.database.user_id_exists(&user.id)? {
+                    return Ok(format!("You already follow @{username}"));
+                }
+                self.twitter_client
+                    .follow_user(&self.user_id, &user.id)
+                    .await?;
+                self.database.insert_user_id(&user.id)?;
+                self.record_action(Action::Follow, &format!("{}: @{username}", user.id))?;
+                Ok(format!("Followed @{username}"))
+            }
+            "search" => {
+                let QueryArgs { query } = parse_args(call)?;
+                if self.x_budget.reads_left()? < 10 {
+                    bail!("You can't search X anymore today");
+                }
+                let tweets = self.twitter_client.search_recent_tweets(&query, 10).await?;
+                self.x_budget.record_reads(tweets.len())?;
+                if tweets.is_empty() {
+                    return Ok("No tweets found".into());
+                }
+                Ok(tweets
+                    .iter()
+                    .map(|t| {
+                        format!(
+                            "id: {}, @{}: {}",
+                            t.id,
+                            t.username.as_deref().unwrap_or("unknown"),
+                            t.text
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n"))
+            }
+            "recall_memory" => {
+                let QueryArgs { query } = parse_args(call)?;
+                let memories = self
+                    .get_long_term_memories(&query, self.config.num_long_term_memories)
+                    .await?;
+                if memories.is_empty() {
+                    return Ok("You don't remember anything about that".into());
+                }
+                Ok(memories.join("\n"))
+            }
+            "check_wallet" => {
+                let balance = self.get_wallet_balance().await?;
+                Ok(format!("Your wallet holds {balance} ETH"))
+            }
+            other => bail!("There is no tool called {other}"),
+        }
+    }
+
+    fn check_post_allowance(&self) -> Result<()> {
+        if !self.x_budget.may_post()? {
+            bail!("You can't post on X anymore today");
+        }
+        Ok(())
+    }
+
+    /// ETH balance of the agent's address
+    async fn get_wallet_balance(&self) -> Result<f64> {
+        let address = format!("0x{}", hex::encode(self.eth_private_key.public().address()));
+        let body = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "eth_getBalance",
+            "params": [address, "latest"],
+            "id": 1
+        });
+
+        let response = reqwest::Client::new()
+            .post(&self.config.eth_rpc_url)
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(|e| anyhow!("{e:?}"))?
+            .json::<RpcResponse>()
+            .await
+            .map_err(|e| anyhow!("{e:?}"))?;
+
+        let wei = u128::from_str_radix(response.result.trim_start_matches("0x"), 16)?;
+        Ok(wei as f64 / 1e18)
+    }
+}
+
+#[test]
+fn test_tool_definitions() {
+    let tools = tools();
+
+    let reply = tools.iter().find(|t| t.name == "reply").unwrap();
+    let mut required = reply.parameters["required"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| r.as_str().unwrap())
+        .collect::<Vec<&str>>();
+    required.sort();
+    assert_eq!(required, vec!["text", "tweet_id"]);
+
+    let check_wallet = tools.iter().find(|t| t.name == "check_wallet").unwrap();
+    assert_eq!(check_wallet.parameters["required"], serde_json::json!([]));
+
+    let call = FunctionCall {
+        name: "reply".into(),
+        arguments: r#"{"tweet_id": "1", "text": "gm"}"#.into(),
+    };
+    let ReplyArgs { tweet_id, text } = parse_args(&call).unwrap();
+    assert_eq!((tweet_id.as_str(), text.as_str()), ("1", "gm"));
+
+    let call = FunctionCall {
+        name: "reply".into(),
+        arguments: r#"{"text": "gm"}"#.into(),
+    };
+    assert!(parse_args::<ReplyArgs>(&call).is_err());
+}
@@ -0,0 +1,231 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use reqwest::Client;
+
+use crate::{
+    cassette::{self, Recorder},
+    llm::{error::read_response, ChatModel, Message, ModelProfile, Role},
+    secret::Secret,
+    usage::UsageMeter,
+};
+
+use self::api_types::{ApiResponse, ContentBlock};
+mod api_types;
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Client for Anthropic's Messages API
+pub struct AnthropicClient {
+    base_url: String,
+    anthropic_api_key: Secret,
+    model: String,
+    usage_meter: Option<Arc<UsageMeter>>,
+    recorder: Option<Arc<Recorder>>,
+    client: Client,
+}
+
+impl AnthropicClient {
+    pub fn new(anthropic_api_key: Secret, base_url: String, model: String) -> Self {
+        let client = Client::new();
+        Self {
+            base_url,
+            anthropic_api_key,
+            model,
+            usage_meter: None,
+            recorder: cassette::recorder(),
+            client,
+        }
+    }
+
+    /// Records the tokens of every call in `usage_meter`
+    pub fn with_usage_meter(mut self, usage_meter: Arc<UsageMeter>) -> Self {
+        self.usage_meter = Some(usage_meter);
+        self
+    }
+
+    /// Records every request and response in the cassette of `recorder`
+    pub fn with_recorder(mut self, recorder: Arc<Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    pub async fn create_message(
+        &self,
+        messages: &[Message],
+        profile: &ModelProfile,
+    ) -> Result<ApiResponse> {
+        let url = format!("{}/messages", self.base_url);
+
+        let (system, messages) = to_anthropic_messages(messages);
+        let mut body = serde_json::json!({
+            "system": system,
+            "messages": messages,
+            "model": profile.model.as_deref().unwrap_or(&self.model),
+            "max_tokens": profile.max_tokens,
+            "temperature": profile.temperature,
+        });
+        // Anthropic has no seeded sampling
+        if let Some(top_p) = profile.top_p {
+            body["top_p"] = top_p.into();
+        }
+        if let Some(top_k) = profile.top_k {
+            body["top_k"] = top_k.into();
+        }
+        if !profile.stop.is_empty() {
+            body["stop_sequences"] = profile.stop.clone().into();
+        }
+
+        let body = body.to_string();
+        let response = self
+            .client
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .header("x-api-key", self.anthropic_api_key.expose_secret())
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .body(body.clone())
+            .send()
+            .await
+            .map_err(|e| anyhow!("{e:?}"))?;
+        let res = read_response::<ApiResponse>(
+            self.recorder.as_deref(),
+            "anthropic",
+            &self.base_url,
+            &body,
+            response,
+        )
+        .await?;
+
+        if let Some(usage_meter) = &self.usage_meter {
+            usage_meter.record(
+                "anthropic",
+                &res.model,
+                &profile.task,
+                res.usage.input_tokens as u64,
+                res.usage.output_tokens as u64,
+            );
+        }
+        Ok(res)
+    }
+}
+
+/// Anthropic takes the system prompt separately, has no names and wants the conversation to
+/// start with a user message and alternate between user and assistant.
+/// Names become a prefix of the content and consecutive messages of one role are merged.
+fn to_anthropic_messages(messages: &[Message]) -> (String, Vec<serde_json::Value>) {
+    let mut system = Vec::new();
+    let mut turns: Vec<(Role, String)> = Vec::new();
+    for message in messages {
+        let content = match &message.name {
+            Some(name) => format!("@{name}: {}", message.content),
+            None => message.content.clone(),
+        };
+        // Tool calls are not supported by this client, tool results can only come from the user
+        let role = match message.role {
+            Role::Tool => Role::User,
+            role => role,
+        };
+        match role {
+            Role::System => system.push(content),
+            role => match turns.last_mut() {
+                Some((last_role, last_content)) if *last_role == role => {
+                    last_content.push_str("\n\n");
+                    last_content.push_str(&content);
+                }
+                _ => turns.push((role, content)),
+            },
+        }
+    }
+    if turns
+        .first()
+        .is_some_and(|(role, _)| *role == Role::Assistant)
+    {
+        turns.insert(
+            0,
+            (
+                Role::User,
+                "(The conversation starts with your message)".into(),
+            ),
+        );
+    }
+
+    let turns = turns
+        .into_iter()
+        .map(|(role, content)| serde_json::json!({ "role": role, "content": content }))
+        .collect();
+    (system.join("\n\n"), turns)
+}
+
+#[async_trait]
+impl ChatModel for AnthropicClient {
+    async fn chat(&self, messages: &[Message], profile: &ModelProfile) -> Result<String> {
+        let res = self.create_message(messages, profile).await?;
+        let text = res
+            .content
+            .into_iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text { text } => Some(text),
+                ContentBlock::Other => None,
+            })
+            .collect::<Vec<String>>()
+            .join("");
+        if text.is_empty() {
+            return Err(anyhow!("Anthropic API response contained no text"));
+        }
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        anthropic::{to_anthropic_messages, AnthropicClient},
+        llm::{ChatModel, Message, ModelProfile},
+    };
+
+    #[test]
+    fn test_to_anthropic_messages() {
+        let (system, messages) = to_anthropic_messages(&[
+            Message::system("be nice"),
+            Message::assistant("gm"),
+            Message::user("gm").with_name("alice"),
+            Message::user("gm to you too").with_name("bob"),
+            Message::assistant("wagmi"),
+        ]);
+
+        assert_eq!(system, "be nice");
+        assert_eq!(
+            serde_json::Value::Array(messages),
+            serde_json::json!([
+                { "role": "user", "content": "(The conversation starts with your message)" },
+                { "role": "assistant", "content": "gm" },
+                { "role": "user", "content": "@alice: gm\n\n@bob: gm to you too" },
+                { "role": "assistant", "content": "wagmi" },
+            ])
+        );
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_generate_text() {
+        let base_url = "https://api.anthropic.com/v1".to_string();
+        let anthropic_api_key = "".into();
+        let client = AnthropicClient::new(
+            anthropic_api_key,
+            base_url,
+            "claude-3-5-sonnet-latest".to_string(),
+        );
+
+        let res = client
+            .generate_text(
+                "hey shitalik, when does ethereum go to zero?",
+                "write a witty response to this tweet",
+                &ModelProfile::creative(),
+            )
+            .await
+            .unwrap();
+
+        println!("{res:?}");
+    }
+}
@@ -1,6 +1,16 @@
-use axum::{response::IntoResponse, routing::get, Json, Router};
+use axum::{
+    extract::{FromRef, State},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use tokio::sync::watch;
 
-use crate::attestation::ra::ra_get_quote;
+use crate::{
+    attestation::ra::ra_get_quote,
+    llm::fallback::{BreakerState, ProviderHealth},
+    usage::UsageReport,
+};
 
 use serde::Serialize;
 
@@ -23,18 +33,54 @@ pub struct QuoteResponse {
     pub quote: String,
 }
 
-pub async fn health_checker_handler() -> impl IntoResponse {
-    const MESSAGE: &str = "Enclave is healthy!";
+#[derive(Clone)]
+pub struct AppState {
+    pub twitter_username: String,
+    /// Circuit breaker state of the chat providers, published by the agent
+    pub chat_health: watch::Receiver<Vec<ProviderHealth>>,
+    /// LLM token usage and spend, published by the agent
+    pub usage: watch::Receiver<UsageReport>,
+}
+
+impl FromRef<AppState> for String {
+    fn from_ref(state: &AppState) -> Self {
+        state.twitter_username.clone()
+    }
+}
+
+pub async fn health_checker_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let chat_providers = state.chat_health.borrow().clone();
+    // Before the agent is up there are no providers yet
+    let message = if !chat_providers.is_empty()
+        && chat_providers
+            .iter()
+            .all(|provider| provider.state == BreakerState::Open)
+    {
+        "Enclave is healthy, but all chat providers are failing!"
+    } else {
+        "Enclave is healthy!"
+    };
 
     let json_response = serde_json::json!({
         "status": "success",
-        "message": MESSAGE
+        "message": message,
+        "chat_providers": chat_providers
     });
 
     Json(json_response)
 }
 
-pub fn create_router(twitter_username: String) -> Router {
+/// Token usage and spend of the day and month for the operator. Like the other endpoints it is
+/// served to anyone who can reach port 8000, there are no per-call details or prompts in it.
+pub async fn usage_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.usage.borrow().clone())
+}
+
+pub fn create_router(
+    twitter_username: String,
+    chat_health: watch::Receiver<Vec<ProviderHealth>>,
+    usage: watch::Receiver<UsageReport>,
+) -> Router {
     let cors = CorsLayer::new()
         .allow_origin("http://localhost:3000".parse::<HeaderValue>().unwrap())
         .allow_methods([Method::GET])
@@ -44,12 +90,21 @@ pub fn create_router(twitter_username: String) -> Router {
     Router::new()
         .route("/api/healthchecker", get(health_checker_handler))
         .route("/api/quote", get(ra_get_quote))
+        .route("/api/usage", get(usage_handler))
         .layer(cors)
-        .with_state(twitter_username)
+        .with_state(AppState {
+            twitter_username,
+            chat_health,
+            usage,
+        })
 }
 
-pub async fn quote_server(twitter_username: String) {
-    let app = create_router(twitter_username);
+pub async fn quote_server(
+    twitter_username: String,
+    chat_health: watch::Receiver<Vec<ProviderHealth>>,
+    usage: watch::Receiver<UsageReport>,
+) {
+    let app = create_router(twitter_username, chat_health, usage);
 
     tracing::info!("🚀 Quote Server started successfully");
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8000").await.unwrap();
@@ -0,0 +1,392 @@
+// Records the HTTP traffic of the API clients (X, Hyperbolic, OpenAI, Anthropic) into a cassette
+// file and replays it from a local server, so the agent can run offline and deterministically.
+// Headers are never recorded and every known secret is scrubbed from the recorded urls and bodies.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    extract::State,
+    http::{header::CONTENT_TYPE, Method, StatusCode, Uri},
+    response::IntoResponse,
+    Router,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    encumber::{FullAccountDetails, XAccountDetails},
+    env::EnvVariables,
+};
+
+const REDACTED: &str = "[REDACTED]";
+
+static CASSETTE: OnceLock<Mode> = OnceLock::new();
+
+enum Mode {
+    Record(Arc<Recorder>),
+    /// Base url of the local replay server
+    Replay(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CassetteMode {
+    /// Save every request and response to the cassette
+    #[serde(rename = "record")]
+    Record,
+    /// Serve the responses from the cassette instead of calling the APIs
+    #[serde(rename = "replay")]
+    Replay,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CassetteConfig {
+    pub mode: CassetteMode,
+    pub path: PathBuf,
+}
+
+/// A request to one of the APIs and the response we got
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Interaction {
+    /// "twitter", "hyperbolic", "openai" or "anthropic"
+    pub service: String,
+    pub method: String,
+    /// Path and query relative to the base url of the service
+    pub path: String,
+    pub request_body: Option<String>,
+    pub status: u16,
+    pub response_body: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+impl Cassette {
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read cassette {}", path.display()))?;
+        serde_json::from_str(&data).map_err(|e| anyhow!("{e:?}"))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("failed to write cassette {}", path.display()))
+    }
+}
+
+/// Appends every interaction to the cassette file as it happens
+pub struct Recorder {
+    path: PathBuf,
+    secrets: Vec<String>,
+    cassette: Mutex<Cassette>,
+}
+
+impl Recorder {
+    /// `secrets` are replaced with "[REDACTED]" wherever they show up
+    pub fn new(path: PathBuf, secrets: Vec<String>) -> Self {
+        Self {
+            path,
+            secrets: secrets.into_iter().filter(|s| !s.is_empty()).collect(),
+            cassette: Mutex::new(Cassette::default()),
+        }
+    }
+
+    pub fn record(&self, mut interaction: Interaction) -> Result<()> {
+        interaction.path = self.scrub(&interaction.path);
+        interaction.request_body = interaction.request_body.map(|body| self.scrub(&body));
+        interaction.response_body = self.scrub(&interaction.response_body);
+
+        let mut cassette = self.cassette.lock().unwrap();
+        cassette.interactions.push(interaction);
+        cassette.save(&self.path)
+    }
+
+    fn scrub(&self, text: &str) -> String {
+        self.secrets.iter().fold(text.to_string(), |text, secret| {
+            text.replace(secret, REDACTED)
+        })
+    }
+}
+
+/// Starts recording or replaying. Has to be called before the clients are created.
+pub async fn init(config: &CassetteConfig, secrets: Vec<String>) -> Result<()> {
+    let mode = match config.mode {
+        CassetteMode::Record => {
+            tracing::info!("Recording API traffic to {}", config.path.display());
+            Mode::Record(Arc::new(Recorder::new(config.path.clone(), secrets)))
+        }
+        CassetteMode::Replay => {
+            let base_url = serve(Cassette::load(&config.path)?).await?;
+            tracing::info!(
+                "Replaying API traffic from {} on {base_url}",
+                config.path.display()
+            );
+            Mode::Replay(base_url)
+        }
+    };
+    CASSETTE
+        .set(mode)
+        .map_err(|_| anyhow!("The cassette is already set up"))
+}
+
+/// The recorder clients record their traffic with, if we are recording
+pub fn recorder() -> Option<Arc<Recorder>> {
+    match CASSETTE.get() {
+        Some(Mode::Record(recorder)) => Some(recorder.clone()),
+        _ => None,
+    }
+}
+
+/// The base url that replaces the API of `service`, if we are replaying
+pub fn replay_url(service: &str) -> Option<String> {
+    match CASSETTE.get() {
+        Some(Mode::Replay(base_url)) => Some(format!("{base_url}/{service}")),
+        _ => None,
+    }
+}
+
+/// Whether the APIs are replaced by a cassette
+pub fn is_replaying() -> bool {
+    matches!(CASSETTE.get(), Some(Mode::Replay(_)))
+}
+
+/// The replay server doesn't check any keys, they were scrubbed from the cassette anyway
+pub fn replay_env() -> EnvVariables {
+    EnvVariables {
+        hyperbolic_api_key: Some(REDACTED.into()),
+        open_ai_api_key: Some(REDACTED.into()),
+        anthropic_api_key: Some(REDACTED.into()),
+        chat_api_key: None,
+    }
+}
+
+/// Stands in for the encumbered account when replaying. Only the username has to match the
+/// recording, the credentials are never checked.
+pub fn replay_account(x_username: &str) -> XAccountDetails {
+    XAccountDetails {
+        x_email: String::new(),
+        x_username: x_username.into(),
+        x_password: REDACTED.into(),
+        x_consumer_key: REDACTED.into(),
+        x_consumer_secret: REDACTED.into(),
+        x_access_token: REDACTED.into(),
+        x_access_token_secret: REDACTED.into(),
+    }
+}
+
+/// Everything the agent knows that must not end up in a cassette
+pub fn known_secrets(account_details: &FullAccountDetails, env: &EnvVariables) -> Vec<String> {
+    let x_account = &account_details.x_account;
+    let mut secrets = vec![
+        x_account.x_password.expose_secret().to_string(),
+        x_account.x_consumer_key.expose_secret().to_string(),
+        x_account.x_consumer_secret.expose_secret().to_string(),
+        x_account.x_access_token.expose_secret().to_string(),
+        x_account.x_access_token_secret.expose_secret().to_string(),
+        account_details.email_password.expose_secret().to_string(),
+    ];
+    for key in [
+        &env.hyperbolic_api_key,
+        &env.open_ai_api_key,
+        &env.anthropic_api_key,
+        &env.chat_api_key,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        secrets.push(key.expose_secret().to_string());
+    }
+    secrets
+}
+
+/// Reads the JSON body of `response` and records the exchange if there is a `recorder`.
+/// `base_url` is the url of the service the request went to.
+pub async fn read_json<T: DeserializeOwned>(
+    recorder: Option<&Recorder>,
+    service: &str,
+    base_url: &str,
+    method: &str,
+    request_body: Option<&str>,
+    response: reqwest::Response,
+) -> Result<T> {
+    let url = response.url().to_string();
+    let status = response.status().as_u16();
+    let body = response.text().await.map_err(|e| anyhow!("{e:?}"))?;
+
+    if let Some(recorder) = recorder {
+        let interaction = Interaction {
+            service: service.into(),
+            method: method.into(),
+            path: url.strip_prefix(base_url).unwrap_or(&url).into(),
+            request_body: request_body.map(str::to_string),
+            status,
+            response_body: body.clone(),
+        };
+        if let Err(e) = recorder.record(interaction) {
+            tracing::error!("Failed to record {method} {url}: {e:?}");
+        }
+    }
+
+    serde_json::from_str::<T>(&body).map_err(|e| anyhow!("{e:?}: {body}"))
+}
+
+struct Replay {
+    interactions: Vec<Interaction>,
+    used: Vec<bool>,
+}
+
+/// Serves the responses of `cassette` on a local port and returns its base url. The api of a
+/// service is served under `/{service}`. Requests are matched by method and path in the order
+/// they were recorded, once all matches are used up the last one is repeated.
+pub async fn serve(cassette: Cassette) -> Result<String> {
+    async fn replay(
+        State(state): State<Arc<Mutex<Replay>>>,
+        method: Method,
+        uri: Uri,
+    ) -> impl IntoResponse {
+        let mut state = state.lock().unwrap();
+        let path_and_query = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        let (service, path) = path_and_query[1..]
+            .split_once('/')
+            .map(|(service, path)| (service, format!("/{path}")))
+            .unwrap_or((&path_and_query[1..], String::new()));
+
+        let matches = state
+            .interactions
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| i.service == service && i.method == method.as_str() && i.path == path)
+            .map(|(index, _)| index)
+            .collect::<Vec<usize>>();
+        let Some(index) = matches
+            .iter()
+            .find(|index| !state.used[**index])
+            .or(matches.last())
+            .copied()
+        else {
+            tracing::warn!("No recorded interaction for {method} {path_and_query}");
+            return (
+                StatusCode::NOT_FOUND,
+                [(CONTENT_TYPE, "application/json")],
+                serde_json::json!({ "error": format!("no recorded interaction for {method} {path_and_query}") })
+                    .to_string(),
+            );
+        };
+
+        state.used[index] = true;
+        let interaction = &state.interactions[index];
+        (
+            StatusCode::from_u16(interaction.status).unwrap_or(StatusCode::OK),
+            [(CONTENT_TYPE, "application/json")],
+            interaction.response_body.clone(),
+        )
+    }
+
+    let used = vec![false; cassette.interactions.len()];
+    let state = Arc::new(Mutex::new(Replay {
+        interactions: cassette.interactions,
+        used,
+    }));
+    let app = Router::new().fallback(replay).with_state(state);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    Ok(format!("http://{addr}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use axum::{routing::post, Json, Router};
+
+    use crate::{
+        cassette::{serve, Cassette, Recorder},
+        llm::{ChatModel, ModelProfile},
+        openai::OpenAIClient,
+    };
+
+    async fn start_stub_server(requests: Arc<AtomicUsize>) -> String {
+        let app = Router::new().route(
+            "/v1/chat/completions",
+            post(move || {
+                let requests = requests.clone();
+                async move {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    Json(serde_json::json!({
+                        "id": "chatcmpl-1",
+                        "model": "stub-model",
+                        "choices": [{
+                            "index": 0,
+                            "message": { "role": "assistant", "content": "gm sk-secret" },
+                            "finish_reason": "stop"
+                        }]
+                    }))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        format!("http://{addr}/v1")
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("cassette-{}.json", std::process::id()));
+        let requests = Arc::new(AtomicUsize::new(0));
+        let base_url = start_stub_server(requests.clone()).await;
+        let recorder = Arc::new(Recorder::new(path.clone(), vec!["sk-secret".into()]));
+
+        let client = OpenAIClient::new("sk-secret".into(), base_url)
+            .with_chat_model("stub-model".into())
+            .with_recorder(recorder);
+        let profile = ModelProfile::deterministic();
+        let recorded = client
+            .generate_text("be nice", "say gm", &profile)
+            .await
+            .unwrap();
+        assert_eq!(recorded, "gm sk-secret");
+
+        let cassette = Cassette::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let interaction = &cassette.interactions[0];
+        assert_eq!(interaction.service, "openai");
+        assert_eq!(interaction.path, "/chat/completions");
+        assert!(interaction
+            .request_body
+            .as_ref()
+            .unwrap()
+            .contains("say gm"));
+        assert!(!interaction.response_body.contains("sk-secret"));
+
+        let replay_url = serve(cassette).await.unwrap();
+        let client = OpenAIClient::new("".into(), format!("{replay_url}/openai"))
+            .with_chat_model("stub-model".into());
+        let replayed = client
+            .generate_text("be nice", "say gm", &profile)
+            .await
+            .unwrap();
+        assert_eq!(replayed, "gm [REDACTED]");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // Unknown requests are not answered
+        let client = OpenAIClient::new("".into(), format!("{replay_url}/hyperbolic"));
+        assert!(client
+            .generate_text("be nice", "say gm", &profile)
+            .await
+            .is_err());
+    }
+}
@@ -1,15 +1,32 @@
 // The config file for this app acts as a typical .env file. Alot of API keys are needed for this agent
 
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    agent::{engagement::EngagementConfig, tools::AgentMode},
+    cassette::CassetteConfig,
+    embeddings::EmbeddingModelConfig,
+    llm::{fallback::CircuitBreakerConfig, ChatModelConfig, ModelProfile, TaskProfileNames},
+    password::PasswordPolicy,
+    secret::Secret,
+    tokens::PromptBudgetConfig,
+    usage::UsageConfig,
+    x_budget::XBudgetConfig,
+};
+
 const CONFIG: &str = include_str!("../config.toml");
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Config {
     pub email: String,
-    pub email_password: String,
+    #[serde(skip_serializing)]
+    pub email_password: Secret,
     pub x_username: String,
-    pub x_password: String,
+    #[serde(skip_serializing)]
+    pub x_password: Secret,
+    pub x_api_url: Option<String>,
     pub eth_rpc_url: String,
     pub kv_db_path: String,
     pub min_storing_memory_score: u16,
@@ -22,7 +39,27 @@ pub struct Config {
     pub scroll_sleep: Option<(u64, u64)>,
     pub scroll_duration: Option<(u64, u64)>,
     pub run_sleep: Option<(u64, u64)>,
+    pub api_health_check_interval: Option<u64>,
+    pub browser_health_check_interval: Option<u64>,
+    pub browser_health_retry_interval: Option<u64>,
+    pub mailbox_health_check_interval: Option<u64>,
     pub release_credentials: u64,
+    pub recovery_public_key: String,
+    pub x_password_policy: Option<PasswordPolicy>,
+    pub email_password_policy: Option<PasswordPolicy>,
+    pub chat_model: Option<ChatModelConfig>,
+    pub fallback_chat_models: Option<Vec<ChatModelConfig>>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    pub model_profiles: Option<HashMap<String, ModelProfile>>,
+    pub task_profiles: Option<TaskProfileNames>,
+    pub embedding_model: Option<EmbeddingModelConfig>,
+    pub agent_mode: Option<AgentMode>,
+    pub action_budget: Option<u32>,
+    pub engagement: Option<EngagementConfig>,
+    pub prompt_budget: Option<PromptBudgetConfig>,
+    pub usage: Option<UsageConfig>,
+    pub x_api_budget: Option<XBudgetConfig>,
+    pub cassette: Option<CassetteConfig>,
 }
 
 impl Config {
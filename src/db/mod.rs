@@ -1,38 +1,69 @@
 use std::path::PathBuf;
 
 use anyhow::{anyhow, Context, Result};
-use qdrant_client::{
-    qdrant::{
-        CreateCollectionBuilder, Distance, PointStruct, ScalarQuantizationBuilder,
-        SearchParamsBuilder, SearchPointsBuilder, UpsertPointsBuilder, VectorParamsBuilder,
+use qdrant_client::Qdrant;
+use rocksdb::{Direction, IteratorMode, Options, DB};
+use serde::{de::DeserializeOwned, Serialize};
+
+use self::{
+    types::{
+        CollectionInfo, CollectionState, Embedding, Memory, MemoryData, SignedActionLogEntry,
+        UsageRecord,
     },
-    Payload, Qdrant,
+    vectors::VectorStore,
 };
-use rocksdb::{IteratorMode, Options, DB};
-use uuid::Uuid;
-
-use self::types::{Embedding, Memory, MemoryData};
 
 pub mod types;
+pub mod vectors;
 
 const TWEET_IDS: &str = "tweet_ids";
 const MEMORY_DATA: &str = "memory-data";
 const USER_ID: &str = "user-id";
+const ACTION_LOG: &str = "action-log";
+const COLLECTION_INFO: &str = "collection-info";
+const EMBEDDING_CACHE: &str = "embedding-cache";
+const LLM_USAGE: &str = "llm-usage";
+const FEEDS: &str = "feeds";
+const X_USAGE: &str = "x-usage";
+const ENGAGEMENTS: &str = "engagements";
+
+// Collections created before we recorded the embedding model were always embedded with OpenAI
+const LEGACY_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+const LEGACY_DIMENSION: u64 = 1536;
 
 pub struct Database {
-    vec_db_client: Qdrant,
+    vec_db_client: VectorStore,
     kv_db: DB,
 }
 
 impl Database {
     pub fn new(vector_db_url: &str, kv_db_path: PathBuf) -> Result<Self> {
         let vec_db_client = Qdrant::from_url(vector_db_url).build()?;
+        Self::open(VectorStore::Qdrant(vec_db_client), kv_db_path)
+    }
+
+    /// Keeps the vectors in memory instead of qdrant, they are gone once the agent stops
+    pub fn with_in_memory_vectors(kv_db_path: PathBuf) -> Result<Self> {
+        Self::open(VectorStore::InMemory(Default::default()), kv_db_path)
+    }
 
+    fn open(vec_db_client: VectorStore, kv_db_path: PathBuf) -> Result<Self> {
         let mut db_options = Options::default();
         db_options.create_if_missing(true);
         db_options.create_missing_column_families(true);
 
-        let cf = vec![TWEET_IDS, MEMORY_DATA, USER_ID];
+        let cf = vec![
+            TWEET_IDS,
+            MEMORY_DATA,
+            USER_ID,
+            ACTION_LOG,
+            COLLECTION_INFO,
+            EMBEDDING_CACHE,
+            LLM_USAGE,
+            FEEDS,
+            X_USAGE,
+            ENGAGEMENTS,
+        ];
         let kv_db = DB::open_cf(&db_options, kv_db_path, cf)?;
 
         Ok(Self {
@@ -50,14 +81,92 @@ impl Database {
             Ok(())
         } else {
             self.vec_db_client
-                .create_collection(
-                    CreateCollectionBuilder::new(collection_name)
-                        .vectors_config(VectorParamsBuilder::new(vector_dim, Distance::Cosine))
-                        .quantization_config(ScalarQuantizationBuilder::default()),
-                )
+                .create_collection(collection_name, vector_dim)
+                .await
+        }
+    }
+
+    /// Opens the vector collection `collection_name` for vectors of `embedding_model` and checks
+    /// that the vectors already stored in it come from the same model.
+    pub async fn open_collection(
+        &self,
+        collection_name: &str,
+        embedding_model: &str,
+        dimension: u64,
+    ) -> Result<CollectionState> {
+        let current = match self.get_collection_info(collection_name)? {
+            Some(info) => info,
+            None => {
+                let info = if self
+                    .vec_db_client
+                    .collection_exists(collection_name)
+                    .await?
+                {
+                    CollectionInfo {
+                        vector_collection: collection_name.into(),
+                        embedding_model: LEGACY_EMBEDDING_MODEL.into(),
+                        dimension: LEGACY_DIMENSION,
+                    }
+                } else {
+                    CollectionInfo {
+                        vector_collection: collection_name.into(),
+                        embedding_model: embedding_model.into(),
+                        dimension,
+                    }
+                };
+                self.set_collection_info(collection_name, &info)?;
+                info
+            }
+        };
+
+        let state = collection_state(collection_name, current, embedding_model, dimension);
+        let info = match &state {
+            CollectionState::Ready(info) => info,
+            CollectionState::Mismatch { target, .. } => target,
+        };
+        self.create_collection(&info.vector_collection, info.dimension)
+            .await?;
+
+        Ok(state)
+    }
+
+    pub fn get_collection_info(&self, collection_name: &str) -> Result<Option<CollectionInfo>> {
+        let cf = self
+            .kv_db
+            .cf_handle(COLLECTION_INFO)
+            .expect("failed to get collection info cf handle");
+        self.kv_db
+            .get_cf(&cf, collection_name.as_bytes())?
+            .map(|data| bincode::deserialize::<CollectionInfo>(&data).map_err(|e| anyhow!("{e:?}")))
+            .transpose()
+    }
+
+    pub fn set_collection_info(&self, collection_name: &str, info: &CollectionInfo) -> Result<()> {
+        let cf = self
+            .kv_db
+            .cf_handle(COLLECTION_INFO)
+            .expect("failed to get collection info cf handle");
+        let info_bytes = bincode::serialize(info)?;
+        self.kv_db
+            .put_cf(&cf, collection_name.as_bytes(), info_bytes)
+            .map_err(|e| anyhow!("{e:?}"))
+    }
+
+    /// Switches `collection_name` over to the re-embedded vectors in `target` and deletes the
+    /// old vectors.
+    pub async fn finish_migration(
+        &self,
+        collection_name: &str,
+        current: &CollectionInfo,
+        target: &CollectionInfo,
+    ) -> Result<()> {
+        self.set_collection_info(collection_name, target)?;
+        if current.vector_collection != target.vector_collection {
+            self.vec_db_client
+                .delete_collection(&current.vector_collection)
                 .await?;
-            Ok(())
         }
+        Ok(())
     }
 
     pub async fn upsert_memories(
@@ -65,62 +174,37 @@ impl Database {
         collection_name: &str,
         memories: Vec<Memory>,
     ) -> Result<()> {
-        let points: Vec<PointStruct> = memories
-            .iter()
-            .map(|m| {
-                let payload: Payload = serde_json::json!(
-                    {
-                        "id": m.data.id
-                    }
-                )
-                .try_into()
-                .unwrap();
-                let id_hash = fasthash::spooky::hash128(m.data.id.to_le_bytes());
-                let id = Uuid::from_bytes(id_hash.to_le_bytes());
-                PointStruct::new(id.to_string(), m.embedding.data.clone(), payload)
-            })
-            .collect();
-        self.vec_db_client
-            .upsert_points(UpsertPointsBuilder::new(collection_name, points))
-            .await?;
+        let (embeddings, data): (Vec<Embedding>, Vec<MemoryData>) =
+            memories.into_iter().map(|m| (m.embedding, m.data)).unzip();
+        self.upsert_embeddings(collection_name, embeddings).await?;
         // TODO: we should make this atomic. If inserting the memory data fails, we remove the
         // embedding etc.
-        for memory in memories {
-            self.insert_memory_data(memory.data)?;
+        for data in data {
+            self.insert_memory_data(data)?;
         }
 
         Ok(())
     }
 
+    /// Stores only the vectors of memories whose data is already in the kv db
+    pub async fn upsert_embeddings(
+        &self,
+        collection_name: &str,
+        embeddings: Vec<Embedding>,
+    ) -> Result<()> {
+        self.vec_db_client.upsert(collection_name, embeddings).await
+    }
+
     pub async fn get_k_most_similar_memories(
         &self,
         collection_name: &str,
         embedding: Embedding,
         k: u64,
     ) -> Result<Vec<MemoryData>> {
-        let search_result = self
+        let ids = self
             .vec_db_client
-            .search_points(
-                SearchPointsBuilder::new(collection_name, embedding.data, k)
-                    //.filter(Filter::all([Condition::matches("bar", 12)]))
-                    .with_payload(true)
-                    .params(SearchParamsBuilder::default().exact(true)),
-            )
-            .await
-            .unwrap();
-
-        let ids = search_result
-            .result
-            .iter()
-            .filter_map(|r| {
-                if let Some(content) = r.payload.get("id") {
-                    let id = content.as_integer().unwrap() as u128;
-                    Some(id)
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<u128>>();
+            .search(collection_name, embedding.data, k)
+            .await?;
         let mut memories = Vec::with_capacity(ids.len());
         for id in ids {
             let memory = self.get_memory(id)?;
@@ -172,6 +256,28 @@ impl Database {
             .map_err(|e| anyhow!("{e:?}"))
     }
 
+    /// Whether the agent did `kind` ("like", "retweet" or "quote") to the tweet with `tweet_id`
+    pub fn engagement_exists(&self, kind: &str, tweet_id: &str) -> Result<bool> {
+        let cf = self
+            .kv_db
+            .cf_handle(ENGAGEMENTS)
+            .expect("failed to get engagements cf handle");
+        self.kv_db
+            .get_cf(&cf, format!("{kind}/{tweet_id}"))
+            .map(|v| v.is_some())
+            .map_err(|e| anyhow!("{e:?}"))
+    }
+
+    pub fn insert_engagement(&self, kind: &str, tweet_id: &str) -> Result<()> {
+        let cf = self
+            .kv_db
+            .cf_handle(ENGAGEMENTS)
+            .expect("failed to get engagements cf handle");
+        self.kv_db
+            .put_cf(&cf, format!("{kind}/{tweet_id}"), b"0")
+            .map_err(|e| anyhow!("{e:?}"))
+    }
+
     fn insert_memory_data(&self, data: MemoryData) -> Result<()> {
         let cf = self
             .kv_db
@@ -196,6 +302,180 @@ impl Database {
         bincode::deserialize::<MemoryData>(&data).map_err(|e| anyhow!("{e:?}"))
     }
 
+    pub fn insert_action_log_entry(&self, entry: &SignedActionLogEntry) -> Result<()> {
+        let cf = self
+            .kv_db
+            .cf_handle(ACTION_LOG)
+            .expect("failed to get action log cf handle");
+        let entry_bytes = bincode::serialize(entry)?;
+        // Big endian so the entries are sorted by time
+        self.kv_db
+            .put_cf(&cf, entry.entry.timestamp.to_be_bytes(), entry_bytes)
+            .map_err(|e| anyhow!("{e:?}"))
+    }
+
+    pub fn get_recent_action_log_entries(
+        &self,
+        max_num: usize,
+    ) -> Result<Vec<SignedActionLogEntry>> {
+        let cf = self
+            .kv_db
+            .cf_handle(ACTION_LOG)
+            .expect("failed to get action log cf handle");
+        let iter = self.kv_db.iterator_cf(cf, IteratorMode::End);
+        let mut entries = Vec::with_capacity(max_num);
+        for (_key, val) in iter.flatten() {
+            if entries.len() >= max_num {
+                break;
+            }
+            if let Ok(entry) = bincode::deserialize::<SignedActionLogEntry>(&val) {
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    pub fn get_cached_embedding(&self, key: &[u8]) -> Result<Option<Vec<f32>>> {
+        let cf = self
+            .kv_db
+            .cf_handle(EMBEDDING_CACHE)
+            .expect("failed to get embedding cache cf handle");
+        self.kv_db
+            .get_cf(&cf, key)?
+            .map(|data| bincode::deserialize::<Vec<f32>>(&data).map_err(|e| anyhow!("{e:?}")))
+            .transpose()
+    }
+
+    pub fn insert_cached_embedding(&self, key: &[u8], embedding: &[f32]) -> Result<()> {
+        let cf = self
+            .kv_db
+            .cf_handle(EMBEDDING_CACHE)
+            .expect("failed to get embedding cache cf handle");
+        let embedding_bytes = bincode::serialize(embedding)?;
+        self.kv_db
+            .put_cf(&cf, key, embedding_bytes)
+            .map_err(|e| anyhow!("{e:?}"))
+    }
+
+    pub fn insert_usage_record(&self, record: &UsageRecord) -> Result<()> {
+        let cf = self
+            .kv_db
+            .cf_handle(LLM_USAGE)
+            .expect("failed to get llm usage cf handle");
+        let record_bytes = bincode::serialize(record)?;
+        // Big endian so the records are sorted by time
+        self.kv_db
+            .put_cf(&cf, record.timestamp.to_be_bytes(), record_bytes)
+            .map_err(|e| anyhow!("{e:?}"))
+    }
+
+    /// All usage records from `timestamp` (unix nanoseconds) on
+    pub fn get_usage_records_since(&self, timestamp: u128) -> Result<Vec<UsageRecord>> {
+        let cf = self
+            .kv_db
+            .cf_handle(LLM_USAGE)
+            .expect("failed to get llm usage cf handle");
+        let start = timestamp.to_be_bytes();
+        let iter = self
+            .kv_db
+            .iterator_cf(cf, IteratorMode::From(&start, Direction::Forward));
+        let mut records = Vec::new();
+        for (_key, val) in iter.flatten() {
+            if let Ok(record) = bincode::deserialize::<UsageRecord>(&val) {
+                records.push(record);
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Id of the newest tweet read from `feed`
+    pub fn get_feed_checkpoint(&self, feed: &str) -> Result<Option<String>> {
+        let cf = self
+            .kv_db
+            .cf_handle(FEEDS)
+            .expect("failed to get feeds cf handle");
+        self.kv_db
+            .get_cf(&cf, format!("{feed}/since-id"))?
+            .map(|id| String::from_utf8(id).map_err(|e| anyhow!("{e:?}")))
+            .transpose()
+    }
+
+    pub fn set_feed_checkpoint(&self, feed: &str, newest_id: &str) -> Result<()> {
+        let cf = self
+            .kv_db
+            .cf_handle(FEEDS)
+            .expect("failed to get feeds cf handle");
+        self.kv_db
+            .put_cf(&cf, format!("{feed}/since-id"), newest_id.as_bytes())
+            .map_err(|e| anyhow!("{e:?}"))
+    }
+
+    /// Tweets read from `feed` that are not used yet, newest first
+    pub fn get_feed_buffer<T: DeserializeOwned>(&self, feed: &str) -> Result<Vec<T>> {
+        let cf = self
+            .kv_db
+            .cf_handle(FEEDS)
+            .expect("failed to get feeds cf handle");
+        match self.kv_db.get_cf(&cf, format!("{feed}/buffer"))? {
+            Some(data) => serde_json::from_slice(&data).map_err(|e| anyhow!("{e:?}")),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn set_feed_buffer<T: Serialize>(&self, feed: &str, tweets: &[T]) -> Result<()> {
+        let cf = self
+            .kv_db
+            .cf_handle(FEEDS)
+            .expect("failed to get feeds cf handle");
+        // JSON instead of bincode so the buffer survives new optional fields on the tweets
+        let data = serde_json::to_vec(tweets)?;
+        self.kv_db
+            .put_cf(&cf, format!("{feed}/buffer"), data)
+            .map_err(|e| anyhow!("{e:?}"))
+    }
+
+    /// How many `kind` (posts, replies, reads) were counted in the month starting at `month`
+    pub fn get_x_usage(&self, month: u64, kind: &str) -> Result<u64> {
+        let cf = self
+            .kv_db
+            .cf_handle(X_USAGE)
+            .expect("failed to get x usage cf handle");
+        match self.kv_db.get_cf(&cf, format!("{month}/{kind}"))? {
+            Some(data) => Ok(u64::from_be_bytes(
+                data.try_into().map_err(|e| anyhow!("{e:?}"))?,
+            )),
+            None => Ok(0),
+        }
+    }
+
+    pub fn add_x_usage(&self, month: u64, kind: &str, count: u64) -> Result<()> {
+        let cf = self
+            .kv_db
+            .cf_handle(X_USAGE)
+            .expect("failed to get x usage cf handle");
+        let total = self.get_x_usage(month, kind)? + count;
+        self.kv_db
+            .put_cf(&cf, format!("{month}/{kind}"), total.to_be_bytes())
+            .map_err(|e| anyhow!("{e:?}"))
+    }
+
+    pub fn get_all_memories(&self) -> Result<Vec<MemoryData>> {
+        let cf = self
+            .kv_db
+            .cf_handle(MEMORY_DATA)
+            .expect("failed to get memory data cf handle");
+        let mut memories = Vec::new();
+        for (_key, val) in self.kv_db.iterator_cf(cf, IteratorMode::Start).flatten() {
+            if let Ok(memory) = bincode::deserialize::<MemoryData>(&val) {
+                memories.push(memory);
+            }
+        }
+
+        Ok(memories)
+    }
+
     pub fn get_recent_memories(&self, max_num: usize) -> Result<Vec<MemoryData>> {
         let sent_tweed_cf = self
             .kv_db
@@ -216,15 +496,83 @@ impl Database {
     }
 }
 
+/// Compares the model the stored vectors come from with the one we run with. On a mismatch the
+/// new vectors get their own qdrant collection named after the model.
+fn collection_state(
+    collection_name: &str,
+    current: CollectionInfo,
+    embedding_model: &str,
+    dimension: u64,
+) -> CollectionState {
+    if current.embedding_model == embedding_model && current.dimension == dimension {
+        return CollectionState::Ready(current);
+    }
+
+    let model = embedding_model
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect::<String>();
+    let target = CollectionInfo {
+        vector_collection: format!("{collection_name}-{model}-{dimension}"),
+        embedding_model: embedding_model.into(),
+        dimension,
+    };
+
+    if target.vector_collection == current.vector_collection {
+        // Only happens if the info was edited by hand, the vectors are already where they belong
+        CollectionState::Ready(target)
+    } else {
+        CollectionState::Mismatch { current, target }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::path::PathBuf;
 
     use crate::db::{
-        types::{Embedding, Memory, MemoryData},
+        collection_state,
+        types::{CollectionInfo, CollectionState, Embedding, Memory, MemoryData},
         Database,
     };
 
+    #[test]
+    fn test_collection_state() {
+        let openai = CollectionInfo {
+            vector_collection: "long-term-memory".into(),
+            embedding_model: "text-embedding-3-small".into(),
+            dimension: 1536,
+        };
+
+        assert_eq!(
+            collection_state(
+                "long-term-memory",
+                openai.clone(),
+                "text-embedding-3-small",
+                1536
+            ),
+            CollectionState::Ready(openai.clone())
+        );
+
+        let CollectionState::Mismatch { current, target } =
+            collection_state("long-term-memory", openai.clone(), "all-MiniLM-L6-v2", 384)
+        else {
+            panic!("expected a mismatch");
+        };
+        assert_eq!(current, openai);
+        assert_eq!(
+            target.vector_collection,
+            "long-term-memory-all-MiniLM-L6-v2-384"
+        );
+        assert_eq!(target.dimension, 384);
+
+        // Same model name but a different size is still a different model
+        assert!(matches!(
+            collection_state("long-term-memory", openai, "text-embedding-3-small", 512),
+            CollectionState::Mismatch { .. }
+        ));
+    }
+
     #[ignore]
     #[tokio::test]
     async fn test_vector_db() {
@@ -21,6 +21,74 @@ pub struct MemoryData {
     pub content: String,
 }
 
+/// Which embedding model the vectors of a collection were created with
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CollectionInfo {
+    /// Name of the qdrant collection holding the vectors
+    pub vector_collection: String,
+    pub embedding_model: String,
+    pub dimension: u64,
+}
+
+/// What `Database::open_collection` found for the embedding model we run with
+#[derive(Debug, Clone, PartialEq)]
+pub enum CollectionState {
+    /// The vectors come from our embedding model
+    Ready(CollectionInfo),
+    /// The vectors in `current` come from another model. New vectors go to `target` and the old
+    /// memories have to be re-embedded into it before it replaces `current`.
+    Mismatch {
+        current: CollectionInfo,
+        target: CollectionInfo,
+    },
+}
+
+/// An action the agent took or an incident it observed. Every entry is signed with the agent's
+/// ethereum key so the log can be verified against the agent's address.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ActionLogEntry {
+    /// Unix timestamp in nanoseconds
+    pub timestamp: u128,
+    pub action: Action,
+    pub details: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Action {
+    Post,
+    Reply,
+    Follow,
+    Incident,
+    Like,
+    Unlike,
+    Retweet,
+    Unretweet,
+    Quote,
+}
+
+/// Tokens used by one call of a language model
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UsageRecord {
+    /// Unix timestamp in nanoseconds
+    pub timestamp: u128,
+    pub provider: String,
+    /// The model as reported by the provider
+    pub model: String,
+    /// The agent's task the call was made for, e.g. "tweet" or "scoring"
+    pub task: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// In USD, from the price table in config.toml
+    pub cost: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SignedActionLogEntry {
+    pub entry: ActionLogEntry,
+    /// Recoverable secp256k1 signature (r, s, v) over the keccak256 hash of the bincode encoded entry
+    pub signature: Vec<u8>,
+}
+
 impl Embedding {
     pub fn new(id: u128, data: Vec<f32>) -> Self {
         Self { id, data }
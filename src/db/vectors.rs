@@ -0,0 +1,190 @@
+// The vectors of the long term memories live in qdrant. When a cassette is replayed offline
+// there is no qdrant to talk to, so they are kept in memory and searched one by one instead.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::{anyhow, Result};
+use qdrant_client::{
+    qdrant::{
+        CreateCollectionBuilder, Distance, PointStruct, ScalarQuantizationBuilder,
+        SearchParamsBuilder, SearchPointsBuilder, UpsertPointsBuilder, VectorParamsBuilder,
+    },
+    Payload, Qdrant,
+};
+use uuid::Uuid;
+
+use super::types::Embedding;
+
+pub enum VectorStore {
+    Qdrant(Qdrant),
+    /// The vectors of every collection by memory id
+    InMemory(Mutex<HashMap<String, HashMap<u128, Vec<f32>>>>),
+}
+
+impl VectorStore {
+    pub async fn collection_exists(&self, collection_name: &str) -> Result<bool> {
+        match self {
+            Self::Qdrant(client) => Ok(client.collection_exists(collection_name).await?),
+            Self::InMemory(collections) => {
+                Ok(collections.lock().unwrap().contains_key(collection_name))
+            }
+        }
+    }
+
+    pub async fn create_collection(&self, collection_name: &str, vector_dim: u64) -> Result<()> {
+        match self {
+            Self::Qdrant(client) => {
+                client
+                    .create_collection(
+                        CreateCollectionBuilder::new(collection_name)
+                            .vectors_config(VectorParamsBuilder::new(vector_dim, Distance::Cosine))
+                            .quantization_config(ScalarQuantizationBuilder::default()),
+                    )
+                    .await?;
+            }
+            Self::InMemory(collections) => {
+                collections
+                    .lock()
+                    .unwrap()
+                    .entry(collection_name.into())
+                    .or_default();
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn delete_collection(&self, collection_name: &str) -> Result<()> {
+        match self {
+            Self::Qdrant(client) => {
+                client.delete_collection(collection_name).await?;
+            }
+            Self::InMemory(collections) => {
+                collections.lock().unwrap().remove(collection_name);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn upsert(&self, collection_name: &str, embeddings: Vec<Embedding>) -> Result<()> {
+        match self {
+            Self::Qdrant(client) => {
+                let points: Vec<PointStruct> = embeddings
+                    .into_iter()
+                    .map(|e| {
+                        let payload: Payload = serde_json::json!(
+                            {
+                                "id": e.id
+                            }
+                        )
+                        .try_into()
+                        .unwrap();
+                        let id_hash = fasthash::spooky::hash128(e.id.to_le_bytes());
+                        let id = Uuid::from_bytes(id_hash.to_le_bytes());
+                        PointStruct::new(id.to_string(), e.data, payload)
+                    })
+                    .collect();
+                client
+                    .upsert_points(UpsertPointsBuilder::new(collection_name, points))
+                    .await?;
+            }
+            Self::InMemory(collections) => {
+                let mut collections = collections.lock().unwrap();
+                let collection = collections
+                    .get_mut(collection_name)
+                    .ok_or_else(|| anyhow!("Collection {collection_name} doesn't exist"))?;
+                for e in embeddings {
+                    collection.insert(e.id, e.data);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Ids of the `k` memories closest to `vector`, the closest first
+    pub async fn search(
+        &self,
+        collection_name: &str,
+        vector: Vec<f32>,
+        k: u64,
+    ) -> Result<Vec<u128>> {
+        match self {
+            Self::Qdrant(client) => {
+                let search_result = client
+                    .search_points(
+                        SearchPointsBuilder::new(collection_name, vector, k)
+                            //.filter(Filter::all([Condition::matches("bar", 12)]))
+                            .with_payload(true)
+                            .params(SearchParamsBuilder::default().exact(true)),
+                    )
+                    .await?;
+
+                Ok(search_result
+                    .result
+                    .iter()
+                    .filter_map(|r| {
+                        let content = r.payload.get("id")?;
+                        Some(content.as_integer().unwrap() as u128)
+                    })
+                    .collect())
+            }
+            Self::InMemory(collections) => {
+                let collections = collections.lock().unwrap();
+                let collection = collections
+                    .get(collection_name)
+                    .ok_or_else(|| anyhow!("Collection {collection_name} doesn't exist"))?;
+                let mut similarities = collection
+                    .iter()
+                    .map(|(id, data)| (*id, cosine_similarity(&vector, data)))
+                    .collect::<Vec<(u128, f32)>>();
+                similarities.sort_by(|a, b| b.1.total_cmp(&a.1));
+                Ok(similarities
+                    .into_iter()
+                    .take(k as usize)
+                    .map(|(id, _)| id)
+                    .collect())
+            }
+        }
+    }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VectorStore;
+    use crate::db::types::Embedding;
+
+    #[tokio::test]
+    async fn test_in_memory_search() {
+        let store = VectorStore::InMemory(Default::default());
+        store.create_collection("memories", 2).await.unwrap();
+        assert!(store.collection_exists("memories").await.unwrap());
+
+        store
+            .upsert(
+                "memories",
+                vec![
+                    Embedding::new(1, vec![1.0, 0.0]),
+                    Embedding::new(2, vec![0.0, 1.0]),
+                    Embedding::new(3, vec![1.0, 1.0]),
+                ],
+            )
+            .await
+            .unwrap();
+
+        let ids = store.search("memories", vec![0.9, 0.1], 2).await.unwrap();
+        assert_eq!(ids, vec![1, 3]);
+
+        store.delete_collection("memories").await.unwrap();
+        assert!(!store.collection_exists("memories").await.unwrap());
+    }
+}
@@ -0,0 +1,122 @@
+// Caches embeddings in the kv db keyed by a hash of the model and the text, so text we have
+// embedded before (memories, repeated thoughts, re-embedding after a restart) costs nothing.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use sha3::{Digest, Sha3_256};
+
+use crate::db::Database;
+
+use super::EmbeddingModel;
+
+pub struct CachedEmbeddingModel {
+    inner: Box<dyn EmbeddingModel>,
+    database: Arc<Database>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CachedEmbeddingModel {
+    pub fn new(inner: Box<dyn EmbeddingModel>, database: Arc<Database>) -> Self {
+        Self {
+            inner,
+            database,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of texts that were served from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of texts that had to be embedded by the model
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn cache_key(&self, text: &str) -> Vec<u8> {
+        cache_key(self.inner.name(), self.inner.dimension(), text)
+    }
+}
+
+fn cache_key(model: &str, dimension: u64, text: &str) -> Vec<u8> {
+    let mut hasher = Sha3_256::new();
+    hasher.update(model.as_bytes());
+    hasher.update(dimension.to_be_bytes());
+    hasher.update(text.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+#[async_trait]
+impl EmbeddingModel for CachedEmbeddingModel {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn dimension(&self) -> u64 {
+        self.inner.dimension()
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        let mut missing = Vec::new();
+        for (i, text) in texts.iter().enumerate() {
+            // A broken cache entry is treated like a miss
+            match self.database.get_cached_embedding(&self.cache_key(text)) {
+                Ok(Some(embedding)) => embeddings.push(Some(embedding)),
+                _ => {
+                    embeddings.push(None);
+                    missing.push(i);
+                }
+            }
+        }
+        self.hits
+            .fetch_add((texts.len() - missing.len()) as u64, Ordering::Relaxed);
+        self.misses
+            .fetch_add(missing.len() as u64, Ordering::Relaxed);
+
+        if !missing.is_empty() {
+            let missing_texts = missing
+                .iter()
+                .map(|i| texts[*i].clone())
+                .collect::<Vec<String>>();
+            let new_embeddings = self.inner.embed_batch(&missing_texts).await?;
+            if new_embeddings.len() != missing.len() {
+                return Err(anyhow!(
+                    "{} returned {} embeddings for {} texts",
+                    self.name(),
+                    new_embeddings.len(),
+                    missing.len()
+                ));
+            }
+            for (i, embedding) in missing.into_iter().zip(new_embeddings) {
+                if let Err(e) = self
+                    .database
+                    .insert_cached_embedding(&self.cache_key(&texts[i]), &embedding)
+                {
+                    tracing::warn!("Unable to cache embedding: {e:?}");
+                }
+                embeddings[i] = Some(embedding);
+            }
+        }
+
+        Ok(embeddings.into_iter().flatten().collect())
+    }
+}
+
+#[test]
+fn test_cache_key() {
+    let key = cache_key("text-embedding-3-small", 1536, "gm");
+
+    assert_eq!(key, cache_key("text-embedding-3-small", 1536, "gm"));
+    assert_ne!(key, cache_key("text-embedding-3-small", 1536, "gn"));
+    assert_ne!(key, cache_key("text-embedding-3-small", 512, "gm"));
+    assert_ne!(key, cache_key("all-MiniLM-L6-v2", 1536, "gm"));
+}
@@ -0,0 +1,160 @@
+// Runs a BERT style sentence-transformer (e.g. all-MiniLM-L6-v2) on the CPU with candle.
+// The weights are shipped as trusted files so they are part of the enclave measurement.
+
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use candle_core::{Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+
+use super::EmbeddingModel;
+
+pub struct LocalEmbeddingModel {
+    name: String,
+    dimension: u64,
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    model: BertModel,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+impl LocalEmbeddingModel {
+    /// Loads the model from a directory containing config.json, tokenizer.json and
+    /// model.safetensors as published on huggingface.
+    pub fn load(model_path: PathBuf) -> Result<Self> {
+        let device = Device::Cpu;
+
+        let config = std::fs::read_to_string(model_path.join("config.json"))
+            .with_context(|| format!("failed to read config.json from {model_path:?}"))?;
+        let config: Config = serde_json::from_str(&config)?;
+
+        let mut tokenizer = Tokenizer::from_file(model_path.join("tokenizer.json"))
+            .map_err(|e| anyhow!("failed to load tokenizer from {model_path:?}: {e}"))?;
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: config.max_position_embeddings,
+                ..Default::default()
+            }))
+            .map_err(|e| anyhow!("{e}"))?;
+        // Pad batches to their longest text, the padding is masked out when pooling
+        tokenizer.with_padding(Some(PaddingParams::default()));
+
+        // Safety: the weights are trusted files that are not modified while we run
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(
+                &[model_path.join("model.safetensors")],
+                DTYPE,
+                &device,
+            )?
+        };
+        let model = BertModel::load(vb, &config)?;
+
+        let name = model_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or("local".into());
+
+        Ok(Self {
+            name,
+            dimension: config.hidden_size as u64,
+            inner: Arc::new(Inner {
+                model,
+                tokenizer,
+                device,
+            }),
+        })
+    }
+}
+
+impl Inner {
+    /// Mean pools the last hidden state over the tokens and normalizes the result, like
+    /// sentence-transformers does.
+    fn embed_batch(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let encodings = self
+            .tokenizer
+            .encode_batch(texts, true)
+            .map_err(|e| anyhow!("failed to tokenize texts: {e}"))?;
+
+        let input_ids = encodings
+            .iter()
+            .map(|e| Tensor::new(e.get_ids(), &self.device))
+            .collect::<candle_core::Result<Vec<Tensor>>>()?;
+        let input_ids = Tensor::stack(&input_ids, 0)?;
+        let token_type_ids = input_ids.zeros_like()?;
+        let attention_mask = encodings
+            .iter()
+            .map(|e| Tensor::new(e.get_attention_mask(), &self.device))
+            .collect::<candle_core::Result<Vec<Tensor>>>()?;
+        let attention_mask = Tensor::stack(&attention_mask, 0)?;
+
+        let hidden_states =
+            self.model
+                .forward(&input_ids, &token_type_ids, Some(&attention_mask))?;
+
+        let mask = attention_mask.to_dtype(DTYPE)?.unsqueeze(2)?;
+        let summed = hidden_states.broadcast_mul(&mask)?.sum(1)?;
+        let mean = summed.broadcast_div(&mask.sum(1)?)?;
+        let norm = mean.sqr()?.sum_keepdim(1)?.sqrt()?;
+
+        Ok(mean.broadcast_div(&norm)?.to_vec2::<f32>()?)
+    }
+}
+
+#[async_trait]
+impl EmbeddingModel for LocalEmbeddingModel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn dimension(&self) -> u64 {
+        self.dimension
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        // Inference is CPU bound, keep it off the async runtime
+        let inner = self.inner.clone();
+        let texts = texts.to_vec();
+        tokio::task::spawn_blocking(move || inner.embed_batch(texts)).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LocalEmbeddingModel;
+    use crate::embeddings::EmbeddingModel;
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_local_embedding() {
+        let model = LocalEmbeddingModel::load("./trusted/all-MiniLM-L6-v2".into()).unwrap();
+
+        let cat = model.embed("a cat sits on the mat").await.unwrap();
+        let kitten = model.embed("a kitten is sitting on a rug").await.unwrap();
+        let eth = model.embed("ethereum gas fees are too high").await.unwrap();
+
+        assert_eq!(cat.len() as u64, model.dimension());
+        let similarity = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+        assert!(similarity(&cat, &kitten) > similarity(&cat, &eth));
+
+        // Padding a batch must not change the embeddings
+        let batch = model
+            .embed_batch(&[
+                "a cat sits on the mat".into(),
+                "ethereum gas fees are too high".into(),
+            ])
+            .await
+            .unwrap();
+        assert!((similarity(&batch[0], &cat) - 1.0).abs() < 1e-4);
+        assert!((similarity(&batch[1], &eth) - 1.0).abs() < 1e-4);
+    }
+}
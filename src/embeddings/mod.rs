@@ -0,0 +1,101 @@
+// The agent embeds its thoughts and tweets through the `EmbeddingModel` trait so long term
+// memory can run on OpenAI's API or fully inside the enclave with a local model.
+
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{cassette, env::EnvVariables, openai::OpenAIClient, usage::UsageMeter};
+
+use self::local::LocalEmbeddingModel;
+
+pub mod cache;
+pub mod local;
+pub mod reembed;
+
+const OPEN_AI_API_URL: &str = "https://api.openai.com/v1";
+const LOCAL_MODEL_PATH: &str = "./trusted/all-MiniLM-L6-v2";
+
+#[async_trait]
+pub trait EmbeddingModel: Send + Sync {
+    /// Name of the model the embeddings come from
+    fn name(&self) -> &str;
+
+    /// Size of the vectors this model returns
+    fn dimension(&self) -> u64;
+
+    /// Embeds all `texts` at once, the embeddings are returned in the same order
+    async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>>;
+
+    async fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        self.embed_batch(&[text.to_string()])
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow!("{} returned no embedding", self.name()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum EmbeddingProvider {
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAI,
+    /// A sentence-transformer model running on the CPU inside the enclave
+    #[serde(rename = "local")]
+    Local,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct EmbeddingModelConfig {
+    pub provider: EmbeddingProvider,
+    /// Directory with config.json, tokenizer.json and model.safetensors for the local provider
+    pub model_path: Option<PathBuf>,
+}
+
+/// Builds the embedding model selected in the config. The tokens of API calls are recorded in
+/// `usage_meter`.
+pub fn build_embedding_model(
+    config: &EmbeddingModelConfig,
+    env: &EnvVariables,
+    usage_meter: Arc<UsageMeter>,
+) -> Result<Box<dyn EmbeddingModel>> {
+    let embedding_model: Box<dyn EmbeddingModel> = match config.provider {
+        EmbeddingProvider::OpenAI => Box::new(
+            OpenAIClient::new(
+                env.open_ai_api_key
+                    .clone()
+                    .ok_or_else(|| anyhow!("open_ai_api_key is required for OpenAI embeddings"))?,
+                cassette::replay_url("openai").unwrap_or(OPEN_AI_API_URL.into()),
+            )
+            .with_usage_meter(usage_meter),
+        ),
+        EmbeddingProvider::Local => Box::new(LocalEmbeddingModel::load(
+            config.model_path.clone().unwrap_or(LOCAL_MODEL_PATH.into()),
+        )?),
+    };
+
+    Ok(embedding_model)
+}
+
+#[test]
+fn test_parse_embedding_model_config() {
+    let config: EmbeddingModelConfig = toml::from_str(
+        r#"
+        provider = "local"
+        model_path = "./trusted/all-MiniLM-L6-v2"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(config.provider, EmbeddingProvider::Local);
+    assert_eq!(
+        config.model_path,
+        Some(PathBuf::from("./trusted/all-MiniLM-L6-v2"))
+    );
+    assert_eq!(
+        EmbeddingModelConfig::default().provider,
+        EmbeddingProvider::OpenAI
+    );
+}
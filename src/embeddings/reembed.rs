@@ -0,0 +1,65 @@
+// Rebuilds the vectors of a collection after the embedding model changed. The memories
+// themselves are kept in the kv db, so we embed them again with the new model and switch the
+// collection over once every memory made it into the new vectors.
+// The agent keeps running meanwhile, it stores and searches memories in the new vectors.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::db::{
+    types::{CollectionInfo, Embedding},
+    Database,
+};
+
+use super::EmbeddingModel;
+
+const BATCH_SIZE: usize = 32;
+
+pub async fn reembed_collection(
+    database: Arc<Database>,
+    embedding_model: Arc<dyn EmbeddingModel>,
+    collection_name: String,
+    current: CollectionInfo,
+    target: CollectionInfo,
+) -> Result<()> {
+    let memories = database.get_all_memories()?;
+    tracing::info!(
+        "Re-embedding {} memories of {collection_name} from {} to {}",
+        memories.len(),
+        current.embedding_model,
+        target.embedding_model
+    );
+
+    for (i, batch) in memories.chunks(BATCH_SIZE).enumerate() {
+        let texts = batch
+            .iter()
+            .map(|memory| memory.content.clone())
+            .collect::<Vec<String>>();
+        let embeddings = embedding_model
+            .embed_batch(&texts)
+            .await?
+            .into_iter()
+            .zip(batch)
+            .map(|(embedding, memory)| Embedding::new(memory.id, embedding))
+            .collect();
+        database
+            .upsert_embeddings(&target.vector_collection, embeddings)
+            .await?;
+        tracing::info!(
+            "Re-embedded {}/{} memories",
+            (i * BATCH_SIZE + batch.len()),
+            memories.len()
+        );
+    }
+
+    database
+        .finish_migration(&collection_name, &current, &target)
+        .await?;
+    tracing::info!(
+        "Finished re-embedding {collection_name} with {}",
+        target.embedding_model
+    );
+
+    Ok(())
+}
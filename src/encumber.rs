@@ -1,60 +1,109 @@
 // This file is ran at the start of the enclave to "encumber" the account and make sure this TEE ai agent is the only one that has access to it
 
-use rand::{thread_rng, Rng};
+use anyhow::{anyhow, bail, Result};
+use base64::{engine::general_purpose, Engine};
+use crypto_box::PublicKey;
+use rand::rngs::OsRng;
 use std::{ffi::OsStr, sync::Arc, time::Duration};
+use zeroize::Zeroize;
 
 use headless_chrome::{Browser, LaunchOptionsBuilder, Tab};
 
 use crate::config::Config;
+use crate::password::PasswordPolicy;
+use crate::secret::Secret;
 
 #[derive(Debug, Clone)]
 pub struct AccountDetails {
     pub x_username: String,
-    pub x_password: String,
+    pub x_password: Secret,
     pub email: String,
-    pub email_password: String,
+    pub email_password: Secret,
+    pub recovery_public_key: String,
+    pub x_password_policy: PasswordPolicy,
+    pub email_password_policy: PasswordPolicy,
 }
 
 #[derive(Debug, Clone)]
 pub struct FullAccountDetails {
     pub x_account: XAccountDetails,
     pub email: String,
-    pub email_password: String,
+    pub email_password: Secret,
 }
 
 #[derive(Debug, Clone)]
 pub struct XAccountDetails {
     pub x_email: String,
     pub x_username: String,
-    pub x_password: String,
-    pub x_consumer_key: String,
-    pub x_consumer_secret: String,
-    pub x_access_token: String,
-    pub x_access_token_secret: String,
+    pub x_password: Secret,
+    pub x_consumer_key: Secret,
+    pub x_consumer_secret: Secret,
+    pub x_access_token: Secret,
+    pub x_access_token_secret: Secret,
 }
 
-pub fn encumber(account_details: AccountDetails) -> FullAccountDetails {
+impl FullAccountDetails {
+    /// Formats every credential in plaintext. This is only meant for handing the accounts back
+    /// once the timelock is up.
+    pub fn expose_secrets(&self) -> String {
+        let x_account = &self.x_account;
+        format!(
+            "x_username: {}\nx_email: {}\nx_password: {}\nx_consumer_key: {}\nx_consumer_secret: {}\nx_access_token: {}\nx_access_token_secret: {}\nemail: {}\nemail_password: {}",
+            x_account.x_username,
+            x_account.x_email,
+            x_account.x_password.expose_secret(),
+            x_account.x_consumer_key.expose_secret(),
+            x_account.x_consumer_secret.expose_secret(),
+            x_account.x_access_token.expose_secret(),
+            x_account.x_access_token_secret.expose_secret(),
+            self.email,
+            self.email_password.expose_secret(),
+        )
+    }
+}
+
+/// Takes over the X account and its email. Fails if the account can still be recovered through
+/// a path the enclave does not control, in which case the agent must not be started.
+/// If any step fails or panics after a credential was rotated, the rotated credentials are
+/// released to the recovery recipient so the accounts are never lost.
+pub fn encumber(account_details: AccountDetails) -> Result<FullAccountDetails> {
+    let mut transaction = EncumberTransaction::new(&account_details.recovery_public_key)?;
+
     let x_account = encumber_twitter_account(
         &account_details.x_username,
-        &account_details.x_password,
+        account_details.x_password.expose_secret(),
         &account_details.email,
-    );
+        account_details.email_password.expose_secret(),
+        &account_details.x_password_policy,
+        &mut transaction,
+    )?;
+
+    let email_password = change_mail_password(
+        &account_details.email,
+        account_details.email_password.expose_secret(),
+        &account_details.email_password_policy,
+        &mut transaction,
+    )?;
 
-    let email_password =
-        change_mail_password(&account_details.email, &account_details.email_password);
+    transaction.commit();
 
-    FullAccountDetails {
+    Ok(FullAccountDetails {
         x_account,
         email: account_details.email,
         email_password,
-    }
+    })
 }
 
-fn get_browser() -> Browser {
+/// Browser profile of the encumbrance
+pub const ENCUMBER_PROFILE: &str = "./temporary";
+
+/// Starts Chrome with the profile in `user_data_dir`. A profile can only be used by one Chrome
+/// at a time.
+pub fn get_browser(user_data_dir: &str) -> Browser {
     let options = LaunchOptionsBuilder::default()
         .sandbox(false)
         .path(Some("./trusted/chrome-linux/chrome".into()))
-        .user_data_dir(Some("./temporary".into()))
+        .user_data_dir(Some(user_data_dir.into()))
         .args(
             [
                 OsStr::new("--start-maximized"),
@@ -67,36 +116,61 @@ fn get_browser() -> Browser {
         .expect("failed building chrome options");
     Browser::new(options).expect("Failed to build chrome browser")
 }
-fn encumber_twitter_account(x_username: &str, x_password: &str, x_email: &str) -> XAccountDetails {
-    let browser = get_browser();
-    let tab = browser.new_tab().expect("Failed to create tab");
 
-    tab.navigate_to("https://twitter.com/i/flow/login").unwrap();
-    let username = tab
-        .wait_for_element("input[autocomplete=\"username\"]")
-        .unwrap();
-    username.type_into(x_username).unwrap();
-    tab.press_key("Enter").unwrap();
+/// Logs into X through the browser flow. X sometimes asks for the email before the password.
+pub fn login_x_account(
+    tab: Arc<Tab>,
+    x_username: &str,
+    x_password: &str,
+    x_email: &str,
+) -> Result<()> {
+    tab.navigate_to("https://twitter.com/i/flow/login")?;
+    let username = tab.wait_for_element("input[autocomplete=\"username\"]")?;
+    username.type_into(x_username)?;
+    tab.press_key("Enter")?;
 
     let password = if let Ok(email) = tab.wait_for_element("input[autocomplete=\"on\"]") {
-        email.type_into(x_email).unwrap();
-        tab.press_key("Enter").unwrap();
-        tab.wait_for_element("input[autocomplete=\"current-password\"]")
-            .unwrap()
+        email.type_into(x_email)?;
+        tab.press_key("Enter")?;
+        tab.wait_for_element("input[autocomplete=\"current-password\"]")?
     } else {
-        tab.wait_for_element("input[name=\"password\"]").unwrap()
+        tab.wait_for_element("input[name=\"password\"]")?
     };
 
-    password.type_into(x_password).unwrap();
-    tab.press_key("Enter").unwrap();
+    password.type_into(x_password)?;
+    tab.press_key("Enter")?;
 
     // we should sleep here a bit
     std::thread::sleep(Duration::from_secs(3));
 
+    Ok(())
+}
+
+fn encumber_twitter_account(
+    x_username: &str,
+    x_password: &str,
+    x_email: &str,
+    email_password: &str,
+    password_policy: &PasswordPolicy,
+    transaction: &mut EncumberTransaction,
+) -> Result<XAccountDetails> {
+    let browser = get_browser(ENCUMBER_PROFILE);
+    let tab = browser.new_tab().expect("Failed to create tab");
+
+    login_x_account(tab.clone(), x_username, x_password, x_email)?;
+
+    // Make sure the owner can't reset the password through a phone number or another email
+    // before we touch any credentials
+    remove_recovery_paths(&browser, tab.clone(), x_password, x_email, email_password)?;
+
     let (x_consumer_key, x_consumer_secret, x_access_token, x_access_token_secret) =
         regenerate_x_tokens(tab.clone());
+    transaction.rotated.x_consumer_key = Some(x_consumer_key.clone());
+    transaction.rotated.x_consumer_secret = Some(x_consumer_secret.clone());
+    transaction.rotated.x_access_token = Some(x_access_token.clone());
+    transaction.rotated.x_access_token_secret = Some(x_access_token_secret.clone());
 
-    let random_password = generate_random_password(10, 1, 1, 1);
+    let random_password = Secret::new(password_policy.generate()?);
 
     tab.navigate_to("https://x.com/settings/password").unwrap();
     let current_password = tab
@@ -107,33 +181,205 @@ fn encumber_twitter_account(x_username: &str, x_password: &str, x_email: &str) -
     let new_password = tab
         .wait_for_element("input[name=\"new_password\"]")
         .unwrap();
-    new_password.type_into(&random_password).unwrap();
+    new_password
+        .type_into(random_password.expose_secret())
+        .unwrap();
 
     let confirm_password = tab
         .wait_for_element("input[name=\"password_confirmation\"]")
         .unwrap();
-    confirm_password.type_into(&random_password).unwrap();
+    confirm_password
+        .type_into(random_password.expose_secret())
+        .unwrap();
 
     let button = tab
         .find_element("button[data-testid=\"settingsDetailSave\"]")
         .unwrap();
 
+    // Record the new password before submitting it, we can't know for sure if it was applied
+    transaction.rotated.x_password = Some(random_password.clone());
     button.click().unwrap();
     std::thread::sleep(Duration::from_secs(3));
 
-    XAccountDetails {
+    Ok(XAccountDetails {
         x_email: x_email.into(),
         x_username: x_username.into(),
-        x_password: x_password.into(),
+        x_password: random_password,
         x_consumer_key,
         x_consumer_secret,
         x_access_token,
         x_access_token_secret,
+    })
+}
+
+/// Deletes the phone number from the X account, replaces an email the enclave doesn't control
+/// with `x_email` and checks that the only recovery path left is the email the enclave is about
+/// to take over.
+fn remove_recovery_paths(
+    browser: &Browser,
+    tab: Arc<Tab>,
+    x_password: &str,
+    x_email: &str,
+    email_password: &str,
+) -> Result<()> {
+    tab.navigate_to("https://x.com/settings/phone")?;
+    if let Ok(delete_phone) = tab.wait_for_xpath("//span[text()=\"Delete phone number\"]") {
+        tracing::info!("Removing phone number from X account");
+        delete_phone.click()?;
+        tab.wait_for_element("button[data-testid=\"confirmationSheetConfirm\"]")?
+            .click()?;
+        std::thread::sleep(Duration::from_secs(3));
+    }
+
+    let (mut phone_row, mut email_row) = read_recovery_paths(&tab, x_password)?;
+    if !foreign_emails(&email_row, x_email).is_empty() {
+        replace_x_email(browser, tab.clone(), x_password, x_email, email_password)?;
+        (phone_row, email_row) = read_recovery_paths(&tab, x_password)?;
+    }
+    check_recovery_paths(&phone_row, &email_row, x_email)
+}
+
+/// The phone and email rows of the X account information page
+fn read_recovery_paths(tab: &Tab, x_password: &str) -> Result<(String, String)> {
+    // X asks for the password again before it shows the account information
+    tab.navigate_to("https://x.com/settings/your_twitter_data/account")?;
+    if let Ok(password) = tab.wait_for_element("input[name=\"password\"]") {
+        password.type_into(x_password)?;
+        tab.press_key("Enter")?;
+        std::thread::sleep(Duration::from_secs(3));
+    }
+
+    let phone_row = tab
+        .wait_for_element("a[href=\"/settings/phone\"]")?
+        .get_inner_text()?;
+    let email_row = tab
+        .wait_for_element("a[href=\"/settings/email\"]")?
+        .get_inner_text()?;
+    Ok((phone_row, email_row))
+}
+
+/// Changes the email of the X account to `x_email`. An X account only has one email, so this
+/// removes the owner's. X mails a confirmation code to the new address, we read it from the
+/// mailbox with the starter password.
+fn replace_x_email(
+    browser: &Browser,
+    tab: Arc<Tab>,
+    x_password: &str,
+    x_email: &str,
+    email_password: &str,
+) -> Result<()> {
+    tracing::info!("Replacing the email of the X account with {x_email}");
+    tab.navigate_to("https://x.com/settings/email")?;
+    tab.wait_for_xpath("//span[text()=\"Update email address\"]")?
+        .click()?;
+    if let Ok(password) = tab.wait_for_element("input[name=\"password\"]") {
+        password.type_into(x_password)?;
+        tab.press_key("Enter")?;
+        std::thread::sleep(Duration::from_secs(3));
+    }
+    tab.wait_for_element("input[name=\"email\"]")?
+        .type_into(x_email)?;
+    tab.press_key("Enter")?;
+    std::thread::sleep(Duration::from_secs(10));
+
+    let code = read_x_confirmation_code(browser, x_email, email_password)?;
+    // Sic, that's how X spells it
+    tab.wait_for_element("input[name=\"verfication_code\"]")?
+        .type_into(&code)?;
+    tab.press_key("Enter")?;
+    std::thread::sleep(Duration::from_secs(3));
+    Ok(())
+}
+
+/// Waits for the confirmation code X sends to a new email address
+fn read_x_confirmation_code(
+    browser: &Browser,
+    email: &str,
+    email_password: &str,
+) -> Result<String> {
+    let tab = browser.new_tab()?;
+    login_mailbox(&tab, email, email_password)?;
+    for _ in 0..6 {
+        // Newest first
+        for row in tab.find_elements("table#messagelist tbody tr")? {
+            if let Some(code) = x_confirmation_code(&row.get_inner_text()?) {
+                tab.close(false)?;
+                return Ok(code);
+            }
+        }
+        std::thread::sleep(Duration::from_secs(10));
+        tab.reload(false, None)?;
+        tab.wait_for_element("table#messagelist")?;
     }
+    bail!("X didn't send a confirmation code to {email}")
 }
 
-fn change_mail_password(email_string: &str, password_string: &str) -> String {
-    let browser = get_browser();
+/// Logs into the cock.li webmail, the profile usually still has a session
+pub fn login_mailbox(tab: &Tab, email: &str, email_password: &str) -> Result<()> {
+    tab.navigate_to("https://mail.cock.li/")?;
+    if let Ok(user) = tab.wait_for_element("input[name=\"_user\"]") {
+        user.type_into(email)?;
+        tab.wait_for_element("input[name=\"_pass\"]")?
+            .type_into(email_password)?;
+        tab.press_key("Enter")?;
+    }
+    tab.wait_for_element("table#messagelist")?;
+    Ok(())
+}
+
+/// The code in a row of the inbox (sender, subject and date) of an email from X, e.g.
+/// "X\nYour X confirmation code is 123456\n10:32"
+fn x_confirmation_code(row: &str) -> Option<String> {
+    let sender = row.lines().next()?.trim().to_lowercase();
+    if sender != "x" && !sender.contains("x.com") && !sender.contains("twitter") {
+        return None;
+    }
+    row.split(|c: char| !c.is_ascii_alphanumeric())
+        .find(|word| (6..=8).contains(&word.len()) && word.chars().all(|c| c.is_ascii_digit()))
+        .map(str::to_string)
+}
+
+/// The emails of the email row of the X account information page other than `x_email`
+fn foreign_emails<'a>(email_row: &'a str, x_email: &str) -> Vec<&'a str> {
+    email_row
+        .lines()
+        .skip(1)
+        .map(str::trim)
+        .filter(|line| line.contains('@') && !line.eq_ignore_ascii_case(x_email))
+        .collect()
+}
+
+/// Checks the phone and email rows of the X account information page.
+/// Each row is the setting name followed by its current value, e.g. "Phone\n+1 555 0100".
+fn check_recovery_paths(phone_row: &str, email_row: &str, x_email: &str) -> Result<()> {
+    let phone = phone_row.lines().skip(1).collect::<String>();
+    if phone.chars().any(|c| c.is_ascii_digit()) {
+        bail!(
+            "Refusing to start: the X account still has the phone number {phone} attached, \
+            which the owner could use to reset the password"
+        );
+    }
+
+    if let Some(email) = foreign_emails(email_row, x_email).first() {
+        bail!(
+            "Refusing to start: the X account has the email {email} attached, \
+            which is not controlled by the enclave"
+        );
+    }
+    if !email_row.lines().skip(1).any(|line| line.contains('@')) {
+        bail!("Refusing to start: unable to confirm the email attached to the X account");
+    }
+
+    Ok(())
+}
+
+fn change_mail_password(
+    email_string: &str,
+    password_string: &str,
+    password_policy: &PasswordPolicy,
+    transaction: &mut EncumberTransaction,
+) -> Result<Secret> {
+    let browser = get_browser(ENCUMBER_PROFILE);
     let tab = browser.new_tab().expect("Failed to create tab");
 
     tab.navigate_to("https://cock.li/login").unwrap();
@@ -149,7 +395,7 @@ fn change_mail_password(email_string: &str, password_string: &str) -> String {
 
     tab.navigate_to("https://cock.li/user/changepass").unwrap();
 
-    let random_pass = generate_random_password(10, 1, 1, 1);
+    let random_pass = Secret::new(password_policy.generate()?);
 
     let current_pass = tab
         .wait_for_element("input[name=\"current_password\"]")
@@ -157,19 +403,20 @@ fn change_mail_password(email_string: &str, password_string: &str) -> String {
     current_pass.type_into(password_string).unwrap();
 
     let new_pass = tab.wait_for_element("input[name=\"password\"]").unwrap();
-    new_pass.type_into(&random_pass).unwrap();
+    new_pass.type_into(random_pass.expose_secret()).unwrap();
 
     let pass_confirm = tab
         .wait_for_element("input[name=\"password_confirmation\"]")
         .unwrap();
-    pass_confirm.type_into(&random_pass).unwrap();
+    pass_confirm.type_into(random_pass.expose_secret()).unwrap();
 
+    transaction.rotated.email_password = Some(random_pass.clone());
     tab.press_key("Enter").unwrap();
     std::thread::sleep(Duration::from_secs(5));
-    random_pass
+    Ok(random_pass)
 }
 
-fn regenerate_x_tokens(tab: Arc<Tab>) -> (String, String, String, String) {
+fn regenerate_x_tokens(tab: Arc<Tab>) -> (Secret, Secret, Secret, Secret) {
     tab.navigate_to("https://developer.x.com/en/portal/projects-and-apps")
         .unwrap();
 
@@ -232,10 +479,10 @@ fn regenerate_x_tokens(tab: Arc<Tab>) -> (String, String, String, String) {
     std::thread::sleep(Duration::from_secs(3));
 
     (
-        consumer_key,
-        consumer_secret,
-        access_token,
-        access_token_secret,
+        consumer_key.into(),
+        consumer_secret.into(),
+        access_token.into(),
+        access_token_secret.into(),
     )
 }
 
@@ -246,62 +493,162 @@ impl From<&Config> for AccountDetails {
             x_password: value.x_password.clone(),
             email: value.email.clone(),
             email_password: value.email_password.clone(),
+            recovery_public_key: value.recovery_public_key.clone(),
+            x_password_policy: value.x_password_policy.clone().unwrap_or_default(),
+            email_password_policy: value.email_password_policy.clone().unwrap_or_default(),
         }
     }
 }
 
-fn generate_random_password(
-    length: usize,
-    min_uppercase: usize,
-    min_numbers: usize,
-    min_special: usize,
-) -> String {
-    const LOWERCASE: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
-    const UPPERCASE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
-    const NUMBERS: &[u8] = b"0123456789";
-    const SPECIAL: &[u8] = b"!@#$%^&*";
-
-    let mut rng = thread_rng();
-    let mut password = Vec::with_capacity(length);
-
-    // Add minimum required characters
-    for _ in 0..min_uppercase {
-        password.push(UPPERCASE[rng.gen_range(0..UPPERCASE.len())] as char);
+/// Credentials that have been rotated so far during encumbrance.
+#[derive(Debug, Default)]
+struct RotatedCredentials {
+    x_password: Option<Secret>,
+    x_consumer_key: Option<Secret>,
+    x_consumer_secret: Option<Secret>,
+    x_access_token: Option<Secret>,
+    x_access_token_secret: Option<Secret>,
+    email_password: Option<Secret>,
+}
+
+impl RotatedCredentials {
+    fn is_empty(&self) -> bool {
+        self.x_password.is_none()
+            && self.x_consumer_key.is_none()
+            && self.x_consumer_secret.is_none()
+            && self.x_access_token.is_none()
+            && self.x_access_token_secret.is_none()
+            && self.email_password.is_none()
     }
-    for _ in 0..min_numbers {
-        password.push(NUMBERS[rng.gen_range(0..NUMBERS.len())] as char);
+}
+
+/// Keeps track of the credentials rotated during encumbrance.
+/// Until `commit` is called, dropping the transaction (on an early return or while unwinding from
+/// a panic) releases the rotated credentials sealed to the recovery recipient, since the process
+/// memory is otherwise the only place they exist.
+struct EncumberTransaction {
+    recovery_key: PublicKey,
+    rotated: RotatedCredentials,
+    committed: bool,
+}
+
+impl EncumberTransaction {
+    fn new(recovery_public_key: &str) -> Result<Self> {
+        let key_bytes = hex::decode(recovery_public_key.trim_start_matches("0x"))
+            .map_err(|e| anyhow!("Invalid recovery_public_key in config.toml: {e:?}"))?;
+        let recovery_key = PublicKey::from_slice(&key_bytes).map_err(|_| {
+            anyhow!("Invalid recovery_public_key in config.toml: expected a 32 byte x25519 key")
+        })?;
+
+        Ok(Self {
+            recovery_key,
+            rotated: RotatedCredentials::default(),
+            committed: false,
+        })
     }
-    for _ in 0..min_special {
-        password.push(SPECIAL[rng.gen_range(0..SPECIAL.len())] as char);
+
+    fn commit(mut self) {
+        self.committed = true;
     }
 
-    // Fill the rest with lowercase letters
-    let remaining = length - (min_uppercase + min_numbers + min_special);
-    for _ in 0..remaining {
-        password.push(LOWERCASE[rng.gen_range(0..LOWERCASE.len())] as char);
+    /// Seals the rotated credentials to the recovery recipient and returns them base64 encoded.
+    /// The result can be opened with libsodium's `crypto_box_seal_open`.
+    fn seal(&self) -> Result<String> {
+        fn expose(secret: &Option<Secret>) -> Option<&str> {
+            secret.as_ref().map(Secret::expose_secret)
+        }
+
+        let rotated = &self.rotated;
+        let mut plaintext = serde_json::to_vec(&serde_json::json!({
+            "x_password": expose(&rotated.x_password),
+            "x_consumer_key": expose(&rotated.x_consumer_key),
+            "x_consumer_secret": expose(&rotated.x_consumer_secret),
+            "x_access_token": expose(&rotated.x_access_token),
+            "x_access_token_secret": expose(&rotated.x_access_token_secret),
+            "email_password": expose(&rotated.email_password),
+        }))?;
+        let sealed = self.recovery_key.seal(&mut OsRng, &plaintext);
+        plaintext.zeroize();
+        let sealed = sealed.map_err(|e| anyhow!("{e:?}"))?;
+
+        Ok(general_purpose::STANDARD.encode(sealed))
     }
+}
+
+impl Drop for EncumberTransaction {
+    fn drop(&mut self) {
+        if self.committed || self.rotated.is_empty() {
+            return;
+        }
 
-    // Shuffle the password
-    for i in (1..password.len()).rev() {
-        let j = rng.gen_range(0..=i);
-        password.swap(i, j);
+        // Must not panic here, we might already be unwinding
+        match self.seal() {
+            Ok(sealed) => {
+                tracing::error!(
+                    "######## Encumberence failed releasing rotated credentials ########"
+                );
+                tracing::error!("{sealed}");
+                tracing::error!(
+                    "####################################################################"
+                );
+            }
+            Err(e) => tracing::error!("Failed to seal rotated credentials: {e:?}"),
+        }
     }
+}
 
-    password.into_iter().collect()
+#[test]
+fn test_check_recovery_paths() {
+    let email = "agent@cock.li";
+
+    assert!(check_recovery_paths("Phone", "Email\nagent@cock.li", email).is_ok());
+    assert!(check_recovery_paths("Phone\nAdd", "Email\nAgent@Cock.li", email).is_ok());
+    assert!(check_recovery_paths("Phone\n+1 555 0100", "Email\nagent@cock.li", email).is_err());
+    assert!(check_recovery_paths("Phone", "Email\nowner@gmail.com", email).is_err());
+    assert!(check_recovery_paths("Phone", "Email", email).is_err());
 }
 
 #[test]
-fn test_random_pass() {
-    for _ in 0..100 {
-        let pass = generate_random_password(10, 2, 2, 2);
+fn test_x_confirmation_code() {
+    assert_eq!(
+        x_confirmation_code("X\nYour X confirmation code is 482913\n10:32").as_deref(),
+        Some("482913")
+    );
+    assert_eq!(
+        x_confirmation_code("verify@x.com\n48291375 is your confirmation code\nMon").as_deref(),
+        Some("48291375")
+    );
+    assert_eq!(x_confirmation_code("X\nNew login to X\n10:32"), None);
+    assert_eq!(
+        x_confirmation_code("cock.li\nYour code is 482913\n10:32"),
+        None
+    );
+}
 
-        println!("{pass}");
-    }
+#[test]
+fn test_encumber_transaction() {
+    use crypto_box::SecretKey;
+
+    assert!(EncumberTransaction::new("").is_err());
+    assert!(EncumberTransaction::new("0x1234").is_err());
+
+    let secret_key = SecretKey::generate(&mut OsRng);
+    let mut transaction =
+        EncumberTransaction::new(&hex::encode(secret_key.public_key().as_bytes())).unwrap();
+    transaction.rotated.x_password = Some("new password".into());
+
+    let sealed = general_purpose::STANDARD
+        .decode(transaction.seal().unwrap())
+        .unwrap();
+    let opened = secret_key.unseal(&sealed).unwrap();
+    assert!(String::from_utf8(opened).unwrap().contains("new password"));
+
+    transaction.commit();
 }
 
 #[test]
 fn test() {
-    let browser = get_browser();
+    let browser = get_browser(ENCUMBER_PROFILE);
     let tab = browser.new_tab().unwrap();
 
     tab.navigate_to("https://google.com").unwrap();
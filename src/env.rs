@@ -12,12 +12,22 @@ use axum::{extract::State, routing::post, Json, Router};
 use tokio::sync::oneshot::{self, Receiver, Sender};
 use tower_http::cors::CorsLayer;
 
+use crate::secret::Secret;
+
 pub static ENV: OnceLock<EnvVariables> = OnceLock::new();
 
 #[derive(Deserialize, Debug)]
 pub struct EnvVariables {
-    pub hyperbolic_api_key: String,
-    pub open_ai_api_key: String,
+    #[serde(default)]
+    pub hyperbolic_api_key: Option<Secret>,
+    /// Only needed when the chat or embedding model runs on OpenAI
+    #[serde(default)]
+    pub open_ai_api_key: Option<Secret>,
+    #[serde(default)]
+    pub anthropic_api_key: Option<Secret>,
+    /// Key for an OpenAI compatible chat endpoint, if it needs one
+    #[serde(default)]
+    pub chat_api_key: Option<Secret>,
 }
 
 pub async fn wait_for_api_keys() {
@@ -0,0 +1,351 @@
+// Watches over the encumbered accounts while the agent is running.
+// X can lock, suspend or force a password reset on the account at any time and nobody would
+// notice, so we periodically check the API tokens, log in through the browser to spot forced
+// challenges and look through the mailbox for security emails from X.
+
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use tokio::sync::{mpsc, watch};
+
+use crate::{
+    cassette,
+    config::Config,
+    encumber::{get_browser, login_mailbox, login_x_account, FullAccountDetails},
+    twitter::{error::TwitterError, TwitterClient, X_API_URL},
+};
+
+// The checks run while encumbrance may still have its browser open, Chrome doesn't start twice
+// on the same profile
+const HEALTH_PROFILE: &str = "./temporary-health";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccountHealth {
+    Healthy,
+    Unhealthy(String),
+}
+
+impl AccountHealth {
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, AccountHealth::Healthy)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IncidentKind {
+    /// The OAuth tokens stopped working
+    TokenCheckFailed,
+    /// X showed a lock, challenge or password reset when logging in through the browser
+    LoginChallenge,
+    /// X sent a security email to the account's mailbox
+    SecurityEmail,
+    /// All checks pass again after an incident
+    Recovered,
+}
+
+#[derive(Debug, Clone)]
+pub struct Incident {
+    pub kind: IncidentKind,
+    pub details: String,
+}
+
+impl Display for Incident {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.details)
+    }
+}
+
+pub struct HealthConfig {
+    x_api_url: String,
+    api_check_interval: Duration,
+    browser_check_interval: Duration,
+    browser_retry_interval: Duration,
+    mailbox_check_interval: Duration,
+}
+
+impl From<&Config> for HealthConfig {
+    fn from(value: &Config) -> Self {
+        // 10min default
+        let api_check_interval = value.api_health_check_interval.unwrap_or(600);
+        // 6h default
+        let browser_check_interval = value.browser_health_check_interval.unwrap_or(21600);
+        // 1h default
+        let browser_retry_interval = value.browser_health_retry_interval.unwrap_or(3600);
+        // 30min default
+        let mailbox_check_interval = value.mailbox_health_check_interval.unwrap_or(1800);
+
+        Self {
+            x_api_url: value.x_api_url.clone().unwrap_or(X_API_URL.into()),
+            api_check_interval: Duration::from_secs(api_check_interval),
+            browser_check_interval: Duration::from_secs(browser_check_interval),
+            browser_retry_interval: Duration::from_secs(browser_retry_interval),
+            mailbox_check_interval: Duration::from_secs(mailbox_check_interval),
+        }
+    }
+}
+
+/// Runs the account health checks forever. The current health is published on `health` and
+/// every incident is sent on `incidents` so the pipeline can pause and record it.
+pub async fn monitor(
+    account_details: FullAccountDetails,
+    config: HealthConfig,
+    health: watch::Sender<AccountHealth>,
+    incidents: mpsc::UnboundedSender<Incident>,
+) {
+    let x_account = &account_details.x_account;
+    let twitter_client = TwitterClient::new(
+        cassette::replay_url("twitter").unwrap_or(config.x_api_url.clone()),
+        x_account.x_consumer_key.expose_secret().into(),
+        x_account.x_consumer_secret.expose_secret().into(),
+        x_account.x_access_token.expose_secret().into(),
+        x_account.x_access_token_secret.expose_secret().into(),
+    );
+
+    let mut api_error: Option<String> = None;
+    let mut browser_error: Option<String> = None;
+    // The inbox already holds the emails X sent during encumbrance, so the first scan only
+    // remembers what is there
+    let mut seen_emails: Option<HashSet<String>> = None;
+    let mut last_browser_check = Instant::now();
+    let mut force_browser_check = false;
+    let mut last_mailbox_check: Option<Instant> = None;
+
+    let mut interval = tokio::time::interval(config.api_check_interval);
+    loop {
+        interval.tick().await;
+
+        // A cheap call that fails as soon as the tokens are revoked or the account is locked
+        api_error = match twitter_client
+            .get_user_info_by_username(&x_account.x_username)
+            .await
+        {
+            Ok(_) => None,
+            Err(e) if is_account_error(&e) => {
+                let details = format!("{e:?}");
+                if api_error.is_none() {
+                    report(&incidents, IncidentKind::TokenCheckFailed, &details);
+                }
+                Some(details)
+            }
+            Err(e) => {
+                tracing::warn!("Skipping the token check: {e:?}");
+                api_error
+            }
+        };
+
+        let mailbox_check_due = match last_mailbox_check {
+            Some(last_check) => last_check.elapsed() >= config.mailbox_check_interval,
+            None => true,
+        };
+        if mailbox_check_due {
+            last_mailbox_check = Some(Instant::now());
+            let seen = seen_emails.clone();
+            match run_blocking(account_details.clone(), move |details| {
+                check_mailbox(&details.email, details.email_password.expose_secret(), seen)
+            })
+            .await
+            {
+                Ok((security_emails, seen)) => {
+                    seen_emails = Some(seen);
+                    for email in security_emails {
+                        report(&incidents, IncidentKind::SecurityEmail, &email);
+                        force_browser_check = true;
+                    }
+                }
+                Err(e) => tracing::warn!("Unable to check mailbox for security emails: {e:?}"),
+            }
+        }
+
+        // Logging in again and again while X is unhappy with the account only makes it worse
+        let browser_check_interval = if force_browser_check || browser_error.is_some() {
+            config.browser_retry_interval
+        } else {
+            config.browser_check_interval
+        };
+        if last_browser_check.elapsed() >= browser_check_interval {
+            last_browser_check = Instant::now();
+            force_browser_check = false;
+            let result =
+                run_blocking(account_details.clone(), |details| check_x_login(&details)).await;
+            browser_error = match result {
+                Ok(()) => None,
+                Err(e) => {
+                    let details = format!("{e:?}");
+                    if browser_error.is_none() {
+                        report(&incidents, IncidentKind::LoginChallenge, &details);
+                    }
+                    Some(details)
+                }
+            };
+        }
+
+        let new_health = match (&api_error, &browser_error) {
+            (None, None) => AccountHealth::Healthy,
+            (Some(e), _) | (None, Some(e)) => AccountHealth::Unhealthy(e.clone()),
+        };
+        if new_health.is_healthy() && !health.borrow().is_healthy() {
+            report(
+                &incidents,
+                IncidentKind::Recovered,
+                "all account checks passed",
+            );
+        }
+        health.send_replace(new_health);
+    }
+}
+
+/// Whether X turned the request away because of the account or its tokens. Rate limits,
+/// network failures and server errors say nothing about the account.
+fn is_account_error(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<TwitterError>(),
+        Some(
+            TwitterError::Unauthorized(_) | TwitterError::Suspended(_) | TwitterError::Forbidden(_)
+        )
+    )
+}
+
+fn report(incidents: &mpsc::UnboundedSender<Incident>, kind: IncidentKind, details: &str) {
+    tracing::error!("Account health incident {kind:?}: {details}");
+    let _ = incidents.send(Incident {
+        kind,
+        details: details.into(),
+    });
+}
+
+/// The browser checks block for a long time so they get their own thread
+async fn run_blocking<T, F>(account_details: FullAccountDetails, f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(FullAccountDetails) -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || f(account_details))
+        .await
+        .map_err(|e| anyhow!("health check panicked: {e:?}"))?
+}
+
+/// Opens X in the browser and makes sure we end up on the home timeline. The browser profile
+/// usually still has a session, if not we log in again.
+fn check_x_login(account_details: &FullAccountDetails) -> Result<()> {
+    let browser = get_browser(HEALTH_PROFILE);
+    let tab = browser.new_tab()?;
+
+    tab.navigate_to("https://x.com/home")?;
+    std::thread::sleep(Duration::from_secs(5));
+
+    if tab.get_url().contains("/login") {
+        let x_account = &account_details.x_account;
+        login_x_account(
+            tab.clone(),
+            &x_account.x_username,
+            x_account.x_password.expose_secret(),
+            &x_account.x_email,
+        )?;
+        tab.navigate_to("https://x.com/home")?;
+        std::thread::sleep(Duration::from_secs(5));
+    }
+
+    let url = tab.get_url();
+    if url.contains("/home") {
+        Ok(())
+    } else {
+        Err(anyhow!("X redirected the login to {url}"))
+    }
+}
+
+/// Looks through the inbox for security emails from X that we haven't seen yet.
+/// Returns the new security emails together with the updated set of seen message ids.
+/// Without a seen set nothing is reported, every email in the inbox is only marked as seen.
+fn check_mailbox(
+    email: &str,
+    email_password: &str,
+    seen: Option<HashSet<String>>,
+) -> Result<(Vec<String>, HashSet<String>)> {
+    let browser = get_browser(HEALTH_PROFILE);
+    let tab = browser.new_tab()?;
+
+    login_mailbox(&tab, email, email_password)?;
+
+    let first_scan = seen.is_none();
+    let mut seen = seen.unwrap_or_default();
+    let mut security_emails = Vec::new();
+    for row in tab.find_elements("table#messagelist tbody tr")? {
+        let Some(id) = row.get_attribute_value("id")? else {
+            continue;
+        };
+        if !seen.insert(id) || first_scan {
+            continue;
+        }
+        let text = row.get_inner_text()?;
+        if is_x_security_email(&text) {
+            security_emails.push(text.split_whitespace().collect::<Vec<&str>>().join(" "));
+        }
+    }
+
+    Ok((security_emails, seen))
+}
+
+/// Whether a row of the inbox (sender, subject and date) is a security email from X
+fn is_x_security_email(row: &str) -> bool {
+    const KEYWORDS: &[&str] = &[
+        "password",
+        "new login",
+        "locked",
+        "suspended",
+        "verify",
+        "verification",
+        "confirmation code",
+        "unusual",
+        "email address",
+        "phone number",
+    ];
+
+    let row = row.to_lowercase();
+    let from_x = row.contains("x.com")
+        || row.contains("twitter")
+        || row.lines().any(|line| line.trim() == "x");
+
+    from_x && KEYWORDS.iter().any(|k| row.contains(k))
+}
+
+#[test]
+fn test_is_x_security_email() {
+    assert!(is_x_security_email("X\nPassword reset request\n10:32"));
+    assert!(is_x_security_email(
+        "info@x.com\nNew login to X from Chrome on Linux\nYesterday"
+    ));
+    assert!(is_x_security_email(
+        "Twitter\nYour account has been locked\nMon"
+    ));
+    assert!(!is_x_security_email("X\nSee what's happening on X\n10:32"));
+    assert!(!is_x_security_email("cock.li\nPassword changed\n10:32"));
+}
+
+#[test]
+fn test_is_account_error() {
+    assert!(is_account_error(
+        &TwitterError::Unauthorized("Unauthorized".into()).into()
+    ));
+    assert!(is_account_error(
+        &TwitterError::Suspended("Your account is suspended".into()).into()
+    ));
+    assert!(!is_account_error(
+        &TwitterError::RateLimited {
+            endpoint: "GET /2/users/by/username/:username".into(),
+            reset_at: 0,
+        }
+        .into()
+    ));
+    assert!(!is_account_error(
+        &TwitterError::Other {
+            status: 503,
+            detail: "Service Unavailable".into(),
+        }
+        .into()
+    ));
+    assert!(!is_account_error(&anyhow!("connection reset by peer")));
+}
@@ -1,80 +1,141 @@
+use std::sync::Arc;
+
 use anyhow::{anyhow, Result};
+use async_trait::async_trait;
 use reqwest::Client;
 
+use crate::{
+    cassette::{self, Recorder},
+    llm::{error::read_response, ChatModel, Message, ModelProfile},
+    secret::Secret,
+    usage::UsageMeter,
+};
+
 use self::api_types::ApiResponse;
 mod api_types;
 
+/// Hyperbolic always sampled the agent's tweets from the top 40 tokens
+const DEFAULT_TOP_K: u32 = 40;
+
 pub struct HyperbolicClient {
     base_url: String,
-    hyperbolic_api_key: String,
+    hyperbolic_api_key: Secret,
+    model: String,
+    usage_meter: Option<Arc<UsageMeter>>,
+    recorder: Option<Arc<Recorder>>,
     client: Client,
 }
 
 impl HyperbolicClient {
-    pub fn new(hyperbolic_api_key: String, base_url: String) -> Self {
+    pub fn new(hyperbolic_api_key: Secret, base_url: String, model: String) -> Self {
         let client = Client::new();
         Self {
             base_url,
             hyperbolic_api_key,
+            model,
+            usage_meter: None,
+            recorder: cassette::recorder(),
             client,
         }
     }
 
-    pub async fn generate_text(&self, context: &str, prompt: &str) -> Result<ApiResponse> {
+    /// Records the tokens of every call in `usage_meter`
+    pub fn with_usage_meter(mut self, usage_meter: Arc<UsageMeter>) -> Self {
+        self.usage_meter = Some(usage_meter);
+        self
+    }
+
+    /// Records every request and response in the cassette of `recorder`
+    pub fn with_recorder(mut self, recorder: Arc<Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    pub async fn chat_completion(
+        &self,
+        messages: &[Message],
+        profile: &ModelProfile,
+    ) -> Result<ApiResponse> {
         let url = format!("{}/chat/completions", self.base_url);
 
-        // TODO: add these params to the config
-        let body = serde_json::json!({
-            "messages": [
-                {
-                    "role": "system",
-                    "content": context,
-                },
-                {
-                    "role": "user",
-                    "content": prompt
-                }
-            ],
-            "model": "meta-llama/Meta-Llama-3.1-70B-Instruct",
-            "max_tokens": 512,
-            "temperature": 1,
-            "top_p": 0.95,
-            "top_k": 40,
+        let mut body = serde_json::json!({
+            "messages": messages,
             "stream": false,
         });
+        profile.extend_openai_request(&mut body, &self.model);
+        if profile.top_k.is_none() && profile.temperature > 0.0 {
+            body["top_k"] = DEFAULT_TOP_K.into();
+        }
 
-        self.client
+        let body = body.to_string();
+        let response = self
+            .client
             .post(&url)
             .header("Content-Type", "application/json")
             .header(
                 "Authorization",
-                format!("Bearer {}", self.hyperbolic_api_key),
+                format!("Bearer {}", self.hyperbolic_api_key.expose_secret()),
             )
-            .body(body.to_string())
+            .body(body.clone())
             .send()
             .await
-            .map_err(|e| anyhow!("{e:?}"))?
-            .json::<ApiResponse>()
-            .await
-            .map_err(|e| anyhow!("{e:?}"))
+            .map_err(|e| anyhow!("{e:?}"))?;
+        let res = read_response::<ApiResponse>(
+            self.recorder.as_deref(),
+            "hyperbolic",
+            &self.base_url,
+            &body,
+            response,
+        )
+        .await?;
+
+        if let Some(usage_meter) = &self.usage_meter {
+            usage_meter.record(
+                "hyperbolic",
+                &res.model,
+                &profile.task,
+                res.usage.prompt_tokens as u64,
+                res.usage.completion_tokens as u64,
+            );
+        }
+        Ok(res)
+    }
+}
+
+#[async_trait]
+impl ChatModel for HyperbolicClient {
+    async fn chat(&self, messages: &[Message], profile: &ModelProfile) -> Result<String> {
+        let mut res = self.chat_completion(messages, profile).await?;
+        if res.choices.is_empty() {
+            return Err(anyhow!("Hyperbolic API response contained no choices"));
+        }
+        Ok(res.choices.swap_remove(0).message.content)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::hyperbolic::HyperbolicClient;
+    use crate::{
+        hyperbolic::HyperbolicClient,
+        llm::{ChatModel, ModelProfile},
+    };
 
     #[ignore]
     #[tokio::test]
     async fn test_generate_text() {
         let base_url = "https://api.hyperbolic.xyz/v1".to_string();
-        let hyperbolic_api_key = "".to_string();
-        let client = HyperbolicClient::new(hyperbolic_api_key, base_url);
+        let hyperbolic_api_key = "".into();
+        let client = HyperbolicClient::new(
+            hyperbolic_api_key,
+            base_url,
+            "meta-llama/Meta-Llama-3.1-70B-Instruct".to_string(),
+        );
 
         let res = client
             .generate_text(
                 "hey shitalik, when does ethereum go to zero?",
                 "write a witty response to this tweet",
+                &ModelProfile::creative(),
             )
             .await
             .unwrap();
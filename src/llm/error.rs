@@ -0,0 +1,63 @@
+// Chat providers answer a failed request with an error status. Server errors and rate limits
+// go away on their own, anything else in the 4xx range is a request the provider turned down,
+// e.g. a context that is too long or an invalid schema, and fails the same way on a retry.
+
+use std::fmt::Display;
+
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::cassette::{self, Recorder};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProviderError {
+    pub status: u16,
+    pub detail: String,
+}
+
+impl ProviderError {
+    /// Whether the provider might answer the same request later
+    pub fn is_transient(&self) -> bool {
+        self.status == 429 || self.status >= 500
+    }
+}
+
+impl Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Provider answered {}: {}", self.status, self.detail)
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+/// Reads the JSON body of a chat provider's response like `cassette::read_json`, an error
+/// status becomes a `ProviderError`
+pub async fn read_response<T: DeserializeOwned>(
+    recorder: Option<&Recorder>,
+    service: &str,
+    base_url: &str,
+    request_body: &str,
+    response: reqwest::Response,
+) -> Result<T> {
+    let status = response.status().as_u16();
+    let json = cassette::read_json::<Value>(
+        recorder,
+        service,
+        base_url,
+        "POST",
+        Some(request_body),
+        response,
+    )
+    .await;
+    if !(200..300).contains(&status) {
+        let detail = match json {
+            Ok(json) => json.to_string(),
+            Err(e) => e.to_string(),
+        };
+        return Err(ProviderError { status, detail }.into());
+    }
+
+    let json = json?;
+    T::deserialize(&json).map_err(|e| anyhow!("{e:?}: {json}"))
+}
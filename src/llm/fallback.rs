@@ -0,0 +1,488 @@
+// Tries the configured chat providers in order until one answers. Every provider has a circuit
+// breaker: after `failure_threshold` failures in a row it is skipped for `open_secs`, then a
+// single request probes whether it is back before it gets the full traffic again.
+// Only unreachable providers, server errors and rate limits count as failures. A request the
+// provider turned down goes straight back to the caller, it would fail on every provider.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use super::{error::ProviderError, ChatModel, Message, ModelProfile, Tool};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct CircuitBreakerConfig {
+    /// Failures in a row after which a provider is skipped, defaults to 3
+    pub failure_threshold: Option<u32>,
+    /// How long a provider is skipped before it is probed again, defaults to 60s
+    pub open_secs: Option<u64>,
+    /// How often a provider is retried before falling back to the next one, defaults to 0
+    pub retries: Option<u32>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Requests go through
+    Closed,
+    /// The provider is skipped
+    Open,
+    /// The next request probes if the provider is back
+    HalfOpen,
+}
+
+/// Health of a provider as shown on the health endpoint
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ProviderHealth {
+    pub name: String,
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+}
+
+type ChatFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+struct Breaker {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    /// A probe request is in flight while half-open
+    probing: bool,
+    last_error: Option<String>,
+}
+
+impl Breaker {
+    fn state(&self, open_duration: Duration) -> BreakerState {
+        match self.opened_at {
+            None => BreakerState::Closed,
+            Some(opened_at) if opened_at.elapsed() >= open_duration => BreakerState::HalfOpen,
+            Some(_) => BreakerState::Open,
+        }
+    }
+}
+
+struct Provider {
+    name: String,
+    model: Box<dyn ChatModel>,
+    breaker: Mutex<Breaker>,
+}
+
+pub struct FallbackChatModel {
+    providers: Vec<Provider>,
+    failure_threshold: u32,
+    open_duration: Duration,
+    retries: u32,
+    health: watch::Sender<Vec<ProviderHealth>>,
+}
+
+impl FallbackChatModel {
+    /// `providers` are named chat models in the order they are tried. The health of the
+    /// providers is published on `health` whenever it changes.
+    pub fn new(
+        providers: Vec<(String, Box<dyn ChatModel>)>,
+        config: &CircuitBreakerConfig,
+        health: watch::Sender<Vec<ProviderHealth>>,
+    ) -> Self {
+        let providers = providers
+            .into_iter()
+            .map(|(name, model)| Provider {
+                name,
+                model,
+                breaker: Mutex::new(Breaker {
+                    consecutive_failures: 0,
+                    opened_at: None,
+                    probing: false,
+                    last_error: None,
+                }),
+            })
+            .collect();
+        let fallback = Self {
+            providers,
+            failure_threshold: config.failure_threshold.unwrap_or(3).max(1),
+            open_duration: Duration::from_secs(config.open_secs.unwrap_or(60)),
+            retries: config.retries.unwrap_or(0),
+            health,
+        };
+        fallback.publish_health();
+        fallback
+    }
+
+    pub fn health(&self) -> Vec<ProviderHealth> {
+        self.providers
+            .iter()
+            .map(|provider| {
+                let breaker = provider.breaker.lock().unwrap();
+                ProviderHealth {
+                    name: provider.name.clone(),
+                    state: breaker.state(self.open_duration),
+                    consecutive_failures: breaker.consecutive_failures,
+                    last_error: breaker.last_error.clone(),
+                }
+            })
+            .collect()
+    }
+
+    fn publish_health(&self) {
+        self.health.send_replace(self.health());
+    }
+
+    /// Whether a request may go to the provider. While half-open only one probe is let through.
+    fn acquire(&self, provider: &Provider) -> bool {
+        let mut breaker = provider.breaker.lock().unwrap();
+        match breaker.state(self.open_duration) {
+            BreakerState::Closed => true,
+            BreakerState::Open => false,
+            BreakerState::HalfOpen if breaker.probing => false,
+            BreakerState::HalfOpen => {
+                breaker.probing = true;
+                true
+            }
+        }
+    }
+
+    fn record<T>(&self, provider: &Provider, result: &Result<T>) {
+        {
+            let mut breaker = provider.breaker.lock().unwrap();
+            breaker.probing = false;
+            match result {
+                Ok(_) => {
+                    if breaker.opened_at.is_some() {
+                        tracing::info!("Chat provider {} recovered", provider.name);
+                    }
+                    breaker.consecutive_failures = 0;
+                    breaker.opened_at = None;
+                }
+                // The provider is up, the request was bad
+                Err(e) if !is_provider_failure(e) => {}
+                Err(e) => {
+                    breaker.consecutive_failures += 1;
+                    breaker.last_error = Some(format!("{e:?}"));
+                    // A failed probe opens the breaker again right away
+                    if breaker.opened_at.is_some()
+                        || breaker.consecutive_failures >= self.failure_threshold
+                    {
+                        if breaker.opened_at.is_none() {
+                            tracing::warn!(
+                                "Chat provider {} failed {} times in a row, skipping it for {}s",
+                                provider.name,
+                                breaker.consecutive_failures,
+                                self.open_duration.as_secs()
+                            );
+                        }
+                        breaker.opened_at = Some(Instant::now());
+                    }
+                }
+            }
+        }
+        self.publish_health();
+    }
+
+    /// Runs `request` against the providers in order and returns the first success
+    async fn call<'a, T, F>(&'a self, tools: bool, request: F) -> Result<T>
+    where
+        F: Fn(&'a dyn ChatModel) -> ChatFuture<'a, T>,
+    {
+        let mut errors = Vec::new();
+        for provider in &self.providers {
+            if tools && !provider.model.supports_tools() {
+                continue;
+            }
+            for _ in 0..=self.retries {
+                if !self.acquire(provider) {
+                    errors.push(format!("{}: circuit open", provider.name));
+                    break;
+                }
+                let result = request(provider.model.as_ref()).await;
+                self.record(provider, &result);
+                match result {
+                    Ok(response) => return Ok(response),
+                    Err(e) if !is_provider_failure(&e) => return Err(e),
+                    Err(e) => {
+                        tracing::warn!("Chat provider {} failed: {e:?}", provider.name);
+                        errors.push(format!("{}: {e}", provider.name));
+                    }
+                }
+            }
+        }
+        if errors.is_empty() {
+            return Err(anyhow!("No chat provider supports tool calls"));
+        }
+        Err(anyhow!("All chat providers failed: {}", errors.join("; ")))
+    }
+}
+
+/// Whether `e` says something about the provider rather than the request
+fn is_provider_failure(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<ProviderError>() {
+        Some(e) => e.is_transient(),
+        // The provider couldn't be reached or answered with garbage
+        None => true,
+    }
+}
+
+#[async_trait]
+impl ChatModel for FallbackChatModel {
+    async fn chat(&self, messages: &[Message], profile: &ModelProfile) -> Result<String> {
+        self.call(false, |model| model.chat(messages, profile))
+            .await
+    }
+
+    async fn chat_structured(
+        &self,
+        messages: &[Message],
+        schema_name: &str,
+        schema: &serde_json::Value,
+        profile: &ModelProfile,
+    ) -> Result<String> {
+        self.call(false, |model| {
+            model.chat_structured(messages, schema_name, schema, profile)
+        })
+        .await
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.providers.iter().any(|p| p.model.supports_tools())
+    }
+
+    async fn chat_with_tools(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+        profile: &ModelProfile,
+    ) -> Result<Message> {
+        self.call(true, |model| {
+            model.chat_with_tools(messages, tools, profile)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+    use tokio::sync::watch;
+
+    use crate::{
+        llm::{
+            error::ProviderError,
+            fallback::{BreakerState, CircuitBreakerConfig, FallbackChatModel},
+            ChatModel, ModelProfile,
+        },
+        openai::OpenAIClient,
+    };
+
+    struct Stub {
+        /// The first `failures` requests get `failure_status`
+        failures: usize,
+        failure_status: StatusCode,
+        requests: AtomicUsize,
+        answer: &'static str,
+    }
+
+    /// An OpenAI compatible server that fails a number of requests before it answers
+    async fn start_stub_server(failures: usize, answer: &'static str) -> (String, Arc<Stub>) {
+        start_stub_server_with_status(failures, StatusCode::INTERNAL_SERVER_ERROR, answer).await
+    }
+
+    async fn start_stub_server_with_status(
+        failures: usize,
+        failure_status: StatusCode,
+        answer: &'static str,
+    ) -> (String, Arc<Stub>) {
+        async fn chat_completions(
+            State(stub): State<Arc<Stub>>,
+        ) -> Result<Json<serde_json::Value>, StatusCode> {
+            if stub.requests.fetch_add(1, Ordering::SeqCst) < stub.failures {
+                return Err(stub.failure_status);
+            }
+            Ok(Json(serde_json::json!({
+                "id": "chatcmpl-1",
+                "model": "stub-model",
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": stub.answer },
+                    "finish_reason": "stop"
+                }]
+            })))
+        }
+
+        let stub = Arc::new(Stub {
+            failures,
+            failure_status,
+            requests: AtomicUsize::new(0),
+            answer,
+        });
+        let app = Router::new()
+            .route("/v1/chat/completions", post(chat_completions))
+            .with_state(stub.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        (format!("http://{addr}/v1"), stub)
+    }
+
+    fn provider(name: &str, base_url: String) -> (String, Box<dyn ChatModel>) {
+        (
+            name.to_string(),
+            Box::new(OpenAIClient::new("".into(), base_url)),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_and_opens_circuit() {
+        let (primary_url, primary) = start_stub_server(usize::MAX, "primary").await;
+        let (secondary_url, secondary) = start_stub_server(0, "secondary").await;
+        let (health, health_receiver) = watch::channel(Vec::new());
+        let model = FallbackChatModel::new(
+            vec![
+                provider("primary", primary_url),
+                provider("secondary", secondary_url),
+            ],
+            &CircuitBreakerConfig {
+                failure_threshold: Some(2),
+                open_secs: Some(3600),
+                retries: None,
+            },
+            health,
+        );
+
+        for _ in 0..4 {
+            let res = model
+                .generate_text("be nice", "say hi", &ModelProfile::deterministic())
+                .await
+                .unwrap();
+            assert_eq!(res, "secondary");
+        }
+
+        // The primary is skipped once its circuit is open
+        assert_eq!(primary.requests.load(Ordering::SeqCst), 2);
+        assert_eq!(secondary.requests.load(Ordering::SeqCst), 4);
+        let health = health_receiver.borrow().clone();
+        assert_eq!(health[0].state, BreakerState::Open);
+        assert_eq!(health[0].consecutive_failures, 2);
+        assert!(health[0].last_error.is_some());
+        assert_eq!(health[1].state, BreakerState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_bad_request_is_not_a_provider_failure() {
+        let (primary_url, primary) =
+            start_stub_server_with_status(usize::MAX, StatusCode::BAD_REQUEST, "primary").await;
+        let (secondary_url, secondary) = start_stub_server(0, "secondary").await;
+        let (health, _) = watch::channel(Vec::new());
+        let model = FallbackChatModel::new(
+            vec![
+                provider("primary", primary_url),
+                provider("secondary", secondary_url),
+            ],
+            &CircuitBreakerConfig {
+                failure_threshold: Some(1),
+                open_secs: Some(3600),
+                retries: Some(1),
+            },
+            health,
+        );
+        let profile = ModelProfile::deterministic();
+
+        for _ in 0..3 {
+            let e = model.generate_text("", "hi", &profile).await.unwrap_err();
+            assert_eq!(e.downcast_ref::<ProviderError>().unwrap().status, 400);
+        }
+
+        // Neither retried nor sent to the next provider, and the circuit stays closed
+        assert_eq!(primary.requests.load(Ordering::SeqCst), 3);
+        assert_eq!(secondary.requests.load(Ordering::SeqCst), 0);
+        assert_eq!(model.health()[0].state, BreakerState::Closed);
+        assert_eq!(model.health()[0].consecutive_failures, 0);
+    }
+
+    #[tokio::test]
+    async fn test_half_open_probe_closes_circuit() {
+        let (primary_url, primary) = start_stub_server(2, "primary").await;
+        let (secondary_url, _) = start_stub_server(0, "secondary").await;
+        let (health, _) = watch::channel(Vec::new());
+        let model = FallbackChatModel::new(
+            vec![
+                provider("primary", primary_url),
+                provider("secondary", secondary_url),
+            ],
+            &CircuitBreakerConfig {
+                failure_threshold: Some(2),
+                // Probe again right away
+                open_secs: Some(0),
+                retries: None,
+            },
+            health,
+        );
+        let profile = ModelProfile::deterministic();
+
+        assert_eq!(
+            model.generate_text("", "hi", &profile).await.unwrap(),
+            "secondary"
+        );
+        assert_eq!(
+            model.generate_text("", "hi", &profile).await.unwrap(),
+            "secondary"
+        );
+        assert_eq!(model.health()[0].state, BreakerState::HalfOpen);
+
+        // The probe succeeds and the primary gets the traffic again
+        assert_eq!(
+            model.generate_text("", "hi", &profile).await.unwrap(),
+            "primary"
+        );
+        assert_eq!(model.health()[0].state, BreakerState::Closed);
+        assert_eq!(model.health()[0].consecutive_failures, 0);
+        assert_eq!(primary.requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retries_before_falling_back() {
+        let (primary_url, primary) = start_stub_server(1, "primary").await;
+        let (secondary_url, secondary) = start_stub_server(usize::MAX, "secondary").await;
+        let (health, _) = watch::channel(Vec::new());
+        let model = FallbackChatModel::new(
+            vec![
+                provider("primary", primary_url),
+                provider("secondary", secondary_url.clone()),
+            ],
+            &CircuitBreakerConfig {
+                retries: Some(1),
+                ..Default::default()
+            },
+            health,
+        );
+        let profile = ModelProfile::deterministic();
+
+        assert_eq!(
+            model.generate_text("", "hi", &profile).await.unwrap(),
+            "primary"
+        );
+        assert_eq!(primary.requests.load(Ordering::SeqCst), 2);
+        assert_eq!(secondary.requests.load(Ordering::SeqCst), 0);
+
+        // Nothing left to fall back to
+        let model = FallbackChatModel::new(
+            vec![provider("secondary", secondary_url)],
+            &CircuitBreakerConfig::default(),
+            watch::channel(Vec::new()).0,
+        );
+        let err = model.generate_text("", "hi", &profile).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("All chat providers failed: secondary"));
+    }
+}
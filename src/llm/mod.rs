@@ -0,0 +1,510 @@
+// The agent talks to its language model through the `ChatModel` trait so the provider can be
+// swapped in config.toml without touching the agent.
+
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::{
+    anthropic::AnthropicClient, cassette, env::EnvVariables, hyperbolic::HyperbolicClient,
+    openai::OpenAIClient, secret::Secret, usage::UsageMeter,
+};
+
+use self::fallback::{CircuitBreakerConfig, FallbackChatModel, ProviderHealth};
+
+pub mod error;
+pub mod fallback;
+pub mod structured;
+
+const HYPERBOLIC_API_URL: &str = "https://api.hyperbolic.xyz/v1";
+const HYPERBOLIC_MODEL: &str = "meta-llama/Meta-Llama-3.1-70B-Instruct";
+const OPEN_AI_API_URL: &str = "https://api.openai.com/v1";
+const OPEN_AI_MODEL: &str = "gpt-4o-mini";
+const ANTHROPIC_API_URL: &str = "https://api.anthropic.com/v1";
+const ANTHROPIC_MODEL: &str = "claude-3-5-sonnet-latest";
+
+#[async_trait]
+pub trait ChatModel: Send + Sync {
+    /// Generates the next assistant message of the conversation in `messages`.
+    /// The model and sampling parameters come from `profile`.
+    async fn chat(&self, messages: &[Message], profile: &ModelProfile) -> Result<String>;
+
+    /// Like `chat` but constrains the response to the JSON `schema` if the provider supports it.
+    /// The prompt has to ask for the JSON as well for providers that don't.
+    async fn chat_structured(
+        &self,
+        messages: &[Message],
+        _schema_name: &str,
+        _schema: &serde_json::Value,
+        profile: &ModelProfile,
+    ) -> Result<String> {
+        self.chat(messages, profile).await
+    }
+
+    /// Whether `chat_with_tools` is implemented
+    fn supports_tools(&self) -> bool {
+        false
+    }
+
+    /// Lets the model answer or call some of the `tools`. The returned assistant message holds
+    /// the tool calls, if any. Providers without tool calls return an error.
+    async fn chat_with_tools(
+        &self,
+        _messages: &[Message],
+        _tools: &[Tool],
+        _profile: &ModelProfile,
+    ) -> Result<Message> {
+        Err(anyhow!("This chat provider does not support tool calls"))
+    }
+
+    /// Generates a response to `prompt` using `context` as the system prompt.
+    async fn generate_text(
+        &self,
+        context: &str,
+        prompt: &str,
+        profile: &ModelProfile,
+    ) -> Result<String> {
+        self.chat(&[Message::system(context), Message::user(prompt)], profile)
+            .await
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    /// The result of a tool call
+    Tool,
+}
+
+/// A message of a chat conversation
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+    /// Tells apart the participants of a conversation with the same role, e.g. the users in a
+    /// reply thread
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Tools the assistant called in this message
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// The tool call a tool message is the result of
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl Message {
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            name: None,
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::new(Role::System, content)
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new(Role::User, content)
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::new(Role::Assistant, content)
+    }
+
+    pub fn tool(tool_call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id.into()),
+            ..Self::new(Role::Tool, content)
+        }
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+}
+
+/// A function the model can call, `parameters` is the JSON schema of its arguments
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value,
+}
+
+impl Tool {
+    /// The tool in the format of OpenAI's `tools` request field
+    pub fn to_openai(&self) -> serde_json::Value {
+        serde_json::json!({
+            "type": "function",
+            "function": {
+                "name": self.name,
+                "description": self.description,
+                "parameters": self.parameters,
+            }
+        })
+    }
+}
+
+/// A call of a tool in the format of the OpenAI API
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON encoded arguments, not guaranteed to be valid
+    pub arguments: String,
+}
+
+/// Model and sampling parameters used for a generation
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModelProfile {
+    /// Overrides the model set in `[chat_model]`
+    pub model: Option<String>,
+    pub max_tokens: u32,
+    pub temperature: f32,
+    pub top_p: Option<f32>,
+    /// Not supported by OpenAI itself, only set this for servers that accept it
+    pub top_k: Option<u32>,
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// Ignored by providers that don't support seeded sampling
+    pub seed: Option<u64>,
+    /// The task the profile was resolved for, the usage of a call is booked on it
+    #[serde(skip)]
+    pub task: String,
+}
+
+impl ModelProfile {
+    /// The sampling the agent always used, for generating tweets and thoughts
+    pub fn creative() -> Self {
+        Self {
+            model: None,
+            max_tokens: 512,
+            temperature: 1.0,
+            top_p: Some(0.95),
+            top_k: None,
+            stop: Vec::new(),
+            seed: None,
+            task: String::new(),
+        }
+    }
+
+    /// Greedy sampling for scores and decisions that should not change from run to run
+    pub fn deterministic() -> Self {
+        Self {
+            model: None,
+            max_tokens: 128,
+            temperature: 0.0,
+            top_p: None,
+            top_k: None,
+            stop: Vec::new(),
+            seed: Some(0),
+            task: String::new(),
+        }
+    }
+
+    /// Adds the model and sampling parameters to an OpenAI style chat completions request
+    pub fn extend_openai_request(&self, body: &mut serde_json::Value, default_model: &str) {
+        body["model"] = self.model.as_deref().unwrap_or(default_model).into();
+        body["max_tokens"] = self.max_tokens.into();
+        body["temperature"] = self.temperature.into();
+        if let Some(top_p) = self.top_p {
+            body["top_p"] = top_p.into();
+        }
+        if let Some(top_k) = self.top_k {
+            body["top_k"] = top_k.into();
+        }
+        if !self.stop.is_empty() {
+            body["stop"] = self.stop.clone().into();
+        }
+        if let Some(seed) = self.seed {
+            body["seed"] = seed.into();
+        }
+    }
+}
+
+/// Which named profile each of the agent's tasks uses
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct TaskProfileNames {
+    pub tweet: Option<String>,
+    pub short_term_memory: Option<String>,
+    pub scoring: Option<String>,
+    pub follow: Option<String>,
+    pub mention_ranking: Option<String>,
+    pub reply: Option<String>,
+    pub wallet_decision: Option<String>,
+    pub tool_agent: Option<String>,
+    pub engagement: Option<String>,
+}
+
+/// The resolved profile for each of the agent's tasks
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskProfiles {
+    pub tweet: ModelProfile,
+    pub short_term_memory: ModelProfile,
+    pub scoring: ModelProfile,
+    pub follow: ModelProfile,
+    pub mention_ranking: ModelProfile,
+    pub reply: ModelProfile,
+    pub wallet_decision: ModelProfile,
+    pub tool_agent: ModelProfile,
+    pub engagement: ModelProfile,
+}
+
+impl TaskProfiles {
+    /// Looks up the profile of every task. The profiles "creative" and "deterministic" always
+    /// exist unless they are overridden in config.toml.
+    pub fn resolve(
+        profiles: &HashMap<String, ModelProfile>,
+        names: &TaskProfileNames,
+    ) -> Result<Self> {
+        let mut all = HashMap::from([
+            ("creative".to_string(), ModelProfile::creative()),
+            ("deterministic".to_string(), ModelProfile::deterministic()),
+        ]);
+        all.extend(profiles.clone());
+
+        let get = |task: &str, name: &Option<String>, default: &str| {
+            let name = name.as_deref().unwrap_or(default);
+            all.get(name)
+                .map(|profile| ModelProfile {
+                    task: task.into(),
+                    ..profile.clone()
+                })
+                .ok_or_else(|| anyhow!("Unknown model profile {name} for the {task} task"))
+        };
+
+        Ok(Self {
+            tweet: get("tweet", &names.tweet, "creative")?,
+            short_term_memory: get("short_term_memory", &names.short_term_memory, "creative")?,
+            scoring: get("scoring", &names.scoring, "deterministic")?,
+            follow: get("follow", &names.follow, "deterministic")?,
+            mention_ranking: get("mention_ranking", &names.mention_ranking, "deterministic")?,
+            reply: get("reply", &names.reply, "creative")?,
+            wallet_decision: get("wallet_decision", &names.wallet_decision, "deterministic")?,
+            tool_agent: get("tool_agent", &names.tool_agent, "creative")?,
+            engagement: get("engagement", &names.engagement, "deterministic")?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum ChatProvider {
+    #[default]
+    #[serde(rename = "hyperbolic")]
+    Hyperbolic,
+    /// OpenAI or any server with an OpenAI compatible chat completions endpoint
+    /// (vLLM, llama.cpp server, Ollama, ...)
+    #[serde(rename = "openai")]
+    OpenAI,
+    #[serde(rename = "anthropic")]
+    Anthropic,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ChatModelConfig {
+    pub provider: ChatProvider,
+    /// Defaults to a sensible model for the provider
+    pub model: Option<String>,
+    /// Defaults to the provider's public API
+    pub base_url: Option<String>,
+}
+
+impl ChatModelConfig {
+    /// Name of the provider in logs and on the health endpoint
+    pub fn name(&self) -> String {
+        let provider = match self.provider {
+            ChatProvider::Hyperbolic => "hyperbolic",
+            ChatProvider::OpenAI => "openai",
+            ChatProvider::Anthropic => "anthropic",
+        };
+        match &self.model {
+            Some(model) => format!("{provider} ({model})"),
+            None => provider.to_string(),
+        }
+    }
+}
+
+/// Builds the chat model selected in the config with the api keys we were given at startup.
+/// The tokens of every call are recorded in `usage_meter`.
+pub fn build_chat_model(
+    config: &ChatModelConfig,
+    env: &EnvVariables,
+    usage_meter: Arc<UsageMeter>,
+) -> Result<Box<dyn ChatModel>> {
+    let missing_key =
+        |name: &str| anyhow!("{name} is required for the {:?} provider", config.provider);
+
+    let chat_model: Box<dyn ChatModel> = match config.provider {
+        ChatProvider::Hyperbolic => Box::new(
+            HyperbolicClient::new(
+                env.hyperbolic_api_key
+                    .clone()
+                    .ok_or_else(|| missing_key("hyperbolic_api_key"))?,
+                cassette::replay_url("hyperbolic")
+                    .or(config.base_url.clone())
+                    .unwrap_or(HYPERBOLIC_API_URL.into()),
+                config.model.clone().unwrap_or(HYPERBOLIC_MODEL.into()),
+            )
+            .with_usage_meter(usage_meter),
+        ),
+        ChatProvider::OpenAI => {
+            let base_url = config.base_url.clone().unwrap_or(OPEN_AI_API_URL.into());
+            // Local servers usually don't need a key, only fall back to the OpenAI key when
+            // we are actually talking to OpenAI
+            let api_key = match &env.chat_api_key {
+                Some(key) => key.clone(),
+                None if base_url == OPEN_AI_API_URL => env
+                    .open_ai_api_key
+                    .clone()
+                    .ok_or_else(|| missing_key("open_ai_api_key"))?,
+                None => Secret::default(),
+            };
+            let base_url = cassette::replay_url("openai").unwrap_or(base_url);
+            Box::new(
+                OpenAIClient::new(api_key, base_url)
+                    .with_chat_model(config.model.clone().unwrap_or(OPEN_AI_MODEL.into()))
+                    .with_usage_meter(usage_meter),
+            )
+        }
+        ChatProvider::Anthropic => Box::new(
+            AnthropicClient::new(
+                env.anthropic_api_key
+                    .clone()
+                    .ok_or_else(|| missing_key("anthropic_api_key"))?,
+                cassette::replay_url("anthropic")
+                    .or(config.base_url.clone())
+                    .unwrap_or(ANTHROPIC_API_URL.into()),
+                config.model.clone().unwrap_or(ANTHROPIC_MODEL.into()),
+            )
+            .with_usage_meter(usage_meter),
+        ),
+    };
+
+    Ok(chat_model)
+}
+
+/// Builds the chat models of all `configs`, which are tried in order when one fails.
+pub fn build_fallback_chat_model(
+    configs: &[ChatModelConfig],
+    circuit_breaker: &CircuitBreakerConfig,
+    env: &EnvVariables,
+    usage_meter: Arc<UsageMeter>,
+    health: watch::Sender<Vec<ProviderHealth>>,
+) -> Result<FallbackChatModel> {
+    let providers = configs
+        .iter()
+        .map(|config| {
+            Ok((
+                config.name(),
+                build_chat_model(config, env, usage_meter.clone())?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(FallbackChatModel::new(providers, circuit_breaker, health))
+}
+
+#[test]
+fn test_resolve_task_profiles() {
+    let profiles: HashMap<String, ModelProfile> = toml::from_str(
+        r#"
+        [spicy]
+        model = "meta-llama/Meta-Llama-3.1-405B-Instruct"
+        max_tokens = 256
+        temperature = 1.2
+        stop = ["\n\n"]
+        "#,
+    )
+    .unwrap();
+    let names: TaskProfileNames = toml::from_str(r#"tweet = "spicy""#).unwrap();
+
+    let profiles = TaskProfiles::resolve(&profiles, &names).unwrap();
+    assert_eq!(profiles.tweet.temperature, 1.2);
+    assert_eq!(profiles.tweet.stop, vec!["\n\n".to_string()]);
+    assert_eq!(
+        profiles.reply,
+        ModelProfile {
+            task: "reply".into(),
+            ..ModelProfile::creative()
+        }
+    );
+    assert_eq!(profiles.scoring.task, "scoring");
+    assert_eq!(profiles.scoring.temperature, 0.0);
+
+    let names = TaskProfileNames {
+        scoring: Some("missing".into()),
+        ..Default::default()
+    };
+    assert!(TaskProfiles::resolve(&HashMap::new(), &names).is_err());
+}
+
+#[test]
+fn test_serialize_messages() {
+    let messages = vec![
+        Message::system("be nice"),
+        Message::user("gm").with_name("vitalik"),
+        Message::assistant("gm ser"),
+    ];
+
+    assert_eq!(
+        serde_json::to_value(&messages).unwrap(),
+        serde_json::json!([
+            { "role": "system", "content": "be nice" },
+            { "role": "user", "content": "gm", "name": "vitalik" },
+            { "role": "assistant", "content": "gm ser" },
+        ])
+    );
+
+    let call: Message = serde_json::from_value(serde_json::json!({
+        "role": "assistant",
+        "content": "",
+        "tool_calls": [{
+            "id": "call_1",
+            "type": "function",
+            "function": { "name": "like", "arguments": "{\"tweet_id\":\"1\"}" }
+        }]
+    }))
+    .unwrap();
+    assert_eq!(call.tool_calls[0].function.name, "like");
+    assert_eq!(
+        serde_json::to_value(Message::tool("call_1", "liked")).unwrap(),
+        serde_json::json!({ "role": "tool", "content": "liked", "tool_call_id": "call_1" })
+    );
+}
+
+#[test]
+fn test_parse_chat_model_config() {
+    let config: ChatModelConfig = toml::from_str(
+        r#"
+        provider = "openai"
+        model = "llama3.1"
+        base_url = "http://localhost:11434/v1"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(config.provider, ChatProvider::OpenAI);
+    assert_eq!(config.model.as_deref(), Some("llama3.1"));
+
+    let config: ChatModelConfig = toml::from_str(r#"provider = "anthropic""#).unwrap();
+    assert_eq!(config.provider, ChatProvider::Anthropic);
+    assert_eq!(config.base_url, None);
+}
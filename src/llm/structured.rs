@@ -0,0 +1,180 @@
+// Asks the model for JSON that matches a schema and deserializes it into a typed response.
+// Models are chatty, so invalid responses are shown the error and get a few tries to fix it.
+
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use super::{ChatModel, Message, ModelProfile};
+
+/// A response type the model can be asked for
+pub trait StructuredOutput: DeserializeOwned + Send {
+    /// Name of the schema, providers with a JSON schema mode require one
+    const NAME: &'static str;
+
+    /// JSON schema of the response. Keep it to what OpenAI's strict mode supports: every
+    /// property required and no additional properties.
+    fn schema() -> Value;
+}
+
+/// Generates a `T` in up to `max_tries` tries. `validate` checks what the schema can't express,
+/// its error is fed back to the model like a parse error.
+pub async fn generate_structured<T, F>(
+    chat_model: &dyn ChatModel,
+    context: &str,
+    prompt: &str,
+    profile: &ModelProfile,
+    max_tries: u32,
+    validate: F,
+) -> Result<T>
+where
+    T: StructuredOutput,
+    F: Fn(&T) -> Result<()> + Sync,
+{
+    let schema = T::schema();
+    let mut messages = vec![
+        Message::system(context),
+        Message::user(format!(
+            "{prompt}\n\nRespond only with a JSON object that matches this JSON schema:\n{schema}"
+        )),
+    ];
+
+    let mut last_error = anyhow!("no tries");
+    for _ in 0..max_tries {
+        let response = chat_model
+            .chat_structured(&messages, T::NAME, &schema, profile)
+            .await?;
+        match parse::<T>(&response).and_then(|output| validate(&output).map(|_| output)) {
+            Ok(output) => return Ok(output),
+            Err(e) => {
+                tracing::warn!("Invalid {} response {response:?}: {e}", T::NAME);
+                messages.push(Message::assistant(response));
+                messages.push(Message::user(format!(
+                    "That response is invalid: {e}. Respond again with only the corrected JSON object."
+                )));
+                last_error = e;
+            }
+        }
+    }
+
+    Err(anyhow!(
+        "No valid {} response after {max_tries} tries: {last_error}",
+        T::NAME
+    ))
+}
+
+fn parse<T: DeserializeOwned>(response: &str) -> Result<T> {
+    // Models like to wrap the JSON in a markdown code block or a sentence
+    let json = match (response.find('{'), response.rfind('}')) {
+        (Some(start), Some(end)) if start < end => &response[start..=end],
+        _ => response,
+    };
+    serde_json::from_str(json).map_err(|e| anyhow!("{e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use anyhow::{bail, Result};
+    use async_trait::async_trait;
+    use serde::Deserialize;
+    use serde_json::Value;
+
+    use super::{generate_structured, parse, StructuredOutput};
+    use crate::llm::{ChatModel, Message, ModelProfile};
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Score {
+        score: u8,
+    }
+
+    impl StructuredOutput for Score {
+        const NAME: &'static str = "score";
+
+        fn schema() -> Value {
+            serde_json::json!({
+                "type": "object",
+                "properties": { "score": { "type": "integer" } },
+                "required": ["score"],
+                "additionalProperties": false
+            })
+        }
+    }
+
+    /// Answers with the queued responses and remembers the conversations it was sent
+    struct ScriptedModel {
+        responses: Mutex<Vec<&'static str>>,
+        conversations: Mutex<Vec<Vec<Message>>>,
+    }
+
+    #[async_trait]
+    impl ChatModel for ScriptedModel {
+        async fn chat(&self, messages: &[Message], _profile: &ModelProfile) -> Result<String> {
+            self.conversations.lock().unwrap().push(messages.to_vec());
+            Ok(self.responses.lock().unwrap().remove(0).into())
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse::<Score>(r#"{"score": 7}"#).unwrap(),
+            Score { score: 7 }
+        );
+        assert_eq!(
+            parse::<Score>("Sure! ```json\n{\"score\": 7}\n```").unwrap(),
+            Score { score: 7 }
+        );
+        assert!(parse::<Score>("7").is_err());
+        assert!(parse::<Score>(r#"{"score": "seven"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_repairs_invalid_responses() {
+        let model = ScriptedModel {
+            responses: Mutex::new(vec!["I'd say 7", r#"{"score": 70}"#, r#"{"score": 7}"#]),
+            conversations: Mutex::new(Vec::new()),
+        };
+        let validate = |s: &Score| {
+            if !(1..=10).contains(&s.score) {
+                bail!("score must be between 1 and 10");
+            }
+            Ok(())
+        };
+
+        let score: Score = generate_structured(
+            &model,
+            "context",
+            "score this",
+            &ModelProfile::deterministic(),
+            3,
+            validate,
+        )
+        .await
+        .unwrap();
+        assert_eq!(score, Score { score: 7 });
+
+        // The last try saw both failed responses and why they failed
+        let conversations = model.conversations.lock().unwrap();
+        let last = conversations.last().unwrap();
+        assert_eq!(last.len(), 6);
+        assert_eq!(last[4].content, r#"{"score": 70}"#);
+        assert!(last[5].content.contains("score must be between 1 and 10"));
+
+        let model = ScriptedModel {
+            responses: Mutex::new(vec!["no", "still no"]),
+            conversations: Mutex::new(Vec::new()),
+        };
+        let res = generate_structured::<Score, _>(
+            &model,
+            "context",
+            "score this",
+            &ModelProfile::deterministic(),
+            2,
+            |_| Ok(()),
+        )
+        .await;
+        assert!(res.is_err());
+    }
+}
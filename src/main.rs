@@ -1,25 +1,39 @@
 use std::error::Error;
 
 use crate::encumber::encumber;
+use agent::Agent;
+use cassette::{CassetteConfig, CassetteMode};
 use config::Config;
 use env::wait_for_api_keys;
-use pipeline::Pipeline;
+use health::AccountHealth;
+use pipeline::{generate_eth_private_key, Pipeline};
 use prompts::Prompts;
 use release_credentials::timelock;
+use tokio::sync::{mpsc, watch};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
 pub mod agent;
+pub mod anthropic;
 pub mod attestation;
+pub mod cassette;
 pub mod config;
 pub mod db;
+pub mod embeddings;
 pub mod encumber;
 pub mod env;
+pub mod health;
 pub mod hyperbolic;
+pub mod llm;
 pub mod openai;
+pub mod password;
 pub mod pipeline;
 pub mod prompts;
 pub mod release_credentials;
+pub mod secret;
+pub mod tokens;
 pub mod twitter;
+pub mod usage;
+pub mod x_budget;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -42,17 +56,39 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let prompts = Prompts::load();
     let config = Config::load();
 
+    if let Some(cassette) = config
+        .cassette
+        .clone()
+        .filter(|c| c.mode == CassetteMode::Replay)
+    {
+        return replay(config, prompts, &cassette).await;
+    }
+
     // First wait to be provided the api keys we need to run the AI Agen
     wait_for_api_keys().await;
 
     // then encumber the account
     tracing::info!("Beginning to encumber Account");
-    let account_details = encumber((&config).into());
+    let account_details = match encumber((&config).into()) {
+        Ok(account_details) => account_details,
+        Err(e) => {
+            tracing::error!("Account encumberence failed: {e:?}");
+            return Err(e.into());
+        }
+    };
     tracing::info!("account encumberence succesful");
+    if let Some(cassette) = &config.cassette {
+        let env = env::ENV.get().expect("unreachable");
+        cassette::init(cassette, cassette::known_secrets(&account_details, env)).await?;
+    }
     // Server for attestation Quote
     tracing::info!("Starting Quote server");
+    let (chat_health_sender, chat_health_receiver) = watch::channel(Vec::new());
+    let (usage_sender, usage_receiver) = watch::channel(Default::default());
     let quote_server_handle = tokio::task::spawn(attestation::server::quote_server(
         account_details.x_account.x_username.clone(),
+        chat_health_receiver,
+        usage_receiver,
     ));
     tracing::info!("Starting account details timelock");
     let timelock_handle = tokio::task::spawn(timelock(
@@ -61,13 +97,57 @@ async fn main() -> Result<(), Box<dyn Error>> {
         config.eth_rpc_url.clone(),
     ));
 
+    tracing::info!("Starting account health monitor");
+    let (health_sender, health_receiver) = watch::channel(AccountHealth::Healthy);
+    let (incident_sender, incident_receiver) = mpsc::unbounded_channel();
+    let health_handle = tokio::task::spawn(health::monitor(
+        account_details.clone(),
+        (&config).into(),
+        health_sender,
+        incident_sender,
+    ));
+
     tracing::info!("AI Agent starting");
-    let mut pipeline = Pipeline::new(config, prompts, account_details).await;
+    let mut pipeline = Pipeline::new(
+        config,
+        prompts,
+        account_details,
+        health_receiver,
+        incident_receiver,
+        chat_health_sender,
+        usage_sender,
+    )
+    .await;
     pipeline.run().await;
 
     // if pipeline stopped running we can shut her down
     quote_server_handle.abort();
     timelock_handle.abort();
+    health_handle.abort();
+
+    Ok(())
+}
+
+/// Runs the agent once against the recorded APIs of the cassette. Nothing is encumbered, no api
+/// keys have to be delivered and the vector db is kept in memory, so this works offline.
+async fn replay(
+    config: Config,
+    prompts: Prompts,
+    cassette: &CassetteConfig,
+) -> Result<(), Box<dyn Error>> {
+    cassette::init(cassette, Vec::new()).await?;
+    let _ = env::ENV.set(cassette::replay_env());
+
+    let agent = Agent::new(
+        cassette::replay_account(&config.x_username),
+        config,
+        generate_eth_private_key(),
+        prompts,
+        watch::channel(Vec::new()).0,
+        watch::channel(Default::default()).0,
+    )
+    .await?;
+    agent.run().await?;
 
     Ok(())
 }
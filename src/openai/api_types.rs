@@ -1,5 +1,7 @@
 use serde::Deserialize;
 
+use crate::llm::ToolCall;
+
 #[derive(Deserialize, Debug)]
 pub struct ApiResponse {
     pub object: String,
@@ -20,3 +22,35 @@ pub struct Usage {
     pub prompt_tokens: usize,
     pub total_tokens: usize,
 }
+
+#[derive(Deserialize, Debug)]
+pub struct ChatResponse {
+    pub id: String,
+    pub model: String,
+    pub choices: Vec<ChatChoice>,
+    // OpenAI compatible servers don't always report usage
+    pub usage: Option<ChatUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatChoice {
+    pub index: usize,
+    pub message: ChatMessage,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatMessage {
+    pub role: String,
+    /// Missing when the model only calls tools
+    pub content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+    pub completion_tokens: usize,
+}
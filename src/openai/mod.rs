@@ -1,43 +1,371 @@
+use std::sync::Arc;
+
 use anyhow::{anyhow, Result};
-use reqwest::Client;
+use async_trait::async_trait;
+use reqwest::{Client, RequestBuilder};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    cassette::{self, Recorder},
+    embeddings::EmbeddingModel,
+    llm::{error::read_response, ChatModel, Message, ModelProfile, Tool},
+    secret::Secret,
+    usage::UsageMeter,
+};
 
-use self::api_types::ApiResponse;
+use self::api_types::{ApiResponse, ChatResponse};
 mod api_types;
 
+const EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+/// Client for the OpenAI API. The chat completions also work against any server with an OpenAI
+/// compatible API like vLLM, llama.cpp server or Ollama.
 pub struct OpenAIClient {
     base_url: String,
-    open_ai_api_key: String,
+    open_ai_api_key: Secret,
+    chat_model: String,
+    usage_meter: Option<Arc<UsageMeter>>,
+    recorder: Option<Arc<Recorder>>,
     client: Client,
 }
 
 impl OpenAIClient {
-    pub fn new(open_ai_api_key: String, base_url: String) -> Self {
+    pub fn new(open_ai_api_key: Secret, base_url: String) -> Self {
         let client = Client::new();
         Self {
             base_url,
             open_ai_api_key,
+            chat_model: "gpt-4o-mini".into(),
+            usage_meter: None,
+            recorder: cassette::recorder(),
             client,
         }
     }
 
-    pub async fn get_text_embedding(&self, text: &str) -> Result<ApiResponse> {
+    /// Sets the model used for chat completions
+    pub fn with_chat_model(mut self, chat_model: String) -> Self {
+        self.chat_model = chat_model;
+        self
+    }
+
+    /// Records the tokens of every call in `usage_meter`
+    pub fn with_usage_meter(mut self, usage_meter: Arc<UsageMeter>) -> Self {
+        self.usage_meter = Some(usage_meter);
+        self
+    }
+
+    /// Records every request and response in the cassette of `recorder`
+    pub fn with_recorder(mut self, recorder: Arc<Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Embeds all `texts` in one request
+    pub async fn get_text_embeddings(&self, texts: &[String]) -> Result<ApiResponse> {
         let url = format!("{}/embeddings", self.base_url);
 
         let body = serde_json::json!({
-            "input": text,
-            "model": "text-embedding-3-small"
+            "input": texts,
+            "model": EMBEDDING_MODEL
         });
 
-        self.client
-            .post(&url)
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", self.open_ai_api_key))
-            .body(body.to_string())
+        let res = self.send::<ApiResponse>(&url, body.to_string()).await?;
+
+        if let Some(usage_meter) = &self.usage_meter {
+            usage_meter.record(
+                "openai",
+                &res.model,
+                "embedding",
+                res.usage.prompt_tokens as u64,
+                0,
+            );
+        }
+        Ok(res)
+    }
+
+    /// `response_format` is passed on as is, e.g. to request a JSON schema
+    pub async fn chat_completion(
+        &self,
+        messages: &[Message],
+        profile: &ModelProfile,
+        response_format: Option<serde_json::Value>,
+        tools: &[Tool],
+    ) -> Result<ChatResponse> {
+        let url = format!("{}/chat/completions", self.base_url);
+
+        let mut body = serde_json::json!({
+            "messages": messages,
+            "stream": false,
+        });
+        profile.extend_openai_request(&mut body, &self.chat_model);
+        if let Some(response_format) = response_format {
+            body["response_format"] = response_format;
+        }
+        if !tools.is_empty() {
+            body["tools"] = tools.iter().map(Tool::to_openai).collect();
+        }
+
+        let res = self.send::<ChatResponse>(&url, body.to_string()).await?;
+
+        if let (Some(usage_meter), Some(usage)) = (&self.usage_meter, &res.usage) {
+            usage_meter.record(
+                "openai",
+                &res.model,
+                &profile.task,
+                usage.prompt_tokens as u64,
+                usage.completion_tokens as u64,
+            );
+        }
+        Ok(res)
+    }
+
+    async fn send<T: DeserializeOwned>(&self, url: &str, body: String) -> Result<T> {
+        let response = self
+            .post(url)
+            .body(body.clone())
             .send()
             .await
-            .map_err(|e| anyhow!("{e:?}"))?
-            .json::<ApiResponse>()
+            .map_err(|e| anyhow!("{e:?}"))?;
+        read_response(
+            self.recorder.as_deref(),
+            "openai",
+            &self.base_url,
+            &body,
+            response,
+        )
+        .await
+    }
+
+    fn post(&self, url: &str) -> RequestBuilder {
+        let request = self
+            .client
+            .post(url)
+            .header("Content-Type", "application/json");
+
+        // Local OpenAI compatible servers usually run without a key
+        if self.open_ai_api_key.expose_secret().is_empty() {
+            request
+        } else {
+            request.header(
+                "Authorization",
+                format!("Bearer {}", self.open_ai_api_key.expose_secret()),
+            )
+        }
+    }
+}
+
+#[async_trait]
+impl ChatModel for OpenAIClient {
+    async fn chat(&self, messages: &[Message], profile: &ModelProfile) -> Result<String> {
+        let mut res = self.chat_completion(messages, profile, None, &[]).await?;
+        if res.choices.is_empty() {
+            return Err(anyhow!("OpenAI API response contained no choices"));
+        }
+        Ok(res
+            .choices
+            .swap_remove(0)
+            .message
+            .content
+            .unwrap_or_default())
+    }
+
+    async fn chat_structured(
+        &self,
+        messages: &[Message],
+        schema_name: &str,
+        schema: &serde_json::Value,
+        profile: &ModelProfile,
+    ) -> Result<String> {
+        let response_format = serde_json::json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": schema_name,
+                "schema": schema,
+                "strict": true
+            }
+        });
+        let mut res = self
+            .chat_completion(messages, profile, Some(response_format), &[])
+            .await?;
+        if res.choices.is_empty() {
+            return Err(anyhow!("OpenAI API response contained no choices"));
+        }
+        Ok(res
+            .choices
+            .swap_remove(0)
+            .message
+            .content
+            .unwrap_or_default())
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
+    }
+
+    async fn chat_with_tools(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+        profile: &ModelProfile,
+    ) -> Result<Message> {
+        let mut res = self.chat_completion(messages, profile, None, tools).await?;
+        if res.choices.is_empty() {
+            return Err(anyhow!("OpenAI API response contained no choices"));
+        }
+        let message = res.choices.swap_remove(0).message;
+        Ok(Message {
+            tool_calls: message.tool_calls,
+            ..Message::assistant(message.content.unwrap_or_default())
+        })
+    }
+}
+
+#[async_trait]
+impl EmbeddingModel for OpenAIClient {
+    fn name(&self) -> &str {
+        EMBEDDING_MODEL
+    }
+
+    fn dimension(&self) -> u64 {
+        // See: https://platform.openai.com/docs/guides/embeddings
+        1536
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut res = self.get_text_embeddings(texts).await?;
+        if res.data.len() != texts.len() {
+            return Err(anyhow!(
+                "Expected {} embeddings from OpenAI API but got {}",
+                texts.len(),
+                res.data.len()
+            ));
+        }
+        res.data.sort_by_key(|data| data.index);
+        Ok(res.data.into_iter().map(|data| data.embedding).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::post, Json, Router};
+
+    use crate::{
+        llm::{ChatModel, Message, ModelProfile, Tool},
+        openai::OpenAIClient,
+    };
+
+    /// Serves a fixed chat completion like a local OpenAI compatible server would
+    async fn start_stub_server() -> String {
+        async fn chat_completions(Json(body): Json<serde_json::Value>) -> Json<serde_json::Value> {
+            // OpenAI rejects requests with top_k
+            assert!(body.get("top_k").is_none());
+            if let Some(tools) = body.get("tools") {
+                assert_eq!(tools[0]["function"]["name"], "like");
+                return Json(serde_json::json!({
+                    "id": "chatcmpl-2",
+                    "model": "stub-model",
+                    "choices": [{
+                        "index": 0,
+                        "message": {
+                            "role": "assistant",
+                            "content": null,
+                            "tool_calls": [{
+                                "id": "call_1",
+                                "type": "function",
+                                "function": { "name": "like", "arguments": "{\"tweet_id\":\"1\"}" }
+                            }]
+                        },
+                        "finish_reason": "tool_calls"
+                    }]
+                }));
+            }
+
+            assert_eq!(body["model"], "stub-model");
+            assert_eq!(body["messages"][0]["role"], "system");
+            if body["messages"][1]["content"] == "say hi" {
+                assert_eq!(body["temperature"], 0.0);
+                assert_eq!(body["seed"], 0);
+            } else {
+                assert_eq!(body["messages"][1]["content"], "say something nice");
+                assert_eq!(body["temperature"], 1.0);
+                assert!(body.get("seed").is_none());
+            }
+
+            Json(serde_json::json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "stub-model",
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": "hi" },
+                    "finish_reason": "stop"
+                }]
+            }))
+        }
+
+        let app = Router::new().route("/v1/chat/completions", post(chat_completions));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        format!("http://{addr}/v1")
+    }
+
+    #[tokio::test]
+    async fn test_generate_text_against_stub() {
+        let base_url = start_stub_server().await;
+        let client = OpenAIClient::new("".into(), base_url).with_chat_model("stub-model".into());
+
+        let res = client
+            .generate_text("be nice", "say hi", &ModelProfile::deterministic())
+            .await
+            .unwrap();
+
+        assert_eq!(res, "hi");
+    }
+
+    #[tokio::test]
+    async fn test_creative_profile_against_stub() {
+        let base_url = start_stub_server().await;
+        let client = OpenAIClient::new("".into(), base_url).with_chat_model("stub-model".into());
+
+        let res = client
+            .generate_text("be nice", "say something nice", &ModelProfile::creative())
+            .await
+            .unwrap();
+
+        assert_eq!(res, "hi");
+    }
+
+    #[tokio::test]
+    async fn test_chat_with_tools_against_stub() {
+        let base_url = start_stub_server().await;
+        let client = OpenAIClient::new("".into(), base_url).with_chat_model("stub-model".into());
+        let like = Tool {
+            name: "like".into(),
+            description: "Like a tweet".into(),
+            parameters: serde_json::json!({
+                "type": "object",
+                "properties": { "tweet_id": { "type": "string" } },
+                "required": ["tweet_id"]
+            }),
+        };
+
+        let res = client
+            .chat_with_tools(
+                &[Message::user("like tweet 1")],
+                &[like],
+                &ModelProfile::deterministic(),
+            )
             .await
-            .map_err(|e| anyhow!("{e:?}"))
+            .unwrap();
+
+        assert_eq!(res.content, "");
+        assert_eq!(res.tool_calls[0].id, "call_1");
+        assert_eq!(res.tool_calls[0].function.arguments, r#"{"tweet_id":"1"}"#);
     }
 }
@@ -0,0 +1,185 @@
+// Generates the passwords the agent rotates the accounts to. Every site has its own rules for
+// what a password may look like, so each one gets a policy that can be set in config.toml.
+
+use anyhow::{bail, Result};
+use rand::{rngs::OsRng, seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+
+const LOWERCASE: &str = "abcdefghijklmnopqrstuvwxyz";
+const UPPERCASE: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const NUMBERS: &str = "0123456789";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PasswordPolicy {
+    /// Total number of characters
+    pub length: usize,
+    pub min_uppercase: usize,
+    pub min_numbers: usize,
+    pub min_special: usize,
+    /// The special characters the site accepts
+    pub special_chars: String,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            length: 24,
+            min_uppercase: 1,
+            min_numbers: 1,
+            min_special: 1,
+            special_chars: "!@#$%^&*".into(),
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Generates a password from OS entropy that satisfies this policy
+    pub fn generate(&self) -> Result<String> {
+        if self.min_uppercase + self.min_numbers + self.min_special > self.length {
+            bail!("Password policy requires more characters than its length: {self:?}");
+        }
+        if self.min_special > 0 && self.special_chars.is_empty() {
+            bail!("Password policy requires special characters but allows none: {self:?}");
+        }
+
+        let special = self.special_chars.chars().collect::<Vec<char>>();
+        let uppercase = UPPERCASE.chars().collect::<Vec<char>>();
+        let numbers = NUMBERS.chars().collect::<Vec<char>>();
+        let all = LOWERCASE
+            .chars()
+            .chain(UPPERCASE.chars())
+            .chain(NUMBERS.chars())
+            .chain(self.special_chars.chars())
+            .collect::<Vec<char>>();
+
+        let mut rng = OsRng;
+        let mut password = Vec::with_capacity(self.length);
+
+        // Add minimum required characters
+        for _ in 0..self.min_uppercase {
+            password.push(uppercase[rng.gen_range(0..uppercase.len())]);
+        }
+        for _ in 0..self.min_numbers {
+            password.push(numbers[rng.gen_range(0..numbers.len())]);
+        }
+        for _ in 0..self.min_special {
+            password.push(special[rng.gen_range(0..special.len())]);
+        }
+
+        // Fill the rest from every allowed character
+        while password.len() < self.length {
+            password.push(all[rng.gen_range(0..all.len())]);
+        }
+
+        password.shuffle(&mut rng);
+        let password = password.into_iter().collect::<String>();
+
+        self.validate(&password)?;
+        Ok(password)
+    }
+
+    /// Checks that the password follows the site's rules before we submit it
+    pub fn validate(&self, password: &str) -> Result<()> {
+        let count = |set: &str| password.chars().filter(|c| set.contains(*c)).count();
+
+        if password.chars().count() != self.length {
+            bail!("Password must be {} characters long", self.length);
+        }
+        if count(UPPERCASE) < self.min_uppercase {
+            bail!(
+                "Password needs at least {} uppercase letters",
+                self.min_uppercase
+            );
+        }
+        if count(NUMBERS) < self.min_numbers {
+            bail!("Password needs at least {} numbers", self.min_numbers);
+        }
+        if count(&self.special_chars) < self.min_special {
+            bail!(
+                "Password needs at least {} special characters",
+                self.min_special
+            );
+        }
+        if let Some(c) = password.chars().find(|c| {
+            !LOWERCASE.contains(*c)
+                && !UPPERCASE.contains(*c)
+                && !NUMBERS.contains(*c)
+                && !self.special_chars.contains(*c)
+        }) {
+            bail!("Password contains the character {c:?} which is not allowed");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::PasswordPolicy;
+
+    fn policy() -> impl Strategy<Value = PasswordPolicy> {
+        (
+            0usize..8,
+            0usize..8,
+            0usize..8,
+            0usize..32,
+            "[!@#$%^&*_-]{1,8}",
+        )
+            .prop_map(
+                |(min_uppercase, min_numbers, min_special, extra, special_chars)| PasswordPolicy {
+                    length: min_uppercase + min_numbers + min_special + extra,
+                    min_uppercase,
+                    min_numbers,
+                    min_special,
+                    special_chars,
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn generated_passwords_follow_policy(policy in policy()) {
+            let password = policy.generate().unwrap();
+
+            prop_assert_eq!(password.chars().count(), policy.length);
+            prop_assert!(password.chars().filter(|c| c.is_ascii_uppercase()).count() >= policy.min_uppercase);
+            prop_assert!(password.chars().filter(|c| c.is_ascii_digit()).count() >= policy.min_numbers);
+            prop_assert!(
+                password.chars().filter(|c| policy.special_chars.contains(*c)).count()
+                    >= policy.min_special
+            );
+            prop_assert!(password
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || policy.special_chars.contains(c)));
+        }
+
+        #[test]
+        fn impossible_policies_are_rejected(policy in policy(), missing in 1usize..8) {
+            let policy = PasswordPolicy {
+                length: policy.min_uppercase + policy.min_numbers + policy.min_special,
+                min_uppercase: policy.min_uppercase + missing,
+                ..policy
+            };
+
+            prop_assert!(policy.generate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let policy = PasswordPolicy {
+            length: 8,
+            special_chars: "!".into(),
+            ..Default::default()
+        };
+
+        assert!(policy.validate("abcD3fg!").is_ok());
+        assert!(policy.validate("abcD3fg!h").is_err());
+        assert!(policy.validate("abcd3fg!").is_err());
+        assert!(policy.validate("abcDefg!").is_err());
+        assert!(policy.validate("abcD3fgh").is_err());
+        assert!(policy.validate("abcD3fg?").is_err());
+    }
+}
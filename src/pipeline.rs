@@ -6,10 +6,18 @@
 
 use ethsign::SecretKey;
 use rand::Rng;
-use std::time::Duration;
-use tokio::select;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::{
+    select,
+    sync::{mpsc, watch},
+};
 
+use crate::db::types::Action;
 use crate::encumber::FullAccountDetails;
+use crate::health::{AccountHealth, Incident};
+use crate::llm::fallback::ProviderHealth;
+use crate::twitter::error::TwitterError;
+use crate::usage::UsageReport;
 
 use crate::{agent::Agent, config::Config, prompts::Prompts};
 
@@ -17,6 +25,11 @@ pub struct Pipeline {
     /// The Ai Agent
     config: PipelineConfig,
     agent: Agent,
+    /// Health of the encumbered accounts, reported by the health monitor
+    health: watch::Receiver<AccountHealth>,
+    incidents: mpsc::UnboundedReceiver<Incident>,
+    /// No run starts before this unix timestamp because an X API rate limit is used up
+    rate_limited_until: u64,
 }
 
 impl Pipeline {
@@ -24,6 +37,10 @@ impl Pipeline {
         config: Config,
         prompts: Prompts,
         account_details: FullAccountDetails,
+        health: watch::Receiver<AccountHealth>,
+        incidents: mpsc::UnboundedReceiver<Incident>,
+        chat_health: watch::Sender<Vec<ProviderHealth>>,
+        usage_report: watch::Sender<UsageReport>,
     ) -> Self {
         let pipeline_config: PipelineConfig = (&config).into();
         let agent: Agent = Agent::new(
@@ -31,6 +48,8 @@ impl Pipeline {
             config,
             generate_eth_private_key(),
             prompts,
+            chat_health,
+            usage_report,
         )
         .await
         .expect("Failed to create Agent");
@@ -38,6 +57,44 @@ impl Pipeline {
         Self {
             agent,
             config: pipeline_config,
+            health,
+            incidents,
+            rate_limited_until: 0,
+        }
+    }
+
+    /// Time left until the X API rate limit that stopped the last run resets
+    fn rate_limit_wait(&self) -> Duration {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Duration::from_secs(self.rate_limited_until.saturating_sub(now))
+    }
+
+    /// Records any incidents reported by the health monitor and waits while the account is
+    /// unhealthy.
+    async fn wait_until_healthy(&mut self) {
+        loop {
+            while let Ok(incident) = self.incidents.try_recv() {
+                if let Err(e) = self
+                    .agent
+                    .record_action(Action::Incident, &incident.to_string())
+                {
+                    tracing::error!("Failed to record incident {incident}: {e:?}");
+                }
+            }
+
+            if let AccountHealth::Unhealthy(reason) = &*self.health.borrow_and_update() {
+                tracing::warn!("Account is unhealthy, pausing agent: {reason}");
+            } else {
+                return;
+            }
+
+            // If the health monitor is gone there is nobody left to tell us to stop
+            if self.health.changed().await.is_err() {
+                return;
+            }
         }
     }
 
@@ -60,7 +117,7 @@ impl Pipeline {
             // todo: I think tokio Sleep is cancel safe we probably dont need to pin here
             tokio::pin!(scroll_duration_fut);
             loop {
-                let run_sleep_time = self.config.get_run_sleep_time();
+                let run_sleep_time = self.config.get_run_sleep_time().max(self.rate_limit_wait());
                 tracing::info!("Next run in: {:?}", run_sleep_time);
 
                 let run_sleep_fut = tokio::time::sleep(run_sleep_time);
@@ -71,8 +128,19 @@ impl Pipeline {
                     }
 
                     _ = run_sleep_fut => {
+                        self.wait_until_healthy().await;
                         if let Err(e) = self.agent.run().await {
-                            tracing::info!("Error while running error: {e:?}");
+                            match e.downcast_ref::<TwitterError>() {
+                                // A run can't do anything without its reads, posts that ran
+                                // into a limit are skipped by the run itself
+                                Some(TwitterError::RateLimited { endpoint, reset_at })
+                                    if endpoint.starts_with("GET ") =>
+                                {
+                                    tracing::warn!("{e}, putting the next run off until then");
+                                    self.rate_limited_until = *reset_at;
+                                }
+                                _ => tracing::info!("Error while running error: {e:?}"),
+                            }
                         };
                     }
                 }
@@ -156,7 +224,7 @@ impl Default for PipelineConfig {
     }
 }
 
-fn generate_eth_private_key() -> SecretKey {
+pub fn generate_eth_private_key() -> SecretKey {
     let mut rng = rand::thread_rng();
     let mut random_bytes = [0u8; 32];
 
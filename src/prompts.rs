@@ -2,6 +2,8 @@ use aho_corasick::AhoCorasick;
 
 use serde::{Deserialize, Serialize};
 
+use crate::tokens::{PromptBudget, PromptTokens, Section};
+
 /// Loads prompts for certain situations from a config file so it can easily be swapped out and changed
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub struct Prompts {
@@ -20,6 +22,10 @@ pub struct Prompts {
     pub wallet_decision_template: String,
     /// Template that formats or chooses the tweet to post if multiple are in the prompt. Takes in the original prompt as an arg
     pub formatter_template: String,
+    /// Template that takes in the action budget, external context and recent posts to let the AI decide what to do with tools
+    pub tool_agent_template: String,
+    /// Template that takes in a list of timeline tweets and how many of them the AI may like, retweet and quote
+    pub engagement_template: String,
     /// List of example tweets we can add to our prompts to give more context
     pub example_tweets: Vec<String>,
 }
@@ -32,13 +38,33 @@ impl Prompts {
         toml::from_str(PROMPTS).expect("Unable to parse prompts.toml")
     }
 
-    pub fn get_mentions_prompt(&self, tweets: Vec<String>) -> String {
+    /// Fills the mentions template with the mentions that fit into the external context budget.
+    /// `max_output_tokens` is kept free for the response.
+    pub fn get_mentions_prompt(
+        &self,
+        budget: &PromptBudget,
+        max_output_tokens: usize,
+        tweets: Vec<String>,
+    ) -> (String, PromptTokens) {
+        let (sections, mut tokens) = budget.fit(
+            &self.mentions_template,
+            vec![Section {
+                name: "mentions",
+                items: tweets,
+                max_tokens: budget.config.external_context,
+            }],
+            max_output_tokens,
+        );
+        let [tweets]: [Vec<String>; 1] = sections.try_into().expect("one entry per section");
+
         let patterns = &["{tweets}"];
         let replace_with = &[tweets.join("\n")];
 
         let ac = AhoCorasick::new(patterns).unwrap();
 
-        ac.replace_all(&self.mentions_template, replace_with)
+        let prompt = ac.replace_all(&self.mentions_template, replace_with);
+        tokens.total = budget.count(&prompt);
+        (prompt, tokens)
     }
 
     pub fn get_follow_prompt(&self, usernames: Vec<String>) -> String {
@@ -50,13 +76,52 @@ impl Prompts {
         ac.replace_all(&self.follow_template, replace_with)
     }
 
+    /// Fills the tweet template, dropping the least important memories, tweets and examples that
+    /// don't fit into the token budget. `max_output_tokens` is kept free for the response.
     pub fn get_tweet_prompt(
         &self,
+        budget: &PromptBudget,
+        max_output_tokens: usize,
         short_term_memory: String,
         long_term_memories: Vec<String>,
         recent_posts: Vec<String>,
         external_context: Vec<String>,
-    ) -> String {
+    ) -> (String, PromptTokens) {
+        let config = &budget.config;
+        let (sections, mut tokens) = budget.fit(
+            &self.tweet_template,
+            vec![
+                Section {
+                    name: "short_term_memory",
+                    items: vec![short_term_memory],
+                    max_tokens: config.short_term_memory,
+                },
+                Section {
+                    name: "long_term_memories",
+                    items: long_term_memories,
+                    max_tokens: config.long_term_memories,
+                },
+                Section {
+                    name: "external_context",
+                    items: external_context,
+                    max_tokens: config.external_context,
+                },
+                Section {
+                    name: "recent_posts",
+                    items: recent_posts,
+                    max_tokens: config.recent_posts,
+                },
+                Section {
+                    name: "example_tweets",
+                    items: self.example_tweets.clone(),
+                    max_tokens: config.example_tweets,
+                },
+            ],
+            max_output_tokens,
+        );
+        let [short_term_memory, long_term_memories, external_context, recent_posts, example_tweets]: [Vec<String>; 5] =
+            sections.try_into().expect("one entry per section");
+
         let patterns = &[
             "{short_term_memories}",
             "{long_term_memories}",
@@ -65,24 +130,47 @@ impl Prompts {
             "{example_tweets}",
         ];
         let replace_with = &[
-            short_term_memory,
+            short_term_memory.join("\n"),
             long_term_memories.join("\n"),
             external_context.join("\n"),
             recent_posts.join("\n"),
-            self.example_tweets.join("\n"),
+            example_tweets.join("\n"),
         ];
 
         let ac = AhoCorasick::new(patterns).unwrap();
 
-        ac.replace_all(&self.tweet_template, replace_with)
+        let prompt = ac.replace_all(&self.tweet_template, replace_with);
+        tokens.total = budget.count(&prompt);
+        (prompt, tokens)
     }
 
-    pub fn get_short_term_memory_prompt(&self, external_context: Vec<String>) -> String {
+    /// Fills the short term memory template, dropping the least important tweets that don't fit
+    /// into the token budget. `max_output_tokens` is kept free for the response.
+    pub fn get_short_term_memory_prompt(
+        &self,
+        budget: &PromptBudget,
+        max_output_tokens: usize,
+        external_context: Vec<String>,
+    ) -> (String, PromptTokens) {
+        let (sections, mut tokens) = budget.fit(
+            &self.short_term_memory_template,
+            vec![Section {
+                name: "external_context",
+                items: external_context,
+                max_tokens: budget.config.external_context,
+            }],
+            max_output_tokens,
+        );
+        let [external_context]: [Vec<String>; 1] =
+            sections.try_into().expect("one entry per section");
+
         let ac = AhoCorasick::new(["{external_context}"]).unwrap();
-        ac.replace_all(
+        let prompt = ac.replace_all(
             &self.short_term_memory_template,
             &[external_context.join("\n")],
-        )
+        );
+        tokens.total = budget.count(&prompt);
+        (prompt, tokens)
     }
 
     pub fn get_significance_prompt(&self, memory: &str) -> String {
@@ -105,6 +193,98 @@ impl Prompts {
         ac.replace_all(&self.wallet_decision_template, replace_with)
     }
 
+    /// Fills the tool agent template, dropping the least important tweets and examples that don't
+    /// fit into the token budget. `max_output_tokens` is kept free for the response.
+    pub fn get_tool_agent_prompt(
+        &self,
+        budget: &PromptBudget,
+        max_output_tokens: usize,
+        action_budget: u32,
+        recent_posts: Vec<String>,
+        external_context: Vec<String>,
+    ) -> (String, PromptTokens) {
+        let config = &budget.config;
+        let (sections, mut tokens) = budget.fit(
+            &self.tool_agent_template,
+            vec![
+                Section {
+                    name: "external_context",
+                    items: external_context,
+                    max_tokens: config.external_context,
+                },
+                Section {
+                    name: "recent_posts",
+                    items: recent_posts,
+                    max_tokens: config.recent_posts,
+                },
+                Section {
+                    name: "example_tweets",
+                    items: self.example_tweets.clone(),
+                    max_tokens: config.example_tweets,
+                },
+            ],
+            max_output_tokens,
+        );
+        let [external_context, recent_posts, example_tweets]: [Vec<String>; 3] =
+            sections.try_into().expect("one entry per section");
+
+        let patterns = &[
+            "{action_budget}",
+            "{external_context}",
+            "{recent_posts}",
+            "{example_tweets}",
+        ];
+        let replace_with = &[
+            action_budget.to_string(),
+            external_context.join("\n"),
+            recent_posts.join("\n"),
+            example_tweets.join("\n"),
+        ];
+
+        let ac = AhoCorasick::new(patterns).unwrap();
+
+        let prompt = ac.replace_all(&self.tool_agent_template, replace_with);
+        tokens.total = budget.count(&prompt);
+        (prompt, tokens)
+    }
+
+    /// Fills the engagement template with the timeline tweets that fit into the external context
+    /// budget. `max_output_tokens` is kept free for the response.
+    pub fn get_engagement_prompt(
+        &self,
+        budget: &PromptBudget,
+        max_output_tokens: usize,
+        tweets: Vec<String>,
+        max_likes: usize,
+        max_retweets: usize,
+        max_quotes: usize,
+    ) -> (String, PromptTokens) {
+        let (sections, mut tokens) = budget.fit(
+            &self.engagement_template,
+            vec![Section {
+                name: "tweets",
+                items: tweets,
+                max_tokens: budget.config.external_context,
+            }],
+            max_output_tokens,
+        );
+        let [tweets]: [Vec<String>; 1] = sections.try_into().expect("one entry per section");
+
+        let patterns = &["{tweets}", "{max_likes}", "{max_retweets}", "{max_quotes}"];
+        let replace_with = &[
+            tweets.join("\n"),
+            max_likes.to_string(),
+            max_retweets.to_string(),
+            max_quotes.to_string(),
+        ];
+
+        let ac = AhoCorasick::new(patterns).unwrap();
+
+        let prompt = ac.replace_all(&self.engagement_template, replace_with);
+        tokens.total = budget.count(&prompt);
+        (prompt, tokens)
+    }
+
     pub fn get_formatter_prompt(&self, original_prompt: &str) -> String {
         let ac = AhoCorasick::new(["{prompt}"]).unwrap();
 
@@ -150,7 +330,11 @@ mod tests {
     fn test_get_tweet_prompt() {
         let prompts = get_prompts();
 
-        let tweet = prompts.get_tweet_prompt(
+        let budget = PromptBudget::new(Default::default()).unwrap();
+
+        let (tweet, _) = prompts.get_tweet_prompt(
+            &budget,
+            512,
             "This is my short term memory".to_string(),
             get_vec_of_strings("memory"),
             get_vec_of_strings("recent"),
@@ -160,15 +344,92 @@ mod tests {
         println!("{tweet}");
     }
 
+    #[test]
+    fn test_get_tool_agent_prompt() {
+        let prompts = get_prompts();
+
+        let budget = PromptBudget::new(Default::default()).unwrap();
+
+        let (prompt, tokens) = prompts.get_tool_agent_prompt(
+            &budget,
+            512,
+            5,
+            get_vec_of_strings("recent"),
+            get_vec_of_strings("context"),
+        );
+
+        assert!(prompt.contains("at most 5 tools"));
+        assert!(prompt.contains("context2"));
+        assert_eq!(tokens.total, budget.count(&prompt));
+        println!("{prompt}");
+    }
+
+    #[test]
+    fn test_get_tweet_prompt_drops_context_over_budget() {
+        let prompts = get_prompts();
+        let budget = PromptBudget::new(crate::tokens::PromptBudgetConfig {
+            external_context: 20,
+            ..Default::default()
+        })
+        .unwrap();
+        let context = (0..50)
+            .map(|i| format!("A tweet on my timeline from @user{i}: gm"))
+            .collect::<Vec<String>>();
+
+        let (prompt, tokens) = prompts.get_tweet_prompt(
+            &budget,
+            512,
+            "This is my short term memory".to_string(),
+            get_vec_of_strings("memory"),
+            get_vec_of_strings("recent"),
+            context,
+        );
+
+        assert!(prompt.contains("@user0:"));
+        assert!(!prompt.contains("@user49:"));
+        let external_context = &tokens.sections[2];
+        assert_eq!(external_context.name, "external_context");
+        assert!(external_context.tokens <= 20);
+        assert!(external_context.dropped > 0);
+        assert!(tokens.total <= budget.config.context_window - 512);
+    }
+
     #[test]
     fn test_get_short_memory_prompt() {
         let prompts = get_prompts();
 
-        let prompt = prompts.get_short_term_memory_prompt(get_vec_of_strings("context"));
+        let budget = PromptBudget::new(Default::default()).unwrap();
+
+        let (prompt, tokens) =
+            prompts.get_short_term_memory_prompt(&budget, 512, get_vec_of_strings("context"));
 
+        assert!(prompt.contains("context3"));
+        assert_eq!(tokens.total, budget.count(&prompt));
         println!("{prompt}");
     }
 
+    #[test]
+    fn test_get_mentions_prompt_drops_mentions_over_budget() {
+        let prompts = get_prompts();
+        let budget = PromptBudget::new(crate::tokens::PromptBudgetConfig {
+            external_context: 40,
+            ..Default::default()
+        })
+        .unwrap();
+        let mentions = (0..50)
+            .map(|i| format!("id: {i}, tweet: @agent what do you think about eth, @user{i}?"))
+            .collect::<Vec<String>>();
+
+        let (prompt, tokens) = prompts.get_mentions_prompt(&budget, 1632, mentions);
+
+        assert!(prompt.contains("@user0?"));
+        assert!(!prompt.contains("@user49?"));
+        assert_eq!(tokens.sections[0].name, "mentions");
+        assert!(tokens.sections[0].tokens <= 40);
+        assert!(tokens.sections[0].dropped > 0);
+        assert_eq!(tokens.total, budget.count(&prompt));
+    }
+
     #[test]
     fn test_get_significance_prompt() {
         let prompts = get_prompts();
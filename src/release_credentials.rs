@@ -45,7 +45,7 @@ pub async fn timelock(account_details: FullAccountDetails, unlock_time: u64, rpc
                         tracing::error!(
                             "######## Unlock Time is up printing Account Details ########"
                         );
-                        tracing::error!("{:?}", account_details);
+                        tracing::error!("{}", account_details.expose_secrets());
                         tracing::error!(
                             "############################################################"
                         );
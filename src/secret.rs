@@ -0,0 +1,74 @@
+// Wrapper for passwords, API keys and tokens held in memory.
+// Formatting a secret only ever prints "[REDACTED]" and the memory is zeroed when it is dropped,
+// so a stray log line or debug print can't leak the credentials of the encumbered accounts.
+
+use std::{
+    fmt::{Debug, Display},
+    panic::Location,
+};
+
+use serde::Deserialize;
+use zeroize::Zeroize;
+
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(secret: String) -> Self {
+        Self(secret)
+    }
+
+    /// Gives access to the plaintext secret.
+    /// Every call is a place the secret can leave this wrapper, so only call this where the
+    /// plaintext is actually needed (typing it into a form, signing a request, releasing it).
+    /// Each call is traced with its call site, run with `RUST_LOG=trace` to audit them.
+    #[track_caller]
+    pub fn expose_secret(&self) -> &str {
+        tracing::trace!(caller = %Location::caller(), "secret exposed");
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self::new(value)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Self::new(value.into())
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[REDACTED]")
+    }
+}
+
+impl Display for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[REDACTED]")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+#[test]
+fn test_secret_is_redacted() {
+    let secret = Secret::from("hunter2");
+
+    assert_eq!(format!("{secret}"), "[REDACTED]");
+    assert_eq!(format!("{secret:?}"), "[REDACTED]");
+    assert_eq!(format!("{:?}", Some(secret.clone())), "Some([REDACTED])");
+    assert_eq!(secret.expose_secret(), "hunter2");
+
+    let secret: Secret = serde_json::from_str("\"hunter2\"").unwrap();
+    assert_eq!(secret.expose_secret(), "hunter2");
+}
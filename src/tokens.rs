@@ -0,0 +1,231 @@
+// Keeps the prompts within the context window of the model. Every section of a template gets a
+// token allowance, items that don't fit are dropped starting with the least important ones.
+// Tokens are counted with OpenAI's cl100k tokenizer, for other models the counts are a close
+// estimate, which is why the allowances leave some room.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tiktoken_rs::CoreBPE;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PromptBudgetConfig {
+    /// Context window of the chat model, the prompt and the response have to fit into it
+    pub context_window: usize,
+    pub short_term_memory: usize,
+    pub long_term_memories: usize,
+    pub external_context: usize,
+    pub recent_posts: usize,
+    pub example_tweets: usize,
+}
+
+impl Default for PromptBudgetConfig {
+    fn default() -> Self {
+        Self {
+            context_window: 8192,
+            short_term_memory: 512,
+            long_term_memories: 1024,
+            external_context: 3072,
+            recent_posts: 512,
+            example_tweets: 1024,
+        }
+    }
+}
+
+/// A section of a template, the items are ordered from most to least important
+pub struct Section {
+    pub name: &'static str,
+    pub items: Vec<String>,
+    pub max_tokens: usize,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SectionTokens {
+    pub name: &'static str,
+    pub tokens: usize,
+    pub items: usize,
+    /// Items that were dropped to stay in budget
+    pub dropped: usize,
+}
+
+/// Token counts of an assembled prompt
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct PromptTokens {
+    pub total: usize,
+    pub sections: Vec<SectionTokens>,
+}
+
+pub struct PromptBudget {
+    bpe: CoreBPE,
+    pub config: PromptBudgetConfig,
+}
+
+impl PromptBudget {
+    pub fn new(config: PromptBudgetConfig) -> Result<Self> {
+        let bpe = tiktoken_rs::cl100k_base().map_err(|e| anyhow!("{e:?}"))?;
+        Ok(Self { bpe, config })
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        self.bpe.encode_with_special_tokens(text).len()
+    }
+
+    fn truncate(&self, text: &str, max_tokens: usize) -> String {
+        let mut tokens = self.bpe.encode_with_special_tokens(text);
+        tokens.truncate(max_tokens);
+        // Cutting a multi-byte character in half makes the decoding fail, drop the broken tail
+        while !tokens.is_empty() {
+            if let Ok(text) = self.bpe.decode(tokens.clone()) {
+                return text;
+            }
+            tokens.pop();
+        }
+        String::new()
+    }
+
+    /// Fits the sections into their allowances and the whole prompt into the context window,
+    /// leaving room for `max_output_tokens`. `template` is the text around the sections and
+    /// `sections` are ordered from most to least important. Returns the items that fit.
+    pub fn fit(
+        &self,
+        template: &str,
+        sections: Vec<Section>,
+        max_output_tokens: usize,
+    ) -> (Vec<Vec<String>>, PromptTokens) {
+        let mut fitted = Vec::with_capacity(sections.len());
+        for section in sections {
+            let total_items = section.items.len();
+            let mut items = Vec::new();
+            let mut tokens = Vec::new();
+            let mut used = 0;
+            for item in section.items {
+                // +1 for the newline the items are joined with
+                let item_tokens = self.count(&item) + 1;
+                if used + item_tokens <= section.max_tokens {
+                    used += item_tokens;
+                    items.push(item);
+                    tokens.push(item_tokens);
+                } else if items.is_empty() && section.max_tokens > 1 {
+                    // Better a cut off item than an empty section
+                    let item = self.truncate(&item, section.max_tokens - 1);
+                    tokens.push(self.count(&item) + 1);
+                    items.push(item);
+                    break;
+                } else {
+                    break;
+                }
+            }
+            fitted.push((section.name, total_items, items, tokens));
+        }
+
+        // The allowances can add up to more than the context window, drop the least important
+        // items until everything fits
+        let available = self
+            .config
+            .context_window
+            .saturating_sub(self.count(template) + max_output_tokens);
+        let mut total = fitted
+            .iter()
+            .map(|(_, _, _, tokens)| tokens.iter().sum::<usize>())
+            .sum::<usize>();
+        for (_, _, items, tokens) in fitted.iter_mut().rev() {
+            while total > available {
+                let Some(item_tokens) = tokens.pop() else {
+                    break;
+                };
+                items.pop();
+                total -= item_tokens;
+            }
+        }
+
+        let mut report = PromptTokens::default();
+        let mut sections = Vec::with_capacity(fitted.len());
+        for (name, total_items, items, tokens) in fitted {
+            report.sections.push(SectionTokens {
+                name,
+                tokens: tokens.iter().sum(),
+                items: items.len(),
+                dropped: total_items - items.len(),
+            });
+            sections.push(items);
+        }
+        (sections, report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PromptBudget, PromptBudgetConfig, Section};
+
+    fn tweets(n: usize) -> Vec<String> {
+        (0..n)
+            .map(|i| format!("tweet number {i} about eth"))
+            .collect()
+    }
+
+    #[test]
+    fn test_sections_stay_in_allowance() {
+        let budget = PromptBudget::new(PromptBudgetConfig::default()).unwrap();
+        let per_tweet = budget.count(&tweets(1)[0]) + 1;
+
+        let (sections, report) = budget.fit(
+            "template",
+            vec![Section {
+                name: "external_context",
+                items: tweets(50),
+                max_tokens: per_tweet * 10,
+            }],
+            512,
+        );
+
+        // The most important items are kept
+        assert_eq!(sections[0], tweets(10));
+        assert_eq!(report.sections[0].items, 10);
+        assert_eq!(report.sections[0].dropped, 40);
+        assert!(report.sections[0].tokens <= per_tweet * 10);
+    }
+
+    #[test]
+    fn test_least_important_sections_are_cut_first() {
+        let budget = PromptBudget::new(PromptBudgetConfig {
+            context_window: 1024,
+            ..Default::default()
+        })
+        .unwrap();
+        let section = |name| Section {
+            name,
+            items: tweets(40),
+            max_tokens: 1024,
+        };
+
+        let (sections, report) = budget.fit(
+            "template",
+            vec![section("recent_posts"), section("example_tweets")],
+            256,
+        );
+
+        assert_eq!(sections[0].len(), 40);
+        assert!(sections[1].len() < 40);
+        let total = report.sections.iter().map(|s| s.tokens).sum::<usize>();
+        assert!(total + budget.count("template") + 256 <= 1024);
+    }
+
+    #[test]
+    fn test_long_item_is_truncated() {
+        let budget = PromptBudget::new(PromptBudgetConfig::default()).unwrap();
+
+        let (sections, report) = budget.fit(
+            "template",
+            vec![Section {
+                name: "short_term_memory",
+                items: vec!["gm ".repeat(1000)],
+                max_tokens: 100,
+            }],
+            512,
+        );
+
+        assert_eq!(sections[0].len(), 1);
+        assert!(budget.count(&sections[0][0]) < 100);
+        assert_eq!(report.sections[0].dropped, 0);
+    }
+}
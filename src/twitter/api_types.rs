@@ -7,21 +7,27 @@ pub struct ApiResponse<T> {
     pub data: T,
 }
 
+/// `data` and `includes` are missing when there are no new mentions
 #[derive(Deserialize, Debug)]
 pub struct MentionsResponse {
+    #[serde(default)]
     pub data: Vec<Tweet>,
-    pub includes: IncludesUsers,
+    #[serde(default)]
+    pub includes: Includes,
     pub meta: Meta,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Meta {
-    pub newest_id: String,
-    pub oldest_id: String,
+    /// The ids are missing when the page is empty
+    pub newest_id: Option<String>,
+    pub oldest_id: Option<String>,
     pub result_count: u32,
+    /// Missing on the last page
+    pub next_token: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Tweet {
     pub id: String,
     pub author_id: String,
@@ -29,11 +35,132 @@ pub struct Tweet {
     pub edit_history_tweet_ids: Vec<String>,
     pub created_at: String,
     pub username: Option<String>,
+    #[serde(default)]
+    pub referenced_tweets: Vec<ReferencedTweet>,
+    /// Id of the tweet that started the reply chain
+    pub conversation_id: Option<String>,
+    #[serde(default)]
+    pub entities: Entities,
+    pub public_metrics: Option<PublicMetrics>,
+    pub lang: Option<String>,
+    #[serde(default)]
+    pub attachments: Attachments,
+    /// Filled in from the media expansion
+    #[serde(default)]
+    pub media: Vec<Media>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReferenceKind {
+    RepliedTo,
+    Quoted,
+    Retweeted,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReferencedTweet {
+    #[serde(rename = "type")]
+    pub kind: ReferenceKind,
+    pub id: String,
+}
+
+/// Parts of the text X recognized. `start` and `end` count characters, not bytes.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Entities {
+    #[serde(default)]
+    pub mentions: Vec<MentionEntity>,
+    #[serde(default)]
+    pub urls: Vec<UrlEntity>,
+    #[serde(default)]
+    pub hashtags: Vec<TagEntity>,
+    #[serde(default)]
+    pub cashtags: Vec<TagEntity>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MentionEntity {
+    pub start: usize,
+    pub end: usize,
+    pub username: String,
+    /// Missing when the user doesn't exist
+    pub id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UrlEntity {
+    pub start: usize,
+    pub end: usize,
+    /// The t.co link in the text
+    pub url: String,
+    pub expanded_url: Option<String>,
+    pub display_url: Option<String>,
+    /// Set for links to attached media
+    pub media_key: Option<String>,
+}
+
+/// A hashtag or cashtag, without the # or $
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TagEntity {
+    pub start: usize,
+    pub end: usize,
+    pub tag: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PublicMetrics {
+    pub retweet_count: u64,
+    pub reply_count: u64,
+    pub like_count: u64,
+    pub quote_count: u64,
+    /// Only counted for newer tweets
+    #[serde(default)]
+    pub bookmark_count: u64,
+    #[serde(default)]
+    pub impression_count: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Attachments {
+    #[serde(default)]
+    pub media_keys: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Media {
+    pub media_key: String,
+    /// "photo", "video" or "animated_gif"
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Only set for photos
+    pub url: Option<String>,
+    pub preview_image_url: Option<String>,
+    pub alt_text: Option<String>,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct SearchResponse {
+    /// Missing when nothing matched the query
+    #[serde(default)]
+    pub data: Vec<Tweet>,
+    #[serde(default)]
+    pub includes: Includes,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TweetResponse {
+    pub data: Tweet,
+    #[serde(default)]
+    pub includes: Includes,
+}
+
+/// `data` is missing when the user has no tweets
 #[derive(Deserialize, Debug)]
 pub struct TweetsResponse {
+    #[serde(default)]
     pub data: Vec<Tweet>,
+    #[serde(default)]
+    pub includes: Includes,
     pub meta: Meta,
 }
 
@@ -51,14 +178,18 @@ pub struct User {
     pub username: String,
 }
 
+/// `data` and `includes` are missing when there are no new tweets
 #[derive(Debug, Deserialize)]
 pub struct TimelineResponse {
+    #[serde(default)]
     pub data: Vec<TimelineTweet>,
-    pub includes: IncludesUsers,
+    #[serde(default)]
+    pub includes: Includes,
     pub meta: TimelineMeta,
 }
 
-#[derive(Debug, Deserialize)]
+/// The fields are those of `Tweet`, the new ones are optional so older buffered tweets still load
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TimelineTweet {
     pub edit_history_tweet_ids: Vec<String>,
     pub article: Option<Article>,
@@ -66,24 +197,71 @@ pub struct TimelineTweet {
     pub author_id: String,
     pub id: String,
     pub username: Option<String>,
+    pub created_at: Option<String>,
+    #[serde(default)]
+    pub referenced_tweets: Vec<ReferencedTweet>,
+    pub conversation_id: Option<String>,
+    #[serde(default)]
+    pub entities: Entities,
+    pub public_metrics: Option<PublicMetrics>,
+    pub lang: Option<String>,
+    #[serde(default)]
+    pub attachments: Attachments,
+    #[serde(default)]
+    pub media: Vec<Media>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Article {
     pub title: String,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct IncludesUsers {
+/// The expansions of a response
+#[derive(Debug, Deserialize, Default)]
+pub struct Includes {
+    #[serde(default)]
     pub users: Vec<User>,
+    #[serde(default)]
+    pub media: Vec<Media>,
+}
+
+impl Includes {
+    pub fn username(&self, author_id: &str) -> Option<String> {
+        self.users
+            .iter()
+            .find(|u| u.id == author_id)
+            .map(|u| u.username.clone())
+    }
+
+    /// The media of `attachments` in their order
+    pub fn media(&self, attachments: &Attachments) -> Vec<Media> {
+        attachments
+            .media_keys
+            .iter()
+            .filter_map(|key| self.media.iter().find(|m| &m.media_key == key))
+            .cloned()
+            .collect()
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct TimelineMeta {
-    pub next_token: String,
+    /// Missing on the last page
+    pub next_token: Option<String>,
     pub result_count: u32,
-    pub newest_id: String,
-    pub oldest_id: String,
+    /// The ids are missing when the page is empty
+    pub newest_id: Option<String>,
+    pub oldest_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LikeData {
+    pub liked: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RetweetData {
+    pub retweeted: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -94,21 +272,44 @@ pub struct FollowData {
 
 impl Display for Tweet {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if let Some(username) = &self.username {
-            write!(f, "A tweet on my timeline from @{username}: {}", self.text)
-        } else {
-            write!(f, "New tweet on my timeline: {}", self.text)
-        }
+        describe(self, &self.text, f)
     }
 }
 
 impl Display for TimelineTweet {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if let Some(username) = &self.username {
-            write!(f, "A tweet on my timeline from @{username}: {}", self.text)
-        } else {
-            write!(f, "New tweet on my timeline: {}", self.text)
-        }
+        describe(self, &self.text, f)
+    }
+}
+
+/// E.g. "A reply on my timeline from @alice (12 likes, 3 retweets): text"
+fn describe(
+    tweet: &impl FeedTweet,
+    text: &str,
+    f: &mut std::fmt::Formatter<'_>,
+) -> std::fmt::Result {
+    let kind = if tweet.is_retweet() {
+        "retweet"
+    } else if tweet.quoted().is_some() {
+        "quote tweet"
+    } else if tweet.replied_to().is_some() {
+        "reply"
+    } else {
+        "tweet"
+    };
+    let popularity = match tweet.public_metrics() {
+        Some(m) if m.like_count + m.retweet_count + m.reply_count > 0 => format!(
+            " ({} likes, {} retweets, {} replies)",
+            m.like_count, m.retweet_count, m.reply_count
+        ),
+        _ => String::new(),
+    };
+    match tweet.username() {
+        Some(username) => write!(
+            f,
+            "A {kind} on my timeline from @{username}{popularity}: {text}"
+        ),
+        None => write!(f, "New {kind} on my timeline{popularity}: {text}"),
     }
 }
 
@@ -117,3 +318,150 @@ impl Display for SentTweet {
         write!(f, "A tweet from us: {}", self.text)
     }
 }
+
+/// Tweets of the feeds the agent reads from
+pub trait FeedTweet {
+    fn id(&self) -> &str;
+    fn username(&self) -> Option<&str>;
+    fn referenced_tweets(&self) -> &[ReferencedTweet];
+    fn public_metrics(&self) -> Option<&PublicMetrics>;
+
+    /// Id of the tweet this tweet replies to
+    fn replied_to(&self) -> Option<&str> {
+        self.referenced(ReferenceKind::RepliedTo)
+    }
+
+    /// Id of the tweet this tweet quotes
+    fn quoted(&self) -> Option<&str> {
+        self.referenced(ReferenceKind::Quoted)
+    }
+
+    fn is_retweet(&self) -> bool {
+        self.referenced(ReferenceKind::Retweeted).is_some()
+    }
+
+    fn referenced(&self, kind: ReferenceKind) -> Option<&str> {
+        self.referenced_tweets()
+            .iter()
+            .find(|t| t.kind == kind)
+            .map(|t| t.id.as_str())
+    }
+}
+
+impl FeedTweet for Tweet {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    fn referenced_tweets(&self) -> &[ReferencedTweet] {
+        &self.referenced_tweets
+    }
+
+    fn public_metrics(&self) -> Option<&PublicMetrics> {
+        self.public_metrics.as_ref()
+    }
+}
+
+impl FeedTweet for TimelineTweet {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    fn referenced_tweets(&self) -> &[ReferencedTweet] {
+        &self.referenced_tweets
+    }
+
+    fn public_metrics(&self) -> Option<&PublicMetrics> {
+        self.public_metrics.as_ref()
+    }
+}
+
+#[test]
+fn test_empty_responses() {
+    // What X sends when there is nothing new
+    let empty = r#"{"meta": {"result_count": 0}}"#;
+
+    let mentions = serde_json::from_str::<MentionsResponse>(empty).unwrap();
+    assert!(mentions.data.is_empty());
+    assert!(mentions.meta.newest_id.is_none());
+    let timeline = serde_json::from_str::<TimelineResponse>(empty).unwrap();
+    assert!(timeline.data.is_empty());
+    assert!(timeline.meta.next_token.is_none());
+    let tweets = serde_json::from_str::<TweetsResponse>(empty).unwrap();
+    assert!(tweets.data.is_empty());
+}
+
+#[test]
+fn test_tweet_expansions() {
+    // A quote tweet with a photo as X sends it
+    let response = r#"{
+        "data": {
+            "id": "1851027565658374426",
+            "author_id": "1852012860596981761",
+            "text": "gm @alice, $ETH to the moon #gm https://t.co/abc",
+            "edit_history_tweet_ids": ["1851027565658374426"],
+            "created_at": "2024-10-28T22:31:14.000Z",
+            "conversation_id": "1851027565658374426",
+            "lang": "en",
+            "referenced_tweets": [{ "type": "quoted", "id": "1850000000000000000" }],
+            "attachments": { "media_keys": ["3_1851027560000000000"] },
+            "entities": {
+                "mentions": [{ "start": 3, "end": 9, "username": "alice", "id": "2244994945" }],
+                "cashtags": [{ "start": 11, "end": 15, "tag": "ETH" }],
+                "hashtags": [{ "start": 28, "end": 31, "tag": "gm" }],
+                "urls": [{
+                    "start": 32,
+                    "end": 48,
+                    "url": "https://t.co/abc",
+                    "expanded_url": "https://x.com/agent/status/1851027565658374426/photo/1",
+                    "display_url": "pic.x.com/abc",
+                    "media_key": "3_1851027560000000000"
+                }]
+            },
+            "public_metrics": {
+                "retweet_count": 3,
+                "reply_count": 1,
+                "like_count": 12,
+                "quote_count": 0,
+                "bookmark_count": 0,
+                "impression_count": 420
+            }
+        },
+        "includes": {
+            "users": [{ "id": "1852012860596981761", "name": "Agent", "username": "agent" }],
+            "media": [{
+                "media_key": "3_1851027560000000000",
+                "type": "photo",
+                "url": "https://pbs.twimg.com/media/abc.jpg"
+            }]
+        }
+    }"#;
+
+    let TweetResponse { mut data, includes } = serde_json::from_str(response).unwrap();
+    data.username = includes.username(&data.author_id);
+    data.media = includes.media(&data.attachments);
+    assert_eq!(data.quoted(), Some("1850000000000000000"));
+    assert_eq!(data.replied_to(), None);
+    assert!(!data.is_retweet());
+    assert_eq!(data.entities.mentions[0].username, "alice");
+    assert_eq!(data.entities.cashtags[0].tag, "ETH");
+    assert_eq!(data.media[0].kind, "photo");
+    assert_eq!(data.public_metrics.as_ref().unwrap().like_count, 12);
+    assert_eq!(
+        data.to_string(),
+        "A quote tweet on my timeline from @agent (12 likes, 3 retweets, 1 replies): gm @alice, $ETH to the moon #gm https://t.co/abc"
+    );
+
+    // Buffered timeline tweets from before the new fields
+    let old = r#"{"edit_history_tweet_ids": ["1"], "article": null, "text": "gm", "author_id": "2", "id": "1", "username": "alice"}"#;
+    let tweet = serde_json::from_str::<TimelineTweet>(old).unwrap();
+    assert_eq!(tweet.to_string(), "A tweet on my timeline from @alice: gm");
+}
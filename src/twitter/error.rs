@@ -0,0 +1,190 @@
+// The ways a request to the X API fails. X answers with a problem (title, detail, type) for
+// failed requests and with an `errors` array, often with status 200, when the thing looked up
+// doesn't exist. Both end up as a `TwitterError` so callers can tell them apart.
+
+use std::{fmt::Display, time::Duration};
+
+use serde_json::Value;
+
+use super::rate_limit::now_secs;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TwitterError {
+    /// The tokens are wrong or were revoked
+    Unauthorized(String),
+    /// The limit of `endpoint` is used up until `reset_at` (unix timestamp in seconds)
+    RateLimited {
+        endpoint: String,
+        reset_at: u64,
+    },
+    /// X doesn't post the same text twice
+    DuplicateContent,
+    /// The tweet or user doesn't exist, was deleted or is protected
+    NotFound(String),
+    /// Our account or the one we tried to reach is suspended
+    Suspended(String),
+    Forbidden(String),
+    Other {
+        status: u16,
+        detail: String,
+    },
+}
+
+impl TwitterError {
+    /// The error in the body of a response, if any. Successful responses only fail when the
+    /// errors come without data, errors next to data are about parts of it, e.g. a deleted
+    /// tweet in a lookup of many.
+    pub fn from_response(status: u16, body: &Value) -> Option<Self> {
+        let errors = body.get("errors").and_then(Value::as_array);
+        if (200..300).contains(&status) {
+            if body.get("data").is_some() {
+                return None;
+            }
+            let error = errors?.first()?;
+            return Some(Self::classify(status, error));
+        }
+        // Problems have the details at the top, some older errors only in the array
+        match errors.and_then(|errors| errors.first()) {
+            Some(error) if body.get("detail").is_none() => Some(Self::classify(status, error)),
+            _ => Some(Self::classify(status, body)),
+        }
+    }
+
+    fn classify(status: u16, problem: &Value) -> Self {
+        let field = |name: &str| problem.get(name).and_then(Value::as_str).unwrap_or("");
+        let detail = [field("detail"), field("message"), field("title")]
+            .into_iter()
+            .find(|s| !s.is_empty())
+            .unwrap_or("unknown error")
+            .to_string();
+        let lowercase = detail.to_lowercase();
+
+        if status == 401 {
+            Self::Unauthorized(detail)
+        } else if lowercase.contains("duplicate content") {
+            Self::DuplicateContent
+        } else if lowercase.contains("suspended") {
+            Self::Suspended(detail)
+        } else if status == 404 || field("type").ends_with("resource-not-found") {
+            Self::NotFound(detail)
+        } else if status == 403 {
+            Self::Forbidden(detail)
+        } else {
+            Self::Other { status, detail }
+        }
+    }
+
+    /// Time left until a rate limit resets
+    pub fn rate_limit_wait(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited { reset_at, .. } => {
+                Some(Duration::from_secs(reset_at.saturating_sub(now_secs())))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Display for TwitterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unauthorized(detail) => write!(f, "X API unauthorized: {detail}"),
+            Self::RateLimited { endpoint, .. } => write!(
+                f,
+                "X API rate limit of {endpoint} reached, resets in {}s",
+                self.rate_limit_wait().unwrap_or_default().as_secs()
+            ),
+            Self::DuplicateContent => write!(f, "X doesn't allow posting the same text twice"),
+            Self::NotFound(detail) => write!(f, "X API not found: {detail}"),
+            Self::Suspended(detail) => write!(f, "X account suspended: {detail}"),
+            Self::Forbidden(detail) => write!(f, "X API forbidden: {detail}"),
+            Self::Other { status, detail } => write!(f, "X API error {status}: {detail}"),
+        }
+    }
+}
+
+impl std::error::Error for TwitterError {}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::TwitterError;
+
+    #[test]
+    fn test_from_response() {
+        let unauthorized = json!({
+            "title": "Unauthorized",
+            "type": "about:blank",
+            "status": 401,
+            "detail": "Unauthorized"
+        });
+        assert_eq!(
+            TwitterError::from_response(401, &unauthorized),
+            Some(TwitterError::Unauthorized("Unauthorized".into()))
+        );
+
+        let duplicate = json!({
+            "detail": "You are not allowed to create a Tweet with duplicate content.",
+            "type": "about:blank",
+            "title": "Forbidden",
+            "status": 403
+        });
+        assert_eq!(
+            TwitterError::from_response(403, &duplicate),
+            Some(TwitterError::DuplicateContent)
+        );
+
+        let not_found = json!({
+            "errors": [{
+                "value": "1",
+                "detail": "Could not find tweet with id: [1].",
+                "title": "Not Found Error",
+                "resource_type": "tweet",
+                "parameter": "id",
+                "resource_id": "1",
+                "type": "https://api.twitter.com/2/problems/resource-not-found"
+            }]
+        });
+        assert_eq!(
+            TwitterError::from_response(200, &not_found),
+            Some(TwitterError::NotFound(
+                "Could not find tweet with id: [1].".into()
+            ))
+        );
+
+        let suspended = json!({
+            "errors": [{
+                "detail": "User has been suspended: [agent].",
+                "title": "Forbidden",
+                "type": "https://api.twitter.com/2/problems/resource-unavailable"
+            }]
+        });
+        assert!(matches!(
+            TwitterError::from_response(200, &suspended),
+            Some(TwitterError::Suspended(_))
+        ));
+
+        let forbidden = json!({
+            "title": "Unsupported Authentication",
+            "detail": "Authenticating with OAuth 2.0 Application-Only is forbidden for this endpoint.",
+            "type": "https://api.twitter.com/2/problems/unsupported-authentication",
+            "status": 403
+        });
+        assert!(matches!(
+            TwitterError::from_response(403, &forbidden),
+            Some(TwitterError::Forbidden(_))
+        ));
+
+        // Errors about parts of the data don't fail the request
+        let partial = json!({
+            "data": [{ "id": "2", "text": "gm" }],
+            "errors": [{ "detail": "Could not find tweet with ids: [1].", "title": "Not Found Error" }]
+        });
+        assert_eq!(TwitterError::from_response(200, &partial), None);
+        assert_eq!(
+            TwitterError::from_response(200, &json!({ "meta": { "result_count": 0 } })),
+            None
+        );
+    }
+}
@@ -0,0 +1,1085 @@
+// A fake of the X API v2 endpoints the agent uses, only compiled into the tests. It keeps users,
+// follows, tweets, likes and retweets in memory and answers in the JSON shapes of `api_types`.
+// Requests have to carry an OAuth1 Authorization header and act as the user its access token
+// belongs to. The signature itself is not checked. Endpoints can be given a rate limit.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use serde_json::{json, Value};
+
+use super::{rate_limit::endpoint, TwitterClient};
+
+// Ids are handed out in order like snowflakes, so newer tweets have bigger ids
+const FIRST_ID: u64 = 1_000_000;
+const RATE_LIMIT_WINDOW_SECS: u64 = 900;
+
+type Response = (StatusCode, Json<Value>);
+
+#[derive(Debug, Clone)]
+pub struct FakeUser {
+    pub id: String,
+    pub username: String,
+    pub name: String,
+    access_token: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct FakeTweet {
+    pub id: String,
+    pub author_id: String,
+    pub text: String,
+    pub in_reply_to: Option<String>,
+    pub quoted: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FakeLimit {
+    limit: u32,
+    remaining: u32,
+    reset_at: u64,
+    /// Requests that reached the server, refused ones included
+    calls: u32,
+}
+
+#[derive(Default)]
+struct FakeState {
+    users: Vec<FakeUser>,
+    /// Oldest first
+    tweets: Vec<FakeTweet>,
+    /// (user, followed user)
+    follows: HashSet<(String, String)>,
+    /// (user, tweet)
+    likes: HashSet<(String, String)>,
+    /// (user, tweet)
+    retweets: HashSet<(String, String)>,
+    /// By endpoint, e.g. "GET /2/users/:id/mentions"
+    limits: HashMap<String, FakeLimit>,
+    next_id: u64,
+}
+
+impl FakeState {
+    fn next_id(&mut self) -> String {
+        let id = FIRST_ID + self.next_id;
+        self.next_id += 1;
+        id.to_string()
+    }
+
+    fn user(&self, user_id: &str) -> Option<&FakeUser> {
+        self.users.iter().find(|u| u.id == user_id)
+    }
+
+    fn tweet(&self, tweet_id: &str) -> Option<&FakeTweet> {
+        self.tweets.iter().find(|t| t.id == tweet_id)
+    }
+
+    fn add_tweet(
+        &mut self,
+        author_id: &str,
+        text: &str,
+        in_reply_to: Option<&str>,
+        quoted: Option<&str>,
+    ) -> String {
+        let id = self.next_id();
+        // One tweet a minute starting at 2024-01-01
+        let minutes = self.next_id;
+        let created_at = format!(
+            "2024-01-{:02}T{:02}:{:02}:00.000Z",
+            1 + minutes / 1440 % 28,
+            minutes / 60 % 24,
+            minutes % 60
+        );
+        self.tweets.push(FakeTweet {
+            id: id.clone(),
+            author_id: author_id.into(),
+            text: text.into(),
+            in_reply_to: in_reply_to.map(str::to_string),
+            quoted: quoted.map(str::to_string),
+            created_at,
+        });
+        id
+    }
+
+    /// Tweets that mention the user or reply to one of their tweets, newest first
+    fn mentions(&self, user_id: &str) -> Vec<&FakeTweet> {
+        let Some(user) = self.user(user_id) else {
+            return Vec::new();
+        };
+        let handle = format!("@{}", user.username.to_lowercase());
+        self.tweets
+            .iter()
+            .rev()
+            .filter(|t| t.author_id != user_id)
+            .filter(|t| {
+                t.text.to_lowercase().contains(&handle)
+                    || t.in_reply_to
+                        .as_deref()
+                        .and_then(|id| self.tweet(id))
+                        .is_some_and(|parent| parent.author_id == user_id)
+            })
+            .collect()
+    }
+
+    /// Tweets of the user and the users they follow, newest first
+    fn timeline(&self, user_id: &str) -> Vec<&FakeTweet> {
+        self.tweets
+            .iter()
+            .rev()
+            .filter(|t| {
+                t.author_id == user_id
+                    || self
+                        .follows
+                        .contains(&(user_id.to_string(), t.author_id.clone()))
+            })
+            .collect()
+    }
+
+    fn user_json(&self, user: &FakeUser) -> Value {
+        json!({ "id": user.id, "name": user.name, "username": user.username })
+    }
+
+    /// The authors of `tweets`, like the `author_id` expansion
+    fn includes(&self, tweets: &[&FakeTweet]) -> Value {
+        let mut seen = HashSet::new();
+        let users = tweets
+            .iter()
+            .filter(|t| seen.insert(t.author_id.clone()))
+            .filter_map(|t| self.user(&t.author_id))
+            .map(|u| self.user_json(u))
+            .collect::<Vec<Value>>();
+        json!({ "users": users })
+    }
+
+    /// The tweet with the fields the client asks for
+    fn tweet_json(&self, tweet: &FakeTweet) -> Value {
+        let mut conversation_id = &tweet.id;
+        while let Some(parent) = self
+            .tweet(conversation_id)
+            .and_then(|t| t.in_reply_to.as_ref())
+        {
+            conversation_id = parent;
+        }
+        let likes = self.likes.iter().filter(|(_, id)| id == &tweet.id).count();
+        let retweets = self
+            .retweets
+            .iter()
+            .filter(|(_, id)| id == &tweet.id)
+            .count();
+        let replies = self
+            .tweets
+            .iter()
+            .filter(|t| t.in_reply_to.as_ref() == Some(&tweet.id))
+            .count();
+        let quotes = self
+            .tweets
+            .iter()
+            .filter(|t| t.quoted.as_ref() == Some(&tweet.id))
+            .count();
+
+        let mut json = json!({
+            "id": tweet.id,
+            "author_id": tweet.author_id,
+            "text": tweet.text,
+            "edit_history_tweet_ids": [tweet.id],
+            "created_at": tweet.created_at,
+            "conversation_id": conversation_id,
+            "lang": "en",
+            "entities": self.entities(&tweet.text),
+            "public_metrics": {
+                "retweet_count": retweets,
+                "reply_count": replies,
+                "like_count": likes,
+                "quote_count": quotes,
+            },
+        });
+        let mut referenced_tweets = Vec::new();
+        if let Some(parent) = &tweet.in_reply_to {
+            referenced_tweets.push(json!({ "type": "replied_to", "id": parent }));
+        }
+        if let Some(quoted) = &tweet.quoted {
+            referenced_tweets.push(json!({ "type": "quoted", "id": quoted }));
+        }
+        if !referenced_tweets.is_empty() {
+            json["referenced_tweets"] = json!(referenced_tweets);
+        }
+        json
+    }
+
+    /// The mentions, hashtags, cashtags and links in `text`
+    fn entities(&self, text: &str) -> Value {
+        let mut entities = json!({ "mentions": [], "hashtags": [], "cashtags": [], "urls": [] });
+        let mut start = 0;
+        for word in text.split(' ') {
+            let token = word.trim_end_matches(|c: char| !c.is_alphanumeric() && c != '_');
+            let (token_start, token_end) = (start, start + token.chars().count());
+            start += word.chars().count() + 1;
+
+            let mut chars = token.chars();
+            let (kind, mut entity) = match (chars.next(), chars.as_str()) {
+                (_, "") => continue,
+                _ if token.starts_with("https://") => {
+                    ("urls", json!({ "url": token, "expanded_url": token }))
+                }
+                (Some('@'), username) => {
+                    let id = self
+                        .users
+                        .iter()
+                        .find(|u| u.username.eq_ignore_ascii_case(username))
+                        .map(|u| u.id.clone());
+                    ("mentions", json!({ "username": username, "id": id }))
+                }
+                (Some('#'), tag) => ("hashtags", json!({ "tag": tag })),
+                (Some('$'), tag) => ("cashtags", json!({ "tag": tag })),
+                _ => continue,
+            };
+            entity["start"] = json!(token_start);
+            entity["end"] = json!(token_end);
+            entities[kind].as_array_mut().unwrap().push(entity);
+        }
+        entities
+    }
+}
+
+/// A page of the `tweets` newer than `since_id` in the shape of the list endpoints. Like X,
+/// `data` and the ids in `meta` are left out when there are no tweets.
+fn page(
+    state: &FakeState,
+    mut tweets: Vec<&FakeTweet>,
+    query: &HashMap<String, String>,
+    max_results: (u16, u16, u16),
+) -> Result<Value, Response> {
+    let (min, max, default) = max_results;
+    if let Some(since_id) = query.get("since_id") {
+        let Ok(since_id) = since_id.parse::<u64>() else {
+            return Err(invalid_request(&format!(
+                "The `since_id` query parameter value [{since_id}] is not valid"
+            )));
+        };
+        tweets.retain(|t| t.id.parse::<u64>().unwrap_or_default() > since_id);
+    }
+    let max_results = match query.get("max_results") {
+        Some(value) => match value.parse::<u16>() {
+            Ok(n) if (min..=max).contains(&n) => n,
+            _ => {
+                return Err(invalid_request(&format!(
+                "The `max_results` query parameter value [{value}] is not between {min} and {max}"
+            )))
+            }
+        },
+        None => default,
+    } as usize;
+    // The token is the id of the first tweet of the page
+    let start = match query.get("pagination_token") {
+        Some(token) => match tweets.iter().position(|t| &t.id == token) {
+            Some(start) => start,
+            None => {
+                return Err(invalid_request(&format!(
+                    "The `pagination_token` query parameter value [{token}] is not valid"
+                )))
+            }
+        },
+        None => 0,
+    };
+
+    let next_token = tweets.get(start + max_results).map(|t| t.id.clone());
+    let tweets = tweets
+        .into_iter()
+        .skip(start)
+        .take(max_results)
+        .collect::<Vec<&FakeTweet>>();
+
+    let mut meta = json!({ "result_count": tweets.len() });
+    if let (Some(newest), Some(oldest)) = (tweets.first(), tweets.last()) {
+        meta["newest_id"] = json!(newest.id);
+        meta["oldest_id"] = json!(oldest.id);
+    }
+    if let Some(next_token) = next_token {
+        meta["next_token"] = json!(next_token);
+    }
+    let mut json = json!({ "meta": meta });
+    if !tweets.is_empty() {
+        json["data"] = tweets.iter().map(|t| state.tweet_json(t)).collect();
+        json["includes"] = state.includes(&tweets);
+    }
+    Ok(json)
+}
+
+fn invalid_request(message: &str) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({
+            "errors": [{ "message": message }],
+            "title": "Invalid Request",
+            "detail": "One or more parameters to your request was invalid.",
+            "type": "https://api.twitter.com/2/problems/invalid-request"
+        })),
+    )
+}
+
+fn forbidden(detail: &str) -> Response {
+    (
+        StatusCode::FORBIDDEN,
+        Json(json!({
+            "title": "Forbidden",
+            "status": 403,
+            "detail": detail,
+            "type": "about:blank"
+        })),
+    )
+}
+
+/// X answers lookups of missing resources with 200 and an error list
+fn not_found(resource_type: &str, parameter: &str, value: &str) -> Response {
+    (
+        StatusCode::OK,
+        Json(json!({
+            "errors": [{
+                "value": value,
+                "detail": format!("Could not find {resource_type} with {parameter}: [{value}]."),
+                "title": "Not Found Error",
+                "resource_type": resource_type,
+                "parameter": parameter,
+                "resource_id": value,
+                "type": "https://api.twitter.com/2/problems/resource-not-found"
+            }]
+        })),
+    )
+}
+
+/// The `oauth_token` of an OAuth1 Authorization header that has all the other protocol
+/// parameters too
+fn oauth_token(headers: &HeaderMap) -> Option<String> {
+    let header = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let params = header
+        .strip_prefix("OAuth ")?
+        .split(',')
+        .filter_map(|param| {
+            let (key, value) = param.trim().split_once('=')?;
+            Some((key, value.trim_matches('"')))
+        })
+        .collect::<HashMap<&str, &str>>();
+    for required in [
+        "oauth_consumer_key",
+        "oauth_nonce",
+        "oauth_signature",
+        "oauth_signature_method",
+        "oauth_timestamp",
+    ] {
+        params.get(required)?;
+    }
+    params.get("oauth_token").map(|token| token.to_string())
+}
+
+/// Id of the user the request acts as
+fn authenticate(state: &FakeState, headers: &HeaderMap) -> Result<String, Response> {
+    oauth_token(headers)
+        .and_then(|token| state.users.iter().find(|u| u.access_token == token))
+        .map(|user| user.id.clone())
+        .ok_or((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "title": "Unauthorized",
+                "type": "about:blank",
+                "status": 401,
+                "detail": "Unauthorized"
+            })),
+        ))
+}
+
+type SharedState = State<Arc<Mutex<FakeState>>>;
+
+async fn get_mentions(
+    State(state): SharedState,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, Response> {
+    let state = state.lock().unwrap();
+    authenticate(&state, &headers)?;
+    if state.user(&user_id).is_none() {
+        return Err(not_found("user", "id", &user_id));
+    }
+    page(&state, state.mentions(&user_id), &query, (5, 100, 10)).map(Json)
+}
+
+async fn get_timeline(
+    State(state): SharedState,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, Response> {
+    let state = state.lock().unwrap();
+    if authenticate(&state, &headers)? != user_id {
+        return Err(forbidden("You can only read your own timeline"));
+    }
+    page(&state, state.timeline(&user_id), &query, (1, 100, 100)).map(Json)
+}
+
+async fn get_user_tweets(
+    State(state): SharedState,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, Response> {
+    let state = state.lock().unwrap();
+    authenticate(&state, &headers)?;
+    if state.user(&user_id).is_none() {
+        return Err(not_found("user", "id", &user_id));
+    }
+    let tweets = state
+        .tweets
+        .iter()
+        .rev()
+        .filter(|t| t.author_id == user_id)
+        .collect();
+    page(&state, tweets, &query, (5, 100, 10)).map(Json)
+}
+
+async fn get_tweet(
+    State(state): SharedState,
+    headers: HeaderMap,
+    Path(tweet_id): Path<String>,
+) -> Result<Json<Value>, Response> {
+    let state = state.lock().unwrap();
+    authenticate(&state, &headers)?;
+    let tweet = state
+        .tweet(&tweet_id)
+        .ok_or_else(|| not_found("tweet", "id", &tweet_id))?;
+    Ok(Json(json!({
+        "data": state.tweet_json(tweet),
+        "includes": state.includes(&[tweet]),
+    })))
+}
+
+async fn search_recent(
+    State(state): SharedState,
+    headers: HeaderMap,
+    Query(query): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, Response> {
+    let state = state.lock().unwrap();
+    authenticate(&state, &headers)?;
+    let search = query
+        .get("query")
+        .cloned()
+        .unwrap_or_default()
+        .to_lowercase();
+    // Only plain terms and `from:` are supported, all of them have to match
+    let tweets = state
+        .tweets
+        .iter()
+        .rev()
+        .filter(|t| {
+            let text = t.text.to_lowercase();
+            search
+                .split_whitespace()
+                .all(|term| match term.strip_prefix("from:") {
+                    Some(username) => state
+                        .user(&t.author_id)
+                        .is_some_and(|u| u.username.to_lowercase() == username),
+                    None => text.contains(term),
+                })
+        })
+        .collect();
+    page(&state, tweets, &query, (10, 100, 10)).map(Json)
+}
+
+async fn create_tweet(
+    State(state): SharedState,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Result<Response, Response> {
+    let mut state = state.lock().unwrap();
+    let user_id = authenticate(&state, &headers)?;
+    let text = body["text"].as_str().unwrap_or_default();
+    if text.is_empty() || text.chars().count() > 280 {
+        return Err(invalid_request("The text has to be 1 to 280 characters"));
+    }
+    if state
+        .tweets
+        .iter()
+        .any(|t| t.author_id == user_id && t.text == text)
+    {
+        return Err(forbidden(
+            "You are not allowed to create a Tweet with duplicate content.",
+        ));
+    }
+    let in_reply_to = body["reply"]["in_reply_to_tweet_id"].as_str();
+    if let Some(parent) = in_reply_to {
+        if state.tweet(parent).is_none() {
+            return Err(invalid_request(&format!(
+                "The tweet {parent} you are replying to does not exist"
+            )));
+        }
+    }
+    let quoted = body["quote_tweet_id"].as_str();
+    if let Some(quoted) = quoted {
+        if state.tweet(quoted).is_none() {
+            return Err(invalid_request(&format!(
+                "The tweet {quoted} you are quoting does not exist"
+            )));
+        }
+    }
+
+    let id = state.add_tweet(&user_id, text, in_reply_to, quoted);
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "data": { "id": id, "text": text, "edit_history_tweet_ids": [id] }
+        })),
+    ))
+}
+
+async fn get_user_by_username(
+    State(state): SharedState,
+    headers: HeaderMap,
+    Path(username): Path<String>,
+) -> Result<Json<Value>, Response> {
+    let state = state.lock().unwrap();
+    authenticate(&state, &headers)?;
+    let user = state
+        .users
+        .iter()
+        .find(|u| u.username.eq_ignore_ascii_case(&username))
+        .ok_or_else(|| not_found("user", "username", &username))?;
+    Ok(Json(json!({ "data": state.user_json(user) })))
+}
+
+async fn get_user(
+    State(state): SharedState,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+) -> Result<Json<Value>, Response> {
+    let state = state.lock().unwrap();
+    authenticate(&state, &headers)?;
+    let user = state
+        .user(&user_id)
+        .ok_or_else(|| not_found("user", "id", &user_id))?;
+    Ok(Json(json!({ "data": state.user_json(user) })))
+}
+
+async fn like_tweet(
+    State(state): SharedState,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    Json(body): Json<Value>,
+) -> Result<Json<Value>, Response> {
+    let mut state = state.lock().unwrap();
+    if authenticate(&state, &headers)? != user_id {
+        return Err(forbidden("You can only like tweets as yourself"));
+    }
+    let tweet_id = body["tweet_id"].as_str().unwrap_or_default();
+    if state.tweet(tweet_id).is_none() {
+        return Err(invalid_request(&format!(
+            "The tweet {tweet_id} does not exist"
+        )));
+    }
+    state.likes.insert((user_id, tweet_id.into()));
+    Ok(Json(json!({ "data": { "liked": true } })))
+}
+
+async fn unlike_tweet(
+    State(state): SharedState,
+    headers: HeaderMap,
+    Path((user_id, tweet_id)): Path<(String, String)>,
+) -> Result<Json<Value>, Response> {
+    let mut state = state.lock().unwrap();
+    if authenticate(&state, &headers)? != user_id {
+        return Err(forbidden("You can only unlike tweets as yourself"));
+    }
+    state.likes.remove(&(user_id, tweet_id));
+    Ok(Json(json!({ "data": { "liked": false } })))
+}
+
+async fn retweet(
+    State(state): SharedState,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    Json(body): Json<Value>,
+) -> Result<Json<Value>, Response> {
+    let mut state = state.lock().unwrap();
+    if authenticate(&state, &headers)? != user_id {
+        return Err(forbidden("You can only retweet as yourself"));
+    }
+    let tweet_id = body["tweet_id"].as_str().unwrap_or_default();
+    if state.tweet(tweet_id).is_none() {
+        return Err(invalid_request(&format!(
+            "The tweet {tweet_id} does not exist"
+        )));
+    }
+    state.retweets.insert((user_id, tweet_id.into()));
+    Ok(Json(json!({ "data": { "retweeted": true } })))
+}
+
+async fn unretweet(
+    State(state): SharedState,
+    headers: HeaderMap,
+    Path((user_id, tweet_id)): Path<(String, String)>,
+) -> Result<Json<Value>, Response> {
+    let mut state = state.lock().unwrap();
+    if authenticate(&state, &headers)? != user_id {
+        return Err(forbidden("You can only undo your own retweets"));
+    }
+    state.retweets.remove(&(user_id, tweet_id));
+    Ok(Json(json!({ "data": { "retweeted": false } })))
+}
+
+async fn follow_user(
+    State(state): SharedState,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    Json(body): Json<Value>,
+) -> Result<Json<Value>, Response> {
+    let mut state = state.lock().unwrap();
+    if authenticate(&state, &headers)? != user_id {
+        return Err(forbidden("You can only follow users as yourself"));
+    }
+    let target_user_id = body["target_user_id"].as_str().unwrap_or_default();
+    if state.user(target_user_id).is_none() {
+        return Err(invalid_request(&format!(
+            "The user {target_user_id} does not exist"
+        )));
+    }
+    state.follows.insert((user_id, target_user_id.into()));
+    Ok(Json(
+        json!({ "data": { "following": true, "pending_follow": false } }),
+    ))
+}
+
+/// Refuses the requests to an endpoint with 429 once its limit is used up. The responses of
+/// limited endpoints carry the x-rate-limit-* headers.
+async fn rate_limit(
+    State(state): SharedState,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    let endpoint = endpoint(
+        request.method().as_str(),
+        &format!("http://localhost{}", request.uri()),
+    );
+    let limit = state
+        .lock()
+        .unwrap()
+        .limits
+        .get_mut(&endpoint)
+        .map(|limit| {
+            limit.calls += 1;
+            let allowed = limit.remaining > 0;
+            limit.remaining = limit.remaining.saturating_sub(1);
+            (allowed, *limit)
+        });
+    let Some((allowed, limit)) = limit else {
+        return next.run(request).await;
+    };
+
+    let mut response = if allowed {
+        next.run(request).await
+    } else {
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(json!({
+                "title": "Too Many Requests",
+                "detail": "Too Many Requests",
+                "type": "about:blank",
+                "status": 429
+            })),
+        )
+            .into_response()
+    };
+    let headers = response.headers_mut();
+    headers.insert("x-rate-limit-limit", limit.limit.into());
+    headers.insert("x-rate-limit-remaining", limit.remaining.into());
+    headers.insert("x-rate-limit-reset", limit.reset_at.into());
+    response
+}
+
+/// A running fake X API. The methods change its state directly, without going through the API,
+/// to play the other users and to check what the agent did.
+pub struct FakeX {
+    base_url: String,
+    state: Arc<Mutex<FakeState>>,
+}
+
+impl FakeX {
+    /// Serves the fake on a local port
+    pub async fn start() -> Result<Self> {
+        let state = Arc::new(Mutex::new(FakeState::default()));
+        let api = Router::new()
+            .route("/tweets", post(create_tweet))
+            .route("/tweets/search/recent", get(search_recent))
+            .route("/tweets/:id", get(get_tweet))
+            .route("/users/by/username/:username", get(get_user_by_username))
+            .route("/users/:id", get(get_user))
+            .route("/users/:id/mentions", get(get_mentions))
+            .route("/users/:id/tweets", get(get_user_tweets))
+            .route(
+                "/users/:id/timelines/reverse_chronological",
+                get(get_timeline),
+            )
+            .route("/users/:id/likes", post(like_tweet))
+            .route("/users/:id/likes/:tweet_id", delete(unlike_tweet))
+            .route("/users/:id/retweets", post(retweet))
+            .route("/users/:id/retweets/:tweet_id", delete(unretweet))
+            .route("/users/:id/following", post(follow_user))
+            .with_state(state.clone());
+        let app = Router::new()
+            .nest("/2", api)
+            .layer(middleware::from_fn_with_state(state.clone(), rate_limit));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        Ok(Self {
+            base_url: format!("http://{addr}/2"),
+            state,
+        })
+    }
+
+    /// The url to use instead of https://api.twitter.com/2
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Creates a user whose requests are signed with `access_token` and returns their id
+    pub fn add_user(&self, username: &str, access_token: &str) -> String {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id();
+        state.users.push(FakeUser {
+            id: id.clone(),
+            username: username.into(),
+            name: username.into(),
+            access_token: access_token.into(),
+        });
+        id
+    }
+
+    /// A client that acts as the user of `access_token`
+    pub fn client(&self, access_token: &str) -> TwitterClient {
+        TwitterClient::new(
+            self.base_url.clone(),
+            "consumer-key".into(),
+            "consumer-secret".into(),
+            access_token.into(),
+            "access-token-secret".into(),
+        )
+    }
+
+    /// Posts a tweet as `author_id` and returns its id
+    pub fn tweet(&self, author_id: &str, text: &str) -> String {
+        self.state
+            .lock()
+            .unwrap()
+            .add_tweet(author_id, text, None, None)
+    }
+
+    /// Posts a reply as `author_id` and returns its id
+    pub fn reply(&self, author_id: &str, text: &str, in_reply_to: &str) -> String {
+        self.state
+            .lock()
+            .unwrap()
+            .add_tweet(author_id, text, Some(in_reply_to), None)
+    }
+
+    pub fn follow(&self, user_id: &str, target_user_id: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .follows
+            .insert((user_id.into(), target_user_id.into()));
+    }
+
+    pub fn is_following(&self, user_id: &str, target_user_id: &str) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .follows
+            .contains(&(user_id.into(), target_user_id.into()))
+    }
+
+    pub fn has_liked(&self, user_id: &str, tweet_id: &str) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .likes
+            .contains(&(user_id.into(), tweet_id.into()))
+    }
+
+    pub fn has_retweeted(&self, user_id: &str, tweet_id: &str) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .retweets
+            .contains(&(user_id.into(), tweet_id.into()))
+    }
+
+    /// Allows `calls` requests to `endpoint`, e.g. "GET /2/users/:id/mentions", in the next
+    /// 15 minutes
+    pub fn limit(&self, endpoint: &str, calls: u32) {
+        let reset_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            + RATE_LIMIT_WINDOW_SECS;
+        self.state.lock().unwrap().limits.insert(
+            endpoint.into(),
+            FakeLimit {
+                limit: calls,
+                remaining: calls,
+                reset_at,
+                calls: 0,
+            },
+        );
+    }
+
+    /// Requests to a limited endpoint so far
+    pub fn calls(&self, endpoint: &str) -> u32 {
+        self.state
+            .lock()
+            .unwrap()
+            .limits
+            .get(endpoint)
+            .map(|limit| limit.calls)
+            .unwrap_or_default()
+    }
+
+    /// Tweets of `user_id`, newest first
+    pub fn tweets_by(&self, user_id: &str) -> Vec<FakeTweet> {
+        self.state
+            .lock()
+            .unwrap()
+            .tweets
+            .iter()
+            .rev()
+            .filter(|t| t.author_id == user_id)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FakeX;
+    use crate::twitter::{api_types::FeedTweet, error::TwitterError};
+
+    #[tokio::test]
+    async fn test_client_against_fake() {
+        let fake = FakeX::start().await.unwrap();
+        let agent_id = fake.add_user("agent", "agent-token");
+        let alice_id = fake.add_user("alice", "alice-token");
+        let client = fake.client("agent-token");
+
+        let user = client.get_user_info_by_username("alice").await.unwrap();
+        assert_eq!(user.id, alice_id);
+        let e = client.get_user_info_by_username("bob").await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<TwitterError>(),
+            Some(TwitterError::NotFound(_))
+        ));
+
+        // Alice shows up on the timeline once she is followed
+        let gm = fake.tweet(&alice_id, "gm");
+        let own = client.post_tweet("first tweet").await.unwrap();
+        client.follow_user(&agent_id, &alice_id).await.unwrap();
+        assert!(fake.is_following(&agent_id, &alice_id));
+        let timeline = client
+            .get_timeline(&agent_id, Some(5), None, None)
+            .await
+            .unwrap();
+        let ids = timeline
+            .data
+            .iter()
+            .map(|t| t.id.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(ids, vec![own.id.as_str(), gm.as_str()]);
+        assert_eq!(timeline.includes.users.len(), 2);
+
+        // Replies to our tweets count as mentions
+        let mention = fake.tweet(&alice_id, "hey @Agent");
+        let reply = fake.reply(&alice_id, "nice", &own.id);
+        let mentions = client
+            .get_mentions(&agent_id, None, None, None)
+            .await
+            .unwrap();
+        let ids = mentions
+            .data
+            .iter()
+            .map(|t| t.id.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(ids, vec![reply.as_str(), mention.as_str()]);
+        assert_eq!(mentions.data[0].replied_to(), Some(own.id.as_str()));
+
+        let sent = client.reply_to_tweet("thanks", &reply).await.unwrap();
+        let tweet = client.get_tweet(sent.id.clone()).await.unwrap();
+        assert_eq!(tweet.replied_to(), Some(reply.as_str()));
+        assert_eq!(tweet.username.as_deref(), Some("agent"));
+        assert_eq!(tweet.conversation_id.as_ref(), Some(&own.id));
+        let own = client.get_tweet(own.id.clone()).await.unwrap();
+        assert_eq!(own.public_metrics.unwrap().reply_count, 1);
+        let mention = client.get_tweet(mention.clone()).await.unwrap();
+        let handle = &mention.entities.mentions[0];
+        assert_eq!(handle.username, "Agent");
+        assert_eq!(handle.id.as_ref(), Some(&agent_id));
+        assert_eq!((handle.start, handle.end), (4, 10));
+        assert_eq!(fake.tweets_by(&agent_id)[0].text, "thanks");
+        // X refuses to post the same text twice
+        let e = client.post_tweet("thanks").await.unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<TwitterError>(),
+            Some(&TwitterError::DuplicateContent)
+        );
+
+        client.like_tweet(&agent_id, &gm).await.unwrap();
+        assert!(fake.has_liked(&agent_id, &gm));
+        // Only the owner of the token can act for a user
+        assert!(client.like_tweet(&alice_id, &gm).await.is_err());
+        client.unlike_tweet(&agent_id, &gm).await.unwrap();
+        assert!(!fake.has_liked(&agent_id, &gm));
+
+        client.retweet(&agent_id, &gm).await.unwrap();
+        assert!(fake.has_retweeted(&agent_id, &gm));
+        let quote = client.quote_tweet("gm indeed", &gm).await.unwrap();
+        let quote = client.get_tweet(quote.id).await.unwrap();
+        assert_eq!(quote.quoted(), Some(gm.as_str()));
+        let metrics = client.get_tweet(gm.clone()).await.unwrap().public_metrics;
+        assert_eq!(metrics.as_ref().map(|m| m.retweet_count), Some(1));
+        assert_eq!(metrics.as_ref().map(|m| m.quote_count), Some(1));
+        client.unretweet(&agent_id, &gm).await.unwrap();
+        assert!(!fake.has_retweeted(&agent_id, &gm));
+
+        let found = client
+            .search_recent_tweets("from:alice nice", 10)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].username.as_deref(), Some("alice"));
+
+        // Unknown tokens are turned away
+        let stranger = fake.client("stolen-token");
+        let e = stranger.get_user_info_by_id(&agent_id).await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<TwitterError>(),
+            Some(TwitterError::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reply_chain() {
+        let fake = FakeX::start().await.unwrap();
+        let alice_id = fake.add_user("alice", "alice-token");
+        let bob_id = fake.add_user("bob", "bob-token");
+        let client = fake.client("alice-token");
+
+        let mut ids = vec![fake.tweet(&alice_id, "0")];
+        for i in 1..6 {
+            let author = if i % 2 == 0 { &alice_id } else { &bob_id };
+            let id = fake.reply(author, &i.to_string(), ids.last().unwrap());
+            ids.push(id);
+        }
+        let last = client.get_tweet(ids[5].clone()).await.unwrap();
+
+        let chain = client.get_reply_chain(&last, 10).await;
+        let texts = chain
+            .tweets
+            .iter()
+            .map(|t| t.text.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(texts, vec!["0", "1", "2", "3", "4", "5"]);
+        assert_eq!(chain.fetched, 5);
+
+        // Long chains keep the root and the newest replies, the tweets read before are cached
+        let chain = client.get_reply_chain(&last, 4).await;
+        let texts = chain
+            .tweets
+            .iter()
+            .map(|t| t.text.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(texts, vec!["0", "3", "4", "5"]);
+        assert_eq!(chain.fetched, 0);
+    }
+
+    #[tokio::test]
+    async fn test_new_mentions_stay_within_max_tweets() {
+        let fake = FakeX::start().await.unwrap();
+        let agent_id = fake.add_user("agent", "agent-token");
+        let alice_id = fake.add_user("alice", "alice-token");
+        let client = fake.client("agent-token");
+
+        fake.limit("GET /2/users/:id/mentions", 10);
+        let since_id = fake.tweet(&alice_id, "hey @agent");
+        for i in 0..110 {
+            fake.tweet(&alice_id, &format!("@agent {i}"));
+        }
+        // A page can't be smaller than 5 mentions
+        let feed = client
+            .get_new_mentions(&agent_id, Some(&since_id), 4)
+            .await
+            .unwrap();
+        assert!(feed.tweets.is_empty());
+        assert_eq!(fake.calls("GET /2/users/:id/mentions"), 0);
+
+        // The second page would have to be smaller than 5 mentions and isn't read
+        let feed = client
+            .get_new_mentions(&agent_id, Some(&since_id), 103)
+            .await
+            .unwrap();
+        assert_eq!(feed.tweets.len(), 100);
+        assert_eq!(fake.calls("GET /2/users/:id/mentions"), 1);
+
+        let feed = client
+            .get_new_mentions(&agent_id, Some(&since_id), 108)
+            .await
+            .unwrap();
+        assert_eq!(feed.tweets.len(), 108);
+        assert_eq!(fake.calls("GET /2/users/:id/mentions"), 3);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits() {
+        let fake = FakeX::start().await.unwrap();
+        let agent_id = fake.add_user("agent", "agent-token");
+        fake.limit("GET /2/users/:id/mentions", 1);
+        let client = fake.client("agent-token");
+
+        client
+            .get_mentions(&agent_id, None, None, None)
+            .await
+            .unwrap();
+        // The client knows the limit is used up and doesn't send the request
+        let e = client
+            .get_mentions(&agent_id, None, None, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<TwitterError>(),
+            Some(TwitterError::RateLimited { .. })
+        ));
+        assert_eq!(fake.calls("GET /2/users/:id/mentions"), 1);
+        // Other endpoints are not affected
+        client.get_user_info_by_id(&agent_id).await.unwrap();
+
+        // A client that doesn't know yet is refused by the server
+        let e = fake
+            .client("agent-token")
+            .get_mentions(&agent_id, None, None, None)
+            .await
+            .unwrap_err();
+        let limited = e.downcast_ref::<TwitterError>().unwrap();
+        assert!(
+            matches!(limited, TwitterError::RateLimited { endpoint, .. } if endpoint == "GET /2/users/:id/mentions")
+        );
+        assert!(limited.rate_limit_wait().unwrap().as_secs() > 0);
+        assert_eq!(fake.calls("GET /2/users/:id/mentions"), 2);
+    }
+}
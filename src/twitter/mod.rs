@@ -1,18 +1,111 @@
 // Client that makes all requests to the twitter client
 
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
 use anyhow::{anyhow, Result};
 use reqwest_oauth1::{Client, DefaultSM, OAuthClientProvider, Secrets, Signer};
+use serde::{de::DeserializeOwned, Deserialize};
 
 pub mod api_types;
+pub mod error;
+#[cfg(test)]
+pub mod fake;
+pub mod rate_limit;
 use api_types::MentionsResponse;
 
-use crate::twitter::api_types::ApiResponse;
+use crate::{
+    cassette::{self, Recorder},
+    twitter::api_types::ApiResponse,
+};
+
+use self::{
+    api_types::{
+        FollowData, LikeData, RetweetData, SearchResponse, SentTweet, TimelineResponse, Tweet,
+        TweetResponse, TweetsResponse, User,
+    },
+    error::TwitterError,
+    rate_limit::RateLimits,
+};
+
+pub const X_API_URL: &str = "https://api.twitter.com/2";
+
+// The fields and expansions of every tweet we read, see `api_types::Tweet`
+const TWEET_PARAMS: [(&str, &str); 3] = [
+    (
+        "tweet.fields",
+        "author_id,created_at,conversation_id,referenced_tweets,entities,public_metrics,lang,attachments",
+    ),
+    ("expansions", "author_id,attachments.media_keys"),
+    ("media.fields", "type,url,preview_image_url,alt_text"),
+];
+// Tweets of reply chains kept so a conversation isn't read again for every reply to it
+const MAX_CACHED_TWEETS: usize = 1000;
+
+/// New tweets of a feed, newest first
+pub struct Feed<T> {
+    pub tweets: Vec<T>,
+    /// Id of the newest tweet, the `since_id` of the next read
+    pub newest_id: Option<String>,
+}
 
-use self::api_types::{FollowData, SentTweet, TimelineResponse, Tweet, TweetsResponse, User};
+impl<T> Default for Feed<T> {
+    fn default() -> Self {
+        Self {
+            tweets: Vec::new(),
+            newest_id: None,
+        }
+    }
+}
+
+impl<T> Feed<T> {
+    fn add_page(&mut self, tweets: Vec<T>, newest_id: Option<String>) {
+        // The first page has the newest tweets
+        if self.newest_id.is_none() {
+            self.newest_id = newest_id;
+        }
+        self.tweets.extend(tweets);
+    }
+}
+
+/// The reply chain that ends in a tweet, oldest first
+pub struct ReplyChain {
+    pub tweets: Vec<Tweet>,
+    /// How many of the tweets were read from X instead of the cache
+    pub fetched: usize,
+}
+
+/// The smallest page of mentions X returns
+pub const MIN_MENTIONS_PAGE: usize = 5;
+
+/// Whether to read the next page after `read` tweets. Only new tweets are read page by page,
+/// until there are `max_tweets`.
+fn has_next_page(
+    since_id: Option<&str>,
+    next_token: &Option<String>,
+    read: usize,
+    max_tweets: usize,
+    feed: &str,
+) -> bool {
+    if next_token.is_none() || since_id.is_none() {
+        return false;
+    }
+    if read >= max_tweets {
+        tracing::warn!("More than {max_tweets} new tweets on the {feed}, skipping the older ones");
+        return false;
+    }
+    true
+}
 
 pub struct TwitterClient {
     client: Client<Signer<'static, Secrets<'static>, DefaultSM>>,
     base_url: String,
+    rate_limits: RateLimits,
+    recorder: Option<Arc<Recorder>>,
+    /// Tweets of reply chains by id
+    tweet_cache: Mutex<HashMap<String, Tweet>>,
 }
 
 impl TwitterClient {
@@ -33,100 +126,307 @@ impl TwitterClient {
         Self {
             client,
             base_url: url,
+            rate_limits: RateLimits::default(),
+            recorder: cassette::recorder(),
+            tweet_cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records every request and response in the cassette of `recorder`
+    pub fn with_recorder(mut self, recorder: Arc<Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Sends a GET request to `url` and reads the JSON response. Fails with
+    /// `TwitterError::RateLimited` without sending the request if the limit of the endpoint is used up.
+    async fn get<T: DeserializeOwned>(&self, url: String) -> Result<T> {
+        let endpoint = rate_limit::endpoint("GET", &url);
+        self.rate_limits.check(&endpoint)?;
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(|e| anyhow!("{e:?}"))?;
+        self.read(&endpoint, "GET", None, response).await
+    }
+
+    /// Sends `json` to `url` and reads the JSON response, rate limited like `get`
+    async fn post<T: DeserializeOwned>(&self, url: String, json: serde_json::Value) -> Result<T> {
+        let endpoint = rate_limit::endpoint("POST", &url);
+        self.rate_limits.check(&endpoint)?;
+        let body = json.to_string();
+        let response = self
+            .client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(body.clone())
+            .send()
+            .await
+            .map_err(|e| anyhow!("{e:?}"))?;
+        self.read(&endpoint, "POST", Some(&body), response).await
+    }
+
+    /// Sends a DELETE request to `url`, rate limited like `get`
+    async fn delete<T: DeserializeOwned>(&self, url: String) -> Result<T> {
+        let endpoint = rate_limit::endpoint("DELETE", &url);
+        self.rate_limits.check(&endpoint)?;
+        let response = self
+            .client
+            .delete(url)
+            .send()
+            .await
+            .map_err(|e| anyhow!("{e:?}"))?;
+        self.read(&endpoint, "DELETE", None, response).await
+    }
+
+    /// Reads the response into `T`. Error responses fail with a `TwitterError`.
+    async fn read<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        method: &str,
+        body: Option<&str>,
+        response: reqwest::Response,
+    ) -> Result<T> {
+        let status = response.status();
+        let rate_limit = self
+            .rate_limits
+            .update(endpoint, status, response.headers());
+        // Refused requests are recorded too, a replay should run into the same limits
+        let json = cassette::read_json::<serde_json::Value>(
+            self.recorder.as_deref(),
+            "twitter",
+            &self.base_url,
+            method,
+            body,
+            response,
+        )
+        .await;
+        rate_limit?;
+        let json = json?;
+
+        if let Some(error) = TwitterError::from_response(status.as_u16(), &json) {
+            return Err(error.into());
         }
+        T::deserialize(&json).map_err(|e| anyhow!("{e:?}: {json}"))
+    }
+
+    /// Url of a paginated list of tweets with the query parameters they have in common
+    fn list_url(
+        &self,
+        path: &str,
+        params: &[(&str, &str)],
+        max_results: Option<u16>,
+        since_id: Option<&str>,
+        pagination_token: Option<&str>,
+    ) -> Result<String> {
+        let mut params = params.to_vec();
+        let max_results = max_results.map(|n| n.to_string());
+        if let Some(max_results) = &max_results {
+            params.push(("max_results", max_results.as_str()));
+        }
+        if let Some(since_id) = since_id {
+            params.push(("since_id", since_id));
+        }
+        if let Some(pagination_token) = pagination_token {
+            params.push(("pagination_token", pagination_token));
+        }
+        let url = reqwest::Url::parse_with_params(&format!("{}{path}", self.base_url), &params)?;
+        Ok(url.to_string())
     }
 
     /// Returns a list of tweets that mention the user with id `user_id`.
     /// The list of tweets is ordered by date created (newest first).
     /// By default, the list contains at most 10 tweets. We can increase this
-    /// limit by passing the 'max_results' param. The value has to be between 0 and 1000.
+    /// limit by passing the 'max_results' param. The value has to be between 5 and 100.
+    /// Only tweets newer than `since_id` are returned if it is set. The next page is requested
+    /// with the `next_token` of the previous one as `pagination_token`.
     pub async fn get_mentions(
         &self,
         user_id: &str,
         max_results: Option<u16>,
+        since_id: Option<&str>,
+        pagination_token: Option<&str>,
     ) -> Result<MentionsResponse> {
-        let url = if let Some(max_results) = max_results {
-            format!(
-            "{}/users/{user_id}/mentions?tweet.fields=created_at&expansions=author_id&max_results={max_results}",
-            self.base_url
-            )
-        } else {
-            format!(
-                "{}/users/{user_id}/mentions?tweet.fields=created_at&expansions=author_id",
-                self.base_url
-            )
-        };
-
-        //let res = self
-        //    .client
-        //    .get(url)
-        //    .send()
-        //    .await
-        //    .unwrap()
-        //    .text()
-        //    .await
-        //    .unwrap();
-
-        //println!("{res}");
-
-        //todo!()
-
-        self.client
-            .get(url)
-            .send()
-            .await
-            .map_err(|e| anyhow!("{e:?}"))?
-            .json::<MentionsResponse>()
-            .await
-            .map_err(|e| anyhow!("{e:?}"))
+        let url = self.list_url(
+            &format!("/users/{user_id}/mentions"),
+            &TWEET_PARAMS,
+            max_results,
+            since_id,
+            pagination_token,
+        )?;
+
+        self.get::<MentionsResponse>(url).await
     }
 
     /// Returns a list of tweets and retweets posted by the user with `user_id`
     /// or a users this account follows
     /// We can set the  limit for the number of tweets by passing the 'max_results' param.
+    /// `since_id` and `pagination_token` work like in `get_mentions`.
     pub async fn get_timeline(
         &self,
         user_id: &str,
         max_results: Option<u16>,
+        since_id: Option<&str>,
+        pagination_token: Option<&str>,
     ) -> Result<TimelineResponse> {
-        let url = if let Some(max_results) = max_results {
-            format!(
-                "{}/users/{user_id}/timelines/reverse_chronological?expansions=author_id&max_results={max_results}",
-                self.base_url
-            )
-        } else {
-            format!(
-                "{}/users/{user_id}/timelines/reverse_chronological?expansions=author_id",
-                self.base_url
-            )
-        };
-
-        self.client
-            .get(url)
-            .send()
-            .await
-            .map_err(|e| anyhow!("{e:?}"))?
-            .json::<TimelineResponse>()
-            .await
-            .map_err(|e| anyhow!("{e:?}"))
+        let url = self.list_url(
+            &format!("/users/{user_id}/timelines/reverse_chronological"),
+            &TWEET_PARAMS,
+            max_results,
+            since_id,
+            pagination_token,
+        )?;
+
+        self.get::<TimelineResponse>(url).await
+    }
+
+    /// Reads at most `max_tweets` of the mentions newer than `since_id` page by page, newest first,
+    /// with the usernames of the authors filled in. Without `since_id` only the first page is
+    /// read. A page can't be smaller than `MIN_MENTIONS_PAGE`, the reading stops when fewer
+    /// tweets than that are left.
+    pub async fn get_new_mentions(
+        &self,
+        user_id: &str,
+        since_id: Option<&str>,
+        max_tweets: usize,
+    ) -> Result<Feed<Tweet>> {
+        let mut feed = Feed::default();
+        let mut pagination_token = None;
+        loop {
+            let page_size = max_tweets.saturating_sub(feed.tweets.len()).min(100);
+            if page_size < MIN_MENTIONS_PAGE {
+                break;
+            }
+            let mut res = self
+                .get_mentions(
+                    user_id,
+                    Some(page_size as u16),
+                    since_id,
+                    pagination_token.as_deref(),
+                )
+                .await?;
+            for tweet in res.data.iter_mut() {
+                tweet.username = res.includes.username(&tweet.author_id);
+                tweet.media = res.includes.media(&tweet.attachments);
+            }
+            feed.add_page(res.data, res.meta.newest_id);
+
+            pagination_token = res.meta.next_token;
+            if !has_next_page(
+                since_id,
+                &pagination_token,
+                feed.tweets.len(),
+                max_tweets,
+                "mentions",
+            ) {
+                break;
+            }
+        }
+        Ok(feed)
+    }
+
+    /// Reads the timeline newer than `since_id` like `get_new_mentions`
+    pub async fn get_new_timeline(
+        &self,
+        user_id: &str,
+        since_id: Option<&str>,
+        max_tweets: usize,
+    ) -> Result<Feed<TimelineTweet>> {
+        let page_size = max_tweets.clamp(1, 100) as u16;
+        let mut feed = Feed::default();
+        let mut pagination_token = None;
+        loop {
+            let mut res = self
+                .get_timeline(
+                    user_id,
+                    Some(page_size),
+                    since_id,
+                    pagination_token.as_deref(),
+                )
+                .await?;
+            for tweet in res.data.iter_mut() {
+                tweet.username = res.includes.username(&tweet.author_id);
+                tweet.media = res.includes.media(&tweet.attachments);
+            }
+            feed.add_page(res.data, res.meta.newest_id);
+
+            pagination_token = res.meta.next_token;
+            if !has_next_page(
+                since_id,
+                &pagination_token,
+                feed.tweets.len(),
+                max_tweets,
+                "timeline",
+            ) {
+                break;
+            }
+        }
+        Ok(feed)
     }
 
     /// Retrieves the tweet data for the tweet with id 'tweet_id'.
     pub async fn get_tweet(&self, tweet_id: String) -> Result<Tweet> {
-        let url = format!(
-            "{}/tweets/{tweet_id}?tweet.fields=author_id,created_at",
-            self.base_url
-        );
+        let url = reqwest::Url::parse_with_params(
+            &format!("{}/tweets/{tweet_id}", self.base_url),
+            &TWEET_PARAMS,
+        )?;
 
-        self.client
-            .get(url)
-            .send()
-            .await
-            .map_err(|e| anyhow!("{e:?}"))?
-            .json::<ApiResponse<Tweet>>()
-            .await
-            .map_err(|e| anyhow!("{e:?}"))
-            .map(|res| res.data)
+        let res = self.get::<TweetResponse>(url.to_string()).await?;
+
+        let mut tweet = res.data;
+        tweet.username = res.includes.username(&tweet.author_id);
+        tweet.media = res.includes.media(&tweet.attachments);
+        Ok(tweet)
+    }
+
+    /// Reads the tweets `tweet` replies to up to the root of its conversation, at most
+    /// `max_depth` tweets including `tweet`. Longer chains keep the root and the newest replies
+    /// and skip the middle. A parent that can't be read, e.g. because it was deleted, ends the
+    /// chain early.
+    pub async fn get_reply_chain(&self, tweet: &Tweet, max_depth: usize) -> ReplyChain {
+        let mut chain = vec![tweet.clone()];
+        let mut fetched = 0;
+        while chain.len() < max_depth {
+            let last = chain.last().expect("unreachable");
+            let Some(mut parent_id) = last.replied_to().map(str::to_string) else {
+                break;
+            };
+            // Leave the last place to the root, it says what the conversation is about
+            if chain.len() + 1 == max_depth {
+                if let Some(root_id) = &tweet.conversation_id {
+                    parent_id = root_id.clone();
+                }
+            }
+
+            let cached = self.tweet_cache.lock().unwrap().get(&parent_id).cloned();
+            let parent = match cached {
+                Some(parent) => parent,
+                None => match self.get_tweet(parent_id.clone()).await {
+                    Ok(parent) => {
+                        fetched += 1;
+                        let mut cache = self.tweet_cache.lock().unwrap();
+                        if cache.len() >= MAX_CACHED_TWEETS {
+                            cache.clear();
+                        }
+                        cache.insert(parent_id, parent.clone());
+                        parent
+                    }
+                    Err(e) => {
+                        tracing::warn!("Failed to fetch tweet {parent_id} of conversation: {e:?}");
+                        break;
+                    }
+                },
+            };
+            chain.push(parent);
+        }
+
+        chain.reverse();
+        ReplyChain {
+            tweets: chain,
+            fetched,
+        }
     }
 
     /// Returns a list of tweets created by the user with id `user_id`.
@@ -138,26 +438,20 @@ impl TwitterClient {
         user_id: String,
         max_results: Option<u16>,
     ) -> Result<TweetsResponse> {
-        let url = if let Some(max_results) = max_results {
-            format!(
-            "{}/users/{user_id}/tweets?tweet.fields=author_id,created_at&max_results={max_results}",
-            self.base_url
-            )
-        } else {
-            format!(
-                "{}/users/{user_id}/tweets?tweet.fields=author_id,created_at",
-                self.base_url
-            )
-        };
-
-        self.client
-            .get(url)
-            .send()
-            .await
-            .map_err(|e| anyhow!("{e:?}"))?
-            .json::<TweetsResponse>()
-            .await
-            .map_err(|e| anyhow!("{e:?}"))
+        let url = self.list_url(
+            &format!("/users/{user_id}/tweets"),
+            &TWEET_PARAMS,
+            max_results,
+            None,
+            None,
+        )?;
+
+        let mut res = self.get::<TweetsResponse>(url).await?;
+        for tweet in res.data.iter_mut() {
+            tweet.username = res.includes.username(&tweet.author_id);
+            tweet.media = res.includes.media(&tweet.attachments);
+        }
+        Ok(res)
     }
 
     /// Posts a tweet and returns the tweet data on success.
@@ -168,16 +462,8 @@ impl TwitterClient {
             "text": content,
         });
 
-        self.client
-            .post(url)
-            .header("Content-Type", "application/json")
-            .body(json.to_string())
-            .send()
-            .await
-            .map_err(|e| anyhow!("{e:?}"))?
-            .json::<ApiResponse<SentTweet>>()
+        self.post::<ApiResponse<SentTweet>>(url, json)
             .await
-            .map_err(|e| anyhow!("{e:?}"))
             .map(|res| res.data)
     }
 
@@ -190,16 +476,22 @@ impl TwitterClient {
             "reply": { "in_reply_to_tweet_id": tweet_id }
         });
 
-        self.client
-            .post(url)
-            .header("Content-Type", "application/json")
-            .body(json.to_string())
-            .send()
+        self.post::<ApiResponse<SentTweet>>(url, json)
             .await
-            .map_err(|e| anyhow!("{e:?}"))?
-            .json::<ApiResponse<SentTweet>>()
+            .map(|res| res.data)
+    }
+
+    /// Posts a tweet that quotes the tweet with id `tweet_id`.
+    pub async fn quote_tweet(&self, content: &str, tweet_id: &str) -> Result<SentTweet> {
+        let url = format!("{}/tweets", self.base_url);
+
+        let json = serde_json::json!({
+            "text": content,
+            "quote_tweet_id": tweet_id,
+        });
+
+        self.post::<ApiResponse<SentTweet>>(url, json)
             .await
-            .map_err(|e| anyhow!("{e:?}"))
             .map(|res| res.data)
     }
 
@@ -207,29 +499,76 @@ impl TwitterClient {
     pub async fn get_user_info_by_username(&self, username: &str) -> Result<User> {
         let url = format!("{}/users/by/username/{username}", self.base_url);
 
-        self.client
-            .get(url)
-            .send()
-            .await
-            .map_err(|e| anyhow!("{e:?}"))?
-            .json::<ApiResponse<User>>()
-            .await
-            .map_err(|e| anyhow!("{e:?}"))
-            .map(|res| res.data)
+        self.get::<ApiResponse<User>>(url).await.map(|res| res.data)
     }
 
     /// Retrieves the user info (username, name, user_id) for the user with the specified id.
     pub async fn get_user_info_by_id(&self, user_id: &str) -> Result<User> {
         let url = format!("{}/users/{user_id}", self.base_url);
 
-        self.client
-            .get(url)
-            .send()
+        self.get::<ApiResponse<User>>(url).await.map(|res| res.data)
+    }
+
+    /// Searches the tweets of the last 7 days for `query`. Usernames of the authors are filled in.
+    /// `max_results` has to be between 10 and 100.
+    pub async fn search_recent_tweets(&self, query: &str, max_results: u16) -> Result<Vec<Tweet>> {
+        let url = format!("{}/tweets/search/recent", self.base_url);
+
+        let max_results = max_results.to_string();
+        let mut params = vec![("query", query), ("max_results", max_results.as_str())];
+        params.extend(TWEET_PARAMS);
+        let url = reqwest::Url::parse_with_params(&url, &params)?;
+        let res = self.get::<SearchResponse>(url.to_string()).await?;
+
+        let mut tweets = res.data;
+        for tweet in tweets.iter_mut() {
+            tweet.username = res.includes.username(&tweet.author_id);
+            tweet.media = res.includes.media(&tweet.attachments);
+        }
+        Ok(tweets)
+    }
+
+    /// Likes the tweet with id `tweet_id` as the user with id `user_id`.
+    pub async fn like_tweet(&self, user_id: &str, tweet_id: &str) -> Result<LikeData> {
+        let url = format!("{}/users/{user_id}/likes", self.base_url);
+
+        let json = serde_json::json!({
+            "tweet_id": tweet_id,
+        });
+
+        self.post::<ApiResponse<LikeData>>(url, json)
+            .await
+            .map(|res| res.data)
+    }
+
+    /// Takes back the like of the user with id `user_id` from the tweet with id `tweet_id`.
+    pub async fn unlike_tweet(&self, user_id: &str, tweet_id: &str) -> Result<LikeData> {
+        let url = format!("{}/users/{user_id}/likes/{tweet_id}", self.base_url);
+
+        self.delete::<ApiResponse<LikeData>>(url)
             .await
-            .map_err(|e| anyhow!("{e:?}"))?
-            .json::<ApiResponse<User>>()
+            .map(|res| res.data)
+    }
+
+    /// Retweets the tweet with id `tweet_id` as the user with id `user_id`.
+    pub async fn retweet(&self, user_id: &str, tweet_id: &str) -> Result<RetweetData> {
+        let url = format!("{}/users/{user_id}/retweets", self.base_url);
+
+        let json = serde_json::json!({
+            "tweet_id": tweet_id,
+        });
+
+        self.post::<ApiResponse<RetweetData>>(url, json)
+            .await
+            .map(|res| res.data)
+    }
+
+    /// Undoes the retweet of the tweet with id `tweet_id` by the user with id `user_id`.
+    pub async fn unretweet(&self, user_id: &str, tweet_id: &str) -> Result<RetweetData> {
+        let url = format!("{}/users/{user_id}/retweets/{tweet_id}", self.base_url);
+
+        self.delete::<ApiResponse<RetweetData>>(url)
             .await
-            .map_err(|e| anyhow!("{e:?}"))
             .map(|res| res.data)
     }
 
@@ -241,16 +580,8 @@ impl TwitterClient {
             "target_user_id": target_user_id,
         });
 
-        self.client
-            .post(url)
-            .header("Content-Type", "application/json")
-            .body(json.to_string())
-            .send()
-            .await
-            .map_err(|e| anyhow!("{e:?}"))?
-            .json::<ApiResponse<FollowData>>()
+        self.post::<ApiResponse<FollowData>>(url, json)
             .await
-            .map_err(|e| anyhow!("{e:?}"))
             .map(|res| res.data)
     }
 }
@@ -289,7 +620,7 @@ mod tests {
         );
 
         let tweets = client
-            .get_timeline("1852012860596981761", Some(5))
+            .get_timeline("1852012860596981761", Some(5), None, None)
             .await
             .unwrap();
         println!("users: {:?}", tweets.includes);
@@ -313,7 +644,7 @@ mod tests {
         );
 
         let mentions = client
-            .get_mentions("1852012860596981761", None)
+            .get_mentions("1852012860596981761", None, None, None)
             .await
             .unwrap();
         println!("users: {:?}", mentions.includes);
@@ -0,0 +1,213 @@
+// X limits how often each endpoint can be called within a window (15 minutes for most, 24 hours
+// for posting) and tells us in the x-rate-limit-* headers of every response how many calls are
+// left. We remember them per endpoint and don't send requests we know would be refused.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::{header::HeaderMap, StatusCode};
+
+use super::error::TwitterError;
+
+// The window of most endpoints, used when a 429 doesn't say when the limit resets
+const DEFAULT_WINDOW_SECS: u64 = 900;
+
+#[derive(Debug, Clone, Copy)]
+struct RateLimit {
+    remaining: u32,
+    reset_at: u64,
+}
+
+/// The last known limits of the endpoints
+#[derive(Default)]
+pub struct RateLimits {
+    limits: Mutex<HashMap<String, RateLimit>>,
+}
+
+impl RateLimits {
+    /// Fails if the limit of `endpoint` is used up
+    pub fn check(&self, endpoint: &str) -> Result<(), TwitterError> {
+        let mut limits = self.limits.lock().unwrap();
+        let Some(limit) = limits.get(endpoint).copied() else {
+            return Ok(());
+        };
+        if limit.reset_at <= now_secs() {
+            limits.remove(endpoint);
+            return Ok(());
+        }
+        if limit.remaining == 0 {
+            return Err(TwitterError::RateLimited {
+                endpoint: endpoint.into(),
+                reset_at: limit.reset_at,
+            });
+        }
+        Ok(())
+    }
+
+    /// Remembers the limit of `endpoint` from the headers of its response. Fails if the request
+    /// was refused because of a limit.
+    pub fn update(
+        &self,
+        endpoint: &str,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> Result<(), TwitterError> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok())
+        };
+        let mut limit = match (
+            header("x-rate-limit-remaining"),
+            header("x-rate-limit-reset"),
+        ) {
+            (Some(remaining), Some(reset_at)) => Some(RateLimit {
+                remaining: remaining as u32,
+                reset_at,
+            }),
+            _ => None,
+        };
+        // Posting also has a limit per user and day which can run out before the window does
+        if let (Some(0), Some(reset_at)) = (
+            header("x-user-limit-24hour-remaining"),
+            header("x-user-limit-24hour-reset"),
+        ) {
+            limit = Some(RateLimit {
+                remaining: 0,
+                reset_at,
+            });
+        }
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let reset_at = limit
+                .map(|l| l.reset_at)
+                .filter(|reset_at| *reset_at > now_secs())
+                .unwrap_or(now_secs() + DEFAULT_WINDOW_SECS);
+            limit = Some(RateLimit {
+                remaining: 0,
+                reset_at,
+            });
+        }
+
+        let Some(limit) = limit else {
+            return Ok(());
+        };
+        tracing::debug!(
+            "{endpoint}: {} calls left until {}",
+            limit.remaining,
+            limit.reset_at
+        );
+        self.limits.lock().unwrap().insert(endpoint.into(), limit);
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(TwitterError::RateLimited {
+                endpoint: endpoint.into(),
+                reset_at: limit.reset_at,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// The endpoint of a request, e.g. "GET /2/users/:id/mentions". Ids and usernames are left out
+/// because the limits are per endpoint.
+pub fn endpoint(method: &str, url: &str) -> String {
+    let path = reqwest::Url::parse(url)
+        .map(|url| url.path().to_string())
+        .unwrap_or_default();
+    let mut segments = Vec::new();
+    let mut after_username = false;
+    for segment in path.split('/') {
+        if after_username {
+            segments.push(":username");
+        } else if !segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit()) {
+            segments.push(":id");
+        } else {
+            segments.push(segment);
+        }
+        after_username = segment == "username";
+    }
+    format!("{method} {}", segments.join("/"))
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::{header::HeaderMap, StatusCode};
+
+    use super::{endpoint, now_secs, RateLimits};
+    use crate::twitter::error::TwitterError;
+
+    #[test]
+    fn test_endpoint() {
+        assert_eq!(
+            endpoint("GET", "https://api.twitter.com/2/users/1852012860596981761/mentions?max_results=100&since_id=1"),
+            "GET /2/users/:id/mentions"
+        );
+        assert_eq!(
+            endpoint("GET", "https://api.twitter.com/2/users/by/username/agent"),
+            "GET /2/users/by/username/:username"
+        );
+        assert_eq!(
+            endpoint("POST", "https://api.twitter.com/2/tweets"),
+            "POST /2/tweets"
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = RateLimits::default();
+        let reset_at = now_secs() + 60;
+        let mut headers = HeaderMap::new();
+        headers.insert("x-rate-limit-remaining", "1".parse().unwrap());
+        headers.insert("x-rate-limit-reset", reset_at.to_string().parse().unwrap());
+
+        limits
+            .update("GET /2/tweets/:id", StatusCode::OK, &headers)
+            .unwrap();
+        assert!(limits.check("GET /2/tweets/:id").is_ok());
+
+        headers.insert("x-rate-limit-remaining", "0".parse().unwrap());
+        limits
+            .update("GET /2/tweets/:id", StatusCode::OK, &headers)
+            .unwrap();
+        assert_eq!(
+            limits.check("GET /2/tweets/:id"),
+            Err(TwitterError::RateLimited {
+                endpoint: "GET /2/tweets/:id".into(),
+                reset_at
+            })
+        );
+        // Other endpoints have their own limits
+        assert!(limits.check("POST /2/tweets").is_ok());
+
+        // A 429 without headers blocks the endpoint for the default window
+        let limited = limits
+            .update(
+                "POST /2/tweets",
+                StatusCode::TOO_MANY_REQUESTS,
+                &HeaderMap::new(),
+            )
+            .unwrap_err();
+        assert!(limited.rate_limit_wait().unwrap().as_secs() >= 899);
+        assert!(limits.check("POST /2/tweets").is_err());
+
+        // Limits that reset in the past are forgotten
+        headers.insert(
+            "x-rate-limit-reset",
+            (now_secs() - 1).to_string().parse().unwrap(),
+        );
+        limits
+            .update("GET /2/tweets/:id", StatusCode::OK, &headers)
+            .unwrap();
+        assert!(limits.check("GET /2/tweets/:id").is_ok());
+    }
+}
@@ -0,0 +1,325 @@
+// Keeps track of the tokens the language models use and what they cost. Every call is stored in
+// the kv db together with the task it was made for. When the spend of the day or month gets
+// close to its cap the agent cuts back on optional calls, at the cap it stops calling models.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::db::{types::UsageRecord, Database};
+
+pub const SECS_PER_DAY: u64 = 86400;
+
+/// Price of a model in USD per million tokens
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct UsageConfig {
+    /// Prices by model name. A model reported as "gpt-4o-mini-2024-07-18" uses the price of
+    /// "gpt-4o-mini" if there is no exact match. Models without a price cost nothing.
+    #[serde(default)]
+    pub prices: HashMap<String, ModelPrice>,
+    /// Spend caps in USD per UTC day and month
+    pub daily_cap: Option<f64>,
+    pub monthly_cap: Option<f64>,
+    /// Share of a cap after which the agent degrades, defaults to 0.8
+    pub degrade_at: Option<f64>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetState {
+    #[default]
+    Normal,
+    /// Close to a cap, optional calls are skipped and the context is cut
+    Degraded,
+    /// A cap is reached, no more calls until the next day or month
+    Exhausted,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct TaskUsage {
+    pub calls: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+}
+
+impl TaskUsage {
+    fn add(&mut self, record: &UsageRecord) {
+        self.calls += 1;
+        self.prompt_tokens += record.prompt_tokens;
+        self.completion_tokens += record.completion_tokens;
+        self.cost += record.cost;
+    }
+}
+
+/// Usage within a day or month
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct PeriodUsage {
+    /// Unix timestamp in seconds the period started at
+    pub start: u64,
+    pub total: TaskUsage,
+    pub tasks: BTreeMap<String, TaskUsage>,
+}
+
+impl PeriodUsage {
+    fn new(start: u64) -> Self {
+        Self {
+            start,
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, record: &UsageRecord) {
+        self.total.add(record);
+        self.tasks
+            .entry(record.task.clone())
+            .or_default()
+            .add(record);
+    }
+}
+
+/// What the operator endpoint shows
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct UsageReport {
+    pub state: BudgetState,
+    pub daily_cap: Option<f64>,
+    pub monthly_cap: Option<f64>,
+    pub day: PeriodUsage,
+    pub month: PeriodUsage,
+}
+
+pub struct UsageMeter {
+    database: Arc<Database>,
+    config: UsageConfig,
+    periods: Mutex<(PeriodUsage, PeriodUsage)>,
+    report: watch::Sender<UsageReport>,
+}
+
+impl UsageMeter {
+    /// Loads the usage of the current month from the db. The totals are published on `report`
+    /// after every call.
+    pub fn new(
+        database: Arc<Database>,
+        config: UsageConfig,
+        report: watch::Sender<UsageReport>,
+    ) -> Result<Self> {
+        let now = now_secs();
+        let mut day = PeriodUsage::new(day_start(now));
+        let mut month = PeriodUsage::new(month_start(now));
+        for record in database.get_usage_records_since(month.start as u128 * 1_000_000_000)? {
+            month.add(&record);
+            if record.timestamp / 1_000_000_000 >= day.start as u128 {
+                day.add(&record);
+            }
+        }
+
+        let meter = Self {
+            database,
+            config,
+            periods: Mutex::new((day, month)),
+            report,
+        };
+        meter.report.send_replace(meter.report());
+        Ok(meter)
+    }
+
+    /// Stores the usage of a call. Failing to store it is logged but doesn't fail the call.
+    pub fn record(
+        &self,
+        provider: &str,
+        model: &str,
+        task: &str,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    ) {
+        let record = UsageRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+            provider: provider.into(),
+            model: model.into(),
+            task: if task.is_empty() { "other" } else { task }.into(),
+            prompt_tokens,
+            completion_tokens,
+            cost: cost(&self.config.prices, model, prompt_tokens, completion_tokens),
+        };
+        tracing::debug!("LLM usage: {record:?}");
+        if let Err(e) = self.database.insert_usage_record(&record) {
+            tracing::error!("Failed to store LLM usage {record:?}: {e:?}");
+        }
+
+        {
+            let mut periods = self.periods.lock().unwrap();
+            self.roll_over(&mut periods);
+            periods.0.add(&record);
+            periods.1.add(&record);
+        }
+        self.report.send_replace(self.report());
+    }
+
+    pub fn state(&self) -> BudgetState {
+        let mut periods = self.periods.lock().unwrap();
+        self.roll_over(&mut periods);
+        budget_state(&self.config, periods.0.total.cost, periods.1.total.cost)
+    }
+
+    pub fn report(&self) -> UsageReport {
+        let mut periods = self.periods.lock().unwrap();
+        self.roll_over(&mut periods);
+        UsageReport {
+            state: budget_state(&self.config, periods.0.total.cost, periods.1.total.cost),
+            daily_cap: self.config.daily_cap,
+            monthly_cap: self.config.monthly_cap,
+            day: periods.0.clone(),
+            month: periods.1.clone(),
+        }
+    }
+
+    /// Starts new periods when the day or month is over
+    fn roll_over(&self, periods: &mut (PeriodUsage, PeriodUsage)) {
+        let now = now_secs();
+        if periods.0.start != day_start(now) {
+            periods.0 = PeriodUsage::new(day_start(now));
+        }
+        if periods.1.start != month_start(now) {
+            periods.1 = PeriodUsage::new(month_start(now));
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn day_start(secs: u64) -> u64 {
+    secs - secs % SECS_PER_DAY
+}
+
+/// Start of the UTC month, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn month_start(secs: u64) -> u64 {
+    let z = secs / SECS_PER_DAY + 719468;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day_of_month = doy - (153 * mp + 2) / 5;
+    day_start(secs) - day_of_month * SECS_PER_DAY
+}
+
+fn cost(
+    prices: &HashMap<String, ModelPrice>,
+    model: &str,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+) -> f64 {
+    let price = prices.get(model).or_else(|| {
+        prices
+            .iter()
+            .filter(|(name, _)| model.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, price)| price)
+    });
+    match price {
+        Some(price) => {
+            (prompt_tokens as f64 * price.input + completion_tokens as f64 * price.output) / 1e6
+        }
+        None => 0.0,
+    }
+}
+
+fn budget_state(config: &UsageConfig, day_cost: f64, month_cost: f64) -> BudgetState {
+    let degrade_at = config.degrade_at.unwrap_or(0.8);
+    let mut state = BudgetState::Normal;
+    for (cap, cost) in [
+        (config.daily_cap, day_cost),
+        (config.monthly_cap, month_cost),
+    ] {
+        let Some(cap) = cap else {
+            continue;
+        };
+        if cost >= cap {
+            return BudgetState::Exhausted;
+        }
+        if cost >= cap * degrade_at {
+            state = BudgetState::Degraded;
+        }
+    }
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{budget_state, cost, day_start, month_start, BudgetState, ModelPrice, UsageConfig};
+
+    #[test]
+    fn test_periods() {
+        // 2024-03-15 12:00 UTC
+        assert_eq!(day_start(1710504000), 1710460800);
+        assert_eq!(month_start(1710504000), 1709251200);
+        // 2024-12-31 23:59 UTC
+        assert_eq!(month_start(1735689540), 1733011200);
+        assert_eq!(month_start(1733011200), 1733011200);
+    }
+
+    #[test]
+    fn test_cost() {
+        let prices = HashMap::from([
+            (
+                "gpt-4o".to_string(),
+                ModelPrice {
+                    input: 2.5,
+                    output: 10.0,
+                },
+            ),
+            (
+                "gpt-4o-mini".to_string(),
+                ModelPrice {
+                    input: 0.15,
+                    output: 0.6,
+                },
+            ),
+        ]);
+
+        assert_eq!(cost(&prices, "gpt-4o", 1_000_000, 0), 2.5);
+        // The longest matching prefix wins
+        assert_eq!(cost(&prices, "gpt-4o-mini-2024-07-18", 0, 1_000_000), 0.6);
+        assert_eq!(cost(&prices, "llama3.1", 1_000_000, 1_000_000), 0.0);
+    }
+
+    #[test]
+    fn test_budget_state() {
+        let config = UsageConfig {
+            daily_cap: Some(1.0),
+            monthly_cap: Some(20.0),
+            ..Default::default()
+        };
+
+        assert_eq!(budget_state(&config, 0.5, 5.0), BudgetState::Normal);
+        assert_eq!(budget_state(&config, 0.9, 5.0), BudgetState::Degraded);
+        assert_eq!(budget_state(&config, 0.1, 17.0), BudgetState::Degraded);
+        assert_eq!(budget_state(&config, 1.0, 5.0), BudgetState::Exhausted);
+        assert_eq!(budget_state(&config, 0.1, 20.0), BudgetState::Exhausted);
+        assert_eq!(
+            budget_state(&UsageConfig::default(), 100.0, 100.0),
+            BudgetState::Normal
+        );
+    }
+}
@@ -0,0 +1,177 @@
+// X API tiers cap how many posts an app may create and read per month. Posts, replies and reads
+// are counted in the kv db per UTC calendar month, and what is left is spread over the rest of
+// the month so the agent doesn't use it all up in the first days and go quiet until the next one.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::Database,
+    twitter::rate_limit::now_secs,
+    usage::{month_start, SECS_PER_DAY},
+};
+
+const POSTS: &str = "posts";
+const REPLIES: &str = "replies";
+const READS: &str = "reads";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum XApiTier {
+    Free,
+    Basic,
+    Pro,
+}
+
+impl XApiTier {
+    fn monthly_posts(&self) -> u64 {
+        match self {
+            Self::Free => 500,
+            Self::Basic => 3000,
+            Self::Pro => 300_000,
+        }
+    }
+
+    fn monthly_reads(&self) -> u64 {
+        match self {
+            Self::Free => 100,
+            Self::Basic => 10_000,
+            Self::Pro => 1_000_000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct XBudgetConfig {
+    pub tier: XApiTier,
+    /// Caps that differ from the tier's, e.g. to leave some posts for a human
+    pub monthly_posts: Option<u64>,
+    pub monthly_reads: Option<u64>,
+}
+
+/// Decides whether the agent may post and how many tweets it may read. Without a config nothing
+/// is capped, the usage is still counted.
+pub struct XBudget {
+    database: Arc<Database>,
+    monthly_posts: Option<u64>,
+    monthly_reads: Option<u64>,
+}
+
+impl XBudget {
+    pub fn new(database: Arc<Database>, config: Option<XBudgetConfig>) -> Self {
+        Self {
+            database,
+            monthly_posts: config
+                .as_ref()
+                .map(|c| c.monthly_posts.unwrap_or(c.tier.monthly_posts())),
+            monthly_reads: config
+                .as_ref()
+                .map(|c| c.monthly_reads.unwrap_or(c.tier.monthly_reads())),
+        }
+    }
+
+    /// Whether a post or reply fits into the allowance of today. Both count towards the same cap.
+    pub fn may_post(&self) -> Result<bool> {
+        let Some(cap) = self.monthly_posts else {
+            return Ok(true);
+        };
+        let now = now_secs();
+        let month = month_start(now);
+        let posted =
+            self.database.get_x_usage(month, POSTS)? + self.database.get_x_usage(month, REPLIES)?;
+        let allowance = allowance(cap, now);
+        if posted >= allowance {
+            tracing::info!("{posted} of {cap} posts of the month used, {allowance} allowed so far");
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// How many tweets may still be read today
+    pub fn reads_left(&self) -> Result<usize> {
+        let Some(cap) = self.monthly_reads else {
+            return Ok(usize::MAX);
+        };
+        let now = now_secs();
+        let read = self.database.get_x_usage(month_start(now), READS)?;
+        Ok(allowance(cap, now).saturating_sub(read) as usize)
+    }
+
+    pub fn record_post(&self, reply: bool) -> Result<()> {
+        let kind = if reply { REPLIES } else { POSTS };
+        self.database.add_x_usage(month_start(now_secs()), kind, 1)
+    }
+
+    pub fn record_reads(&self, tweets: usize) -> Result<()> {
+        if tweets == 0 {
+            return Ok(());
+        }
+        self.database
+            .add_x_usage(month_start(now_secs()), READS, tweets as u64)
+    }
+}
+
+/// The part of `cap` that may be used by the end of the day of `now`. Every day of the month
+/// adds the same share, what is left over on one day can be used on the next.
+fn allowance(cap: u64, now: u64) -> u64 {
+    let start = month_start(now);
+    let days = (month_start(start + 32 * SECS_PER_DAY) - start) / SECS_PER_DAY;
+    let day = (now - start) / SECS_PER_DAY + 1;
+    (cap * day).div_ceil(days)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{allowance, XApiTier, XBudget, XBudgetConfig};
+    use crate::db::Database;
+
+    #[test]
+    fn test_allowance() {
+        // 2024-03-01 00:00 UTC, the first of 31 days
+        assert_eq!(allowance(3100, 1709251200), 100);
+        // 2024-03-15 12:00 UTC
+        assert_eq!(allowance(3100, 1710504000), 1500);
+        // 2024-03-31 23:59 UTC
+        assert_eq!(allowance(3100, 1711929540), 3100);
+        // 2024-02-29 12:00 UTC, the last of 29 days
+        assert_eq!(allowance(500, 1709208000), 500);
+        // Small caps still allow something on the first day
+        assert_eq!(allowance(1, 1709251200), 1);
+    }
+
+    #[test]
+    fn test_budget() {
+        let path = std::env::temp_dir().join(format!("x-budget-{}", std::process::id()));
+        let database = Arc::new(Database::new("http://localhost:6334", path.clone()).unwrap());
+
+        let unlimited = XBudget::new(database.clone(), None);
+        assert!(unlimited.may_post().unwrap());
+        assert_eq!(unlimited.reads_left().unwrap(), usize::MAX);
+
+        let budget = XBudget::new(
+            database.clone(),
+            Some(XBudgetConfig {
+                tier: XApiTier::Free,
+                monthly_posts: Some(1),
+                monthly_reads: Some(1),
+            }),
+        );
+        assert!(budget.may_post().unwrap());
+        assert_eq!(budget.reads_left().unwrap(), 1);
+
+        // Replies use up the same cap as posts
+        budget.record_post(true).unwrap();
+        budget.record_reads(1).unwrap();
+        assert!(!budget.may_post().unwrap());
+        assert_eq!(budget.reads_left().unwrap(), 0);
+
+        drop(unlimited);
+        drop(budget);
+        drop(database);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}